@@ -0,0 +1,245 @@
+use std::{fmt, io::{self, Write}, path::{Path, PathBuf}};
+
+use crate::{
+    csv::{self, CsvOptions}, engine_simple::{self, Expr}, file::{self, FileError}, sheet_state::SheetState,
+    value::Value, workbook::{CellPos, PythonPolicy},
+};
+
+const USAGE: &str = "Usage:
+    rusty-sheet eval FILE [--sheet NAME] [--trust] --cell CELL [--cell CELL]...
+    rusty-sheet convert INPUT OUTPUT [--sheet NAME] [--trust]
+    rusty-sheet recalc FILE [--sheet NAME] [--trust] [--output OUTPUT]
+
+eval prints the value of each cell on its own line, cells may name their sheet as in 'Q1 Data'!B7.
+recalc prints the values of a sheet as CSV, or writes the workbook with fresh values to OUTPUT.
+--sheet picks the sheet for cells without one, and the one written to CSV.
+--trust runs the Python code of the workbook, which is left out otherwise.
+Files are read and written by extension: .rsheet, .xlsx, .ods, .csv and .tsv.";
+
+/// Headless commands, working on workbook files without a display
+#[derive(Clone, PartialEq, Debug)]
+pub enum Command {
+    /// Print the values of cells
+    Eval{file: PathBuf, sheet: Option<String>, trust: bool, cells: Vec<String>},
+    /// Write a workbook in the format of another file
+    Convert{input: PathBuf, output: PathBuf, sheet: Option<String>, trust: bool},
+    /// Evaluate every cell, printing a sheet as CSV or writing the workbook
+    Recalc{file: PathBuf, sheet: Option<String>, trust: bool, output: Option<PathBuf>},
+    Help,
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    Usage(String),
+    File(PathBuf, FileError),
+    /// Workbook without the asked sheet or cell
+    Lookup(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(reason) => write!(f, "{}\n\n{}", reason, USAGE),
+            CliError::File(path, err) => write!(f, "{}: {}", path.display(), err),
+            CliError::Lookup(reason) => write!(f, "{}", reason),
+            CliError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(err: io::Error) -> Self {
+        CliError::Io(err)
+    }
+}
+
+impl CliError {
+    /// Process exit status, 2 for wrong usage as is common for command line tools
+    pub fn status(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Command given by the arguments after the program name, `None` when there is none and the GUI should start
+pub fn parse(args: &[String]) -> Option<Result<Command, CliError>> {
+    let (name, rest) = args.split_first()?;
+    match name.as_str() {
+        "eval" | "convert" | "recalc" => Some(parse_command(name, rest)),
+        "help" | "--help" | "-h" => Some(Ok(Command::Help)),
+        _ => None,
+    }
+}
+
+fn parse_command(name: &str, args: &[String]) -> Result<Command, CliError> {
+    let mut paths = vec![];
+    let mut cells = vec![];
+    let mut sheet = None;
+    let mut output = None;
+    let mut trust = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or_else(|| CliError::Usage(format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "--cell" | "-c" => cells.push(value()?),
+            "--sheet" | "-s" => sheet = Some(value()?),
+            "--output" | "-o" => output = Some(PathBuf::from(value()?)),
+            "--trust" => trust = true,
+            flag if flag.starts_with('-') && flag.len() > 1 => return Err(CliError::Usage(format!("Unknown option {}", flag))),
+            path => paths.push(PathBuf::from(path)),
+        }
+    }
+
+    match (name, paths.as_slice()) {
+        ("eval", [file]) if output.is_none() => {
+            if cells.is_empty() {
+                return Err(CliError::Usage("eval needs at least one --cell".to_string()));
+            }
+            Ok(Command::Eval{file: file.clone(), sheet, trust, cells})
+        },
+        ("convert", [input, out]) if cells.is_empty() && output.is_none() => {
+            Ok(Command::Convert{input: input.clone(), output: out.clone(), sheet, trust})
+        },
+        ("recalc", [file]) if cells.is_empty() => Ok(Command::Recalc{file: file.clone(), sheet, trust, output}),
+        _ => Err(CliError::Usage(format!("Wrong arguments for {}", name))),
+    }
+}
+
+/// Read a workbook, making `sheet` the active one when given and running its Python code when trusted
+fn open(path: &Path, sheet: Option<&str>, trust: bool) -> Result<SheetState, CliError> {
+    let (mut state, info) = file::open(path).map_err(|err| CliError::File(path.to_path_buf(), err))?;
+    let policy = state.workbook.python_policy();
+    state.workbook.set_python_policy(PythonPolicy{trusted: trust, ..policy});
+    for unsupported in info.unsupported {
+        eprintln!("{} is not supported, using its last value", unsupported);
+    }
+    if let Some(name) = sheet {
+        let id = state.workbook.find(name).ok_or_else(|| CliError::Lookup(format!("No sheet named \"{}\"", name)))?;
+        state.set_active(id);
+    }
+    Ok(state)
+}
+
+fn write(state: &mut SheetState, path: &Path) -> Result<(), CliError> {
+    file::write(state, path)
+        .ok_or_else(|| CliError::Usage(format!("Unknown file type of {}", path.display())))?
+        .map_err(|err| CliError::File(path.to_path_buf(), err))
+}
+
+/// Value of a cell given like `B7` or `Sheet2!B7`
+fn cell_value(state: &mut SheetState, cell: &str) -> Result<Value, CliError> {
+    let not_cell = || CliError::Lookup(format!("\"{}\" is not a cell", cell));
+    let (sheet, reference) = match engine_simple::parse(&format!("={}", cell.trim())) {
+        Ok(Expr::Reference(sheet, reference)) => (sheet, reference),
+        _ => return Err(not_cell()),
+    };
+    let sheet = state.resolve_sheet(sheet.as_deref())
+        .map_err(|_| CliError::Lookup(format!("No sheet named \"{}\"", sheet.unwrap_or_default())))?;
+    Ok(state.get_value_at(&CellPos{sheet, idx: reference.idx()}))
+}
+
+pub fn run(command: Command, out: &mut dyn Write) -> Result<(), CliError> {
+    match command {
+        Command::Help => writeln!(out, "{}", USAGE)?,
+        Command::Eval{file, sheet, trust, cells} => {
+            let mut state = open(&file, sheet.as_deref(), trust)?;
+            for cell in cells {
+                let value = cell_value(&mut state, &cell)?;
+                writeln!(out, "{}", value)?;
+            }
+        },
+        Command::Convert{input, output, sheet, trust} => {
+            let mut state = open(&input, sheet.as_deref(), trust)?;
+            write(&mut state, &output)?;
+        },
+        Command::Recalc{file, sheet, trust, output} => {
+            let mut state = open(&file, sheet.as_deref(), trust)?;
+            match output {
+                Some(path) => write(&mut state, &path)?,
+                None => {
+                    let active = state.active();
+                    out.write_all(&csv::export(&mut state, active, &CsvOptions::csv()))?;
+                },
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Run the command of the process arguments, `None` when there is none.
+/// Otherwise the exit status, after reporting any error.
+pub fn main() -> Option<i32> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let result = parse(&args)?.and_then(|command| run(command, &mut io::stdout().lock()));
+    Some(match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            err.status()
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::CellIdx;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parse_args() {
+        assert!(parse(&args("book.rsheet --autosave")).is_none());
+        assert!(parse(&[]).is_none());
+        assert_eq!(parse(&args("eval book.rsheet --cell B7 -c Data!A1")).unwrap().unwrap(), Command::Eval{
+            file: PathBuf::from("book.rsheet"), sheet: None, trust: false, cells: vec!["B7".to_string(), "Data!A1".to_string()],
+        });
+        assert_eq!(parse(&args("recalc in.xlsx -s Data --trust -o out.ods")).unwrap().unwrap(), Command::Recalc{
+            file: PathBuf::from("in.xlsx"), sheet: Some("Data".to_string()), trust: true, output: Some(PathBuf::from("out.ods")),
+        });
+        assert!(matches!(parse(&args("eval book.rsheet")), Some(Err(CliError::Usage(_)))));
+        assert!(matches!(parse(&args("convert in.csv")), Some(Err(CliError::Usage(_)))));
+        assert!(matches!(parse(&args("recalc in.csv --cell")), Some(Err(CliError::Usage(_)))));
+        assert!(matches!(parse(&args("recalc in.csv --fast")), Some(Err(CliError::Usage(_)))));
+    }
+
+    #[test]
+    fn convert_and_eval() {
+        let name = |ext: &str| std::env::temp_dir().join(format!("rusty-sheet-cli-{}.{}", std::process::id(), ext));
+        let (native, xlsx_path, csv_path) = (name("rsheet"), name("xlsx"), name("csv"));
+        let mut state = SheetState::new();
+        for (col, row, text) in [(0, 0, "1"), (1, 0, "2"), (0, 1, "3"), (1, 1, "=A1+B1+A2")] {
+            state.sheet_mut().set_text(CellIdx{col, row}, text.to_string());
+        }
+        crate::rsheet::save_file(&mut state, &native).unwrap();
+
+        run(Command::Convert{input: native.clone(), output: xlsx_path.clone(), sheet: None, trust: false}, &mut vec![]).unwrap();
+        let mut out = vec![];
+        let eval = Command::Eval{file: xlsx_path.clone(), sheet: None, trust: false, cells: vec!["A2".to_string(), "Sheet1!B2".to_string()]};
+        run(eval, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "3\n6\n");
+
+        let mut out = vec![];
+        run(Command::Recalc{file: xlsx_path.clone(), sheet: None, trust: false, output: None}, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "1,2\r\n3,6\r\n");
+        run(Command::Recalc{file: native.clone(), sheet: None, trust: false, output: Some(csv_path.clone())}, &mut vec![]).unwrap();
+        assert_eq!(std::fs::read_to_string(&csv_path).unwrap(), "1,2\r\n3,6\r\n");
+
+        let mut state = open(&csv_path, None, false).unwrap();
+        assert!(!state.workbook.python_policy().trusted);
+        assert_eq!(state.get_value(&CellIdx{col: 1, row: 1}).to_string(), "6");
+        assert!(matches!(cell_value(&mut state, "A1:B2"), Err(CliError::Lookup(_))));
+        assert!(matches!(cell_value(&mut state, "Other!A1"), Err(CliError::Lookup(_))));
+        assert!(matches!(open(&csv_path, Some("Other"), true), Err(CliError::Lookup(_))));
+        assert!(matches!(write(&mut state, &name("bin")), Err(CliError::Usage(_))));
+        for path in [native, xlsx_path, csv_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
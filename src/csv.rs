@@ -0,0 +1,293 @@
+use std::{fs, io, path::Path};
+
+use crate::{sheet::{Sheet, CellIdx}, sheet_state::SheetState, workbook::{SheetId, CellPos}, value::Value, file::literal};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Utf8,
+    /// UTF-8 starting with a byte order mark, as written by Excel
+    Utf8Bom,
+    Latin1,
+}
+
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    /// Detected when reading if `None`, UTF-8 when writing
+    pub encoding: Option<Encoding>,
+    /// Whether the first row holds column names, detected when reading if `None`
+    pub header: Option<bool>,
+    /// Read fields starting with `=` as formulas, and write formulas rather than their values
+    pub formulas: bool,
+}
+
+impl CsvOptions {
+    pub fn csv() -> Self {
+        CsvOptions{delimiter: ',', encoding: None, header: None, formulas: false}
+    }
+
+    pub fn tsv() -> Self {
+        CsvOptions{delimiter: '\t', ..CsvOptions::csv()}
+    }
+
+    /// Tab separated for `.tsv` / `.tab` files, comma separated otherwise
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_ascii_lowercase()).as_deref() {
+            Some("tsv") | Some("tab") => CsvOptions::tsv(),
+            _ => CsvOptions::csv(),
+        }
+    }
+}
+
+/// What was found while importing
+#[derive(Clone, PartialEq, Debug)]
+pub struct CsvInfo {
+    pub encoding: Encoding,
+    pub header: bool,
+    pub rows: u32,
+    pub cols: u32,
+}
+
+/// Text of a file, in the given encoding or the detected one
+pub fn decode(bytes: &[u8], encoding: Option<Encoding>) -> (String, Encoding) {
+    let encoding = encoding.unwrap_or_else(|| {
+        if bytes.starts_with(BOM) {
+            Encoding::Utf8Bom
+        } else if std::str::from_utf8(bytes).is_ok() {
+            Encoding::Utf8
+        } else {
+            Encoding::Latin1
+        }
+    });
+    let text = match encoding {
+        Encoding::Latin1 => bytes.iter().map(|b| *b as char).collect(),
+        _ => String::from_utf8_lossy(bytes.strip_prefix(BOM).unwrap_or(bytes)).into_owned(),
+    };
+    (text, encoding)
+}
+
+/// Characters Latin-1 cannot hold are written as `?`
+pub fn encode(text: &str, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Utf8 => text.as_bytes().to_vec(),
+        Encoding::Utf8Bom => [BOM, text.as_bytes()].concat(),
+        Encoding::Latin1 => text.chars().map(|c| u8::try_from(c as u32).unwrap_or(b'?')).collect(),
+    }
+}
+
+/// Split RFC 4180 text into rows of fields.
+/// Quoted fields may hold delimiters, line breaks and doubled quotes; CRLF and LF both end a row.
+pub fn parse(text: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => { chars.next(); field.push('"'); },
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            '\r' if chars.peek() == Some(&'\n') => (),
+            '\n' | '\r' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            },
+            _ if c == delimiter => row.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+/// Join fields as an RFC 4180 line, quoting fields that need it
+pub fn write_row<S: AsRef<str>>(out: &mut String, fields: &[S], delimiter: char) {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(delimiter);
+        }
+        let field = field.as_ref();
+        if field.contains([delimiter, '"', '\r', '\n']) {
+            out.push('"');
+            out.push_str(&field.replace('"', "\"\""));
+            out.push('"');
+        } else {
+            out.push_str(field);
+        }
+    }
+    out.push_str("\r\n");
+}
+
+/// A first row of text only, above a column that holds something else (a number, a boolean)
+/// further down, is taken for column names
+pub fn detect_header(rows: &[Vec<String>]) -> bool {
+    let (first, rest) = match rows.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+    let is_text = |field: &str| matches!(Value::from_input(field), Value::Text(_));
+    if first.is_empty() || !first.iter().all(|field| is_text(field)) {
+        return false;
+    }
+    (0..first.len()).any(|col| {
+        rest.iter().any(|row| row.get(col).is_some_and(|field| !field.is_empty() && !is_text(field)))
+    })
+}
+
+/// Fill `sheet` from A1 with the content of a CSV / TSV file
+pub fn import(sheet: &mut Sheet, bytes: &[u8], options: &CsvOptions) -> CsvInfo {
+    let (text, encoding) = decode(bytes, options.encoding);
+    let rows = parse(&text, options.delimiter);
+    let header = options.header.unwrap_or_else(|| detect_header(&rows));
+
+    let mut cols = 0;
+    for (row, fields) in rows.iter().enumerate() {
+        cols = cols.max(fields.len());
+        for (col, field) in fields.iter().enumerate() {
+            if field.is_empty() {
+                continue;
+            }
+            // Column names stay text, and so do formulas unless asked otherwise. Other text reads back
+            // as it is, a leading apostrophe included.
+            let formula = field.starts_with('=');
+            let text = if formula && options.formulas {
+                field.clone()
+            } else if formula || (row == 0 && header) || matches!(Value::from_input(field), Value::Text(_)) {
+                literal(field.clone())
+            } else {
+                field.clone()
+            };
+            sheet.set_text(CellIdx{col: col as u32, row: row as u32}, text);
+        }
+    }
+    CsvInfo{encoding, header, rows: rows.len() as u32, cols: cols as u32}
+}
+
+/// Used part of a sheet as CSV / TSV, formulas written as entered or as their values
+pub fn export(sheet_state: &mut SheetState, sheet: SheetId, options: &CsvOptions) -> Vec<u8> {
+    let mut extent = match sheet_state.workbook.sheet(sheet) {
+        Some(sheet) => sheet.extent(),
+        None => return vec![],
+    };
+    if !options.formulas {
+        // Spilled elements are values too, formulas written as entered spill again
+        for idx in sheet_state.spilled_cells(sheet) {
+            extent.col = extent.col.max(idx.col + 1);
+            extent.row = extent.row.max(idx.row + 1);
+        }
+    }
+
+    let mut out = String::new();
+    for row in 0..extent.row {
+        let fields = (0..extent.col).map(|col| {
+            let idx = CellIdx{col, row};
+            if options.formulas {
+                sheet_state.workbook.sheet(sheet).unwrap().get_text(&idx)
+            } else {
+                sheet_state.get_value_at(&CellPos{sheet, idx}).to_string()
+            }
+        }).collect::<Vec<_>>();
+        write_row(&mut out, &fields, options.delimiter);
+    }
+    encode(&out, options.encoding.unwrap_or(Encoding::Utf8))
+}
+
+pub fn import_file(sheet: &mut Sheet, path: &Path, options: &CsvOptions) -> io::Result<CsvInfo> {
+    Ok(import(sheet, &fs::read(path)?, options))
+}
+
+pub fn export_file(sheet_state: &mut SheetState, sheet: SheetId, path: &Path, options: &CsvOptions) -> io::Result<()> {
+    fs::write(path, export(sheet_state, sheet, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        let text = "a,\"b,c\",\"say \"\"hi\"\"\"\r\n1,\"two\nlines\",\r\n,,3";
+        let rows = parse(text, ',');
+        assert_eq!(rows, vec![
+            vec!["a", "b,c", "say \"hi\""],
+            vec!["1", "two\nlines", ""],
+            vec!["", "", "3"],
+        ]);
+
+        let mut out = String::new();
+        for row in rows.iter() {
+            write_row(&mut out, row, ',');
+        }
+        assert_eq!(out, text.to_string() + "\r\n");
+        assert_eq!(parse("a\tb c\n", '\t'), vec![vec!["a", "b c"]]);
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(decode(b"\xef\xbb\xbfcaf\xc3\xa9", None), ("café".to_string(), Encoding::Utf8Bom));
+        assert_eq!(decode(b"caf\xc3\xa9", None), ("café".to_string(), Encoding::Utf8));
+        assert_eq!(decode(b"caf\xe9", None), ("café".to_string(), Encoding::Latin1));
+        assert_eq!(encode("café €", Encoding::Latin1), b"caf\xe9 ?".to_vec());
+        assert_eq!(encode("a", Encoding::Utf8Bom), b"\xef\xbb\xbfa".to_vec());
+    }
+
+    #[test]
+    fn header() {
+        let rows = |text| parse(text, ',');
+        assert!(detect_header(&rows("name,age\nbob,42")));
+        assert!(!detect_header(&rows("bob,42\nalice,7")));
+        assert!(!detect_header(&rows("name,city\nbob,paris")));
+    }
+
+    #[test]
+    fn import_export() {
+        let mut state = SheetState::new();
+        let id = state.active();
+        let info = import(state.sheet_mut(), b"year,total\n2021,=A2*2\n2022,TRUE", &CsvOptions::csv());
+        assert_eq!(info, CsvInfo{encoding: Encoding::Utf8, header: true, rows: 3, cols: 2});
+        assert_eq!(state.sheet().get_text(&CellIdx{col: 1, row: 1}), "'=A2*2");
+
+        let options = CsvOptions{formulas: true, ..CsvOptions::tsv()};
+        import(state.sheet_mut(), b"year\ttotal\n2021\t=A2*2", &options);
+        assert_eq!(export(&mut state, id, &CsvOptions::csv()), b"year,total\r\n2021,4042\r\n2022,TRUE\r\n".to_vec());
+        assert_eq!(export(&mut state, id, &options), b"year\ttotal\r\n2021\t=A2*2\r\n2022\tTRUE\r\n".to_vec());
+
+        // Leading apostrophes are part of the text
+        let mut state = SheetState::new();
+        let id = state.active();
+        let bytes = b"'90s,'007,42\r\n";
+        import(state.sheet_mut(), bytes, &CsvOptions{header: Some(false), ..CsvOptions::csv()});
+        assert_eq!(state.get_value(&CellIdx{col: 1, row: 0}), Value::from("'007"));
+        assert_eq!(state.get_value(&CellIdx{col: 2, row: 0}), Value::Number(42.0));
+        assert_eq!(export(&mut state, id, &CsvOptions::csv()), bytes.to_vec());
+    }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn spilled_values() {
+        use crate::{sheet::Cell, engine::EngineId};
+
+        let mut state = SheetState::new();
+        let id = state.active();
+        state.sheet_mut().insert(CellIdx{col: 0, row: 0}, Cell{engine: EngineId::PYTHON, value: "[[1, 2], [3, 4]]".to_string()});
+        let bytes = export(&mut state, id, &CsvOptions::csv());
+        assert_eq!(bytes, b"1,2\r\n3,4\r\n".to_vec());
+
+        let mut imported = SheetState::new();
+        import(imported.sheet_mut(), &bytes, &CsvOptions::csv());
+        assert_eq!(imported.get_value(&CellIdx{col: 1, row: 1}), Value::Number(4.0));
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{sheet::CellIdx, workbook::{CellPos, SheetId}};
+
+/// Rectangle of cells (zero based, inclusive), `None` spans the whole column / row
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Area {
+    pub cols: Option<(u32, u32)>,
+    pub rows: Option<(u32, u32)>,
+}
+
+impl Area {
+    pub fn contains(&self, idx: &CellIdx) -> bool {
+        let within = |bounds: Option<(u32, u32)>, val: u32| match bounds {
+            Some((start, end)) => start <= val && val <= end,
+            None => true,
+        };
+        within(self.cols, idx.col) && within(self.rows, idx.row)
+    }
+}
+
+/// Columns, or rows for areas spanning whole rows, of the index buckets
+const BAND: u32 = 64;
+
+fn bands((start, end): (u32, u32)) -> std::ops::RangeInclusive<u32> {
+    start / BAND..=end / BAND
+}
+
+/// Areas read on one sheet, in buckets for each band of columns they touch. Whole rows go by
+/// their band of rows instead, so finding the areas containing a cell looks at few of them.
+#[derive(Default)]
+struct AreaIndex {
+    cols: HashMap<u32, HashSet<Area>>,
+    rows: HashMap<u32, HashSet<Area>>,
+    /// Neither columns nor rows bounded
+    whole: HashSet<Area>,
+}
+
+impl AreaIndex {
+    fn insert(&mut self, area: &Area) {
+        match (area.cols, area.rows) {
+            (Some(cols), _) => bands(cols).for_each(|band| { self.cols.entry(band).or_default().insert(area.clone()); }),
+            (None, Some(rows)) => bands(rows).for_each(|band| { self.rows.entry(band).or_default().insert(area.clone()); }),
+            (None, None) => { self.whole.insert(area.clone()); },
+        }
+    }
+
+    fn remove(&mut self, area: &Area) {
+        let remove = |buckets: &mut HashMap<u32, HashSet<Area>>, band| {
+            if let Some(areas) = buckets.get_mut(&band) {
+                areas.remove(area);
+                if areas.is_empty() {
+                    buckets.remove(&band);
+                }
+            }
+        };
+        match (area.cols, area.rows) {
+            (Some(cols), _) => bands(cols).for_each(|band| remove(&mut self.cols, band)),
+            (None, Some(rows)) => bands(rows).for_each(|band| remove(&mut self.rows, band)),
+            (None, None) => { self.whole.remove(area); },
+        }
+    }
+
+    /// Areas containing `idx`
+    fn containing<'a>(&'a self, idx: &'a CellIdx) -> impl Iterator<Item = &'a Area> {
+        self.cols.get(&(idx.col / BAND)).into_iter().flatten()
+            .chain(self.rows.get(&(idx.row / BAND)).into_iter().flatten())
+            .chain(self.whole.iter())
+            .filter(move |area| area.contains(idx))
+    }
+}
+
+#[derive(Default)]
+struct Precedents {
+    cells: HashSet<CellPos>,
+    areas: HashSet<(SheetId, Area)>,
+}
+
+/// Which cells read which, as recorded while formulas resolve their references
+#[derive(Default)]
+pub struct DependencyGraph {
+    precedents: HashMap<CellPos, Precedents>,
+    dependents: HashMap<CellPos, HashSet<CellPos>>,
+    area_dependents: HashMap<(SheetId, Area), HashSet<CellPos>>,
+    /// Areas of `area_dependents` by sheet
+    areas: HashMap<SheetId, AreaIndex>,
+}
+
+impl DependencyGraph {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add_cell(&mut self, reader: &CellPos, read: &CellPos) {
+        let precedents = self.precedents.entry(reader.clone()).or_default();
+        if precedents.cells.insert(read.clone()) {
+            self.dependents.entry(read.clone()).or_default().insert(reader.clone());
+        }
+    }
+
+    pub fn add_area(&mut self, reader: &CellPos, sheet: SheetId, area: &Area) {
+        let area = (sheet, area.clone());
+        let precedents = self.precedents.entry(reader.clone()).or_default();
+        if precedents.areas.insert(area.clone()) {
+            let readers = self.area_dependents.entry(area.clone()).or_default();
+            if readers.is_empty() {
+                self.areas.entry(area.0).or_default().insert(&area.1);
+            }
+            readers.insert(reader.clone());
+        }
+    }
+
+    /// Forget everything `reader` read, before it is evaluated again
+    pub fn clear(&mut self, reader: &CellPos) {
+        let precedents = match self.precedents.remove(reader) {
+            Some(precedents) => precedents,
+            None => return,
+        };
+
+        for cell in precedents.cells {
+            if let Some(dependents) = self.dependents.get_mut(&cell) {
+                dependents.remove(reader);
+                if dependents.is_empty() {
+                    self.dependents.remove(&cell);
+                }
+            }
+        }
+        for area in precedents.areas {
+            if let Some(dependents) = self.area_dependents.get_mut(&area) {
+                dependents.remove(reader);
+                if dependents.is_empty() {
+                    self.area_dependents.remove(&area);
+                    if let Some(index) = self.areas.get_mut(&area.0) {
+                        index.remove(&area.1);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Cells that read `idx` directly or through a range
+    pub fn dependents(&self, pos: &CellPos) -> HashSet<CellPos> {
+        let mut res = self.dependents.get(pos).cloned().unwrap_or_default();
+        if let Some(index) = self.areas.get(&pos.sheet) {
+            for area in index.containing(&pos.idx) {
+                res.extend(self.area_dependents[&(pos.sheet, area.clone())].iter().cloned());
+            }
+        }
+        res
+    }
+
+    /// `pos` and every cell that depends on it, transitively
+    pub fn affected(&self, pos: &CellPos) -> HashSet<CellPos> {
+        let mut res = HashSet::new();
+        let mut pending = vec![pos.clone()];
+        while let Some(current) = pending.pop() {
+            if res.insert(current.clone()) {
+                pending.extend(self.dependents(&current));
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitive_dependents() {
+        let pos = |col, sheet| CellPos{sheet, idx: CellIdx{col, row: 0}};
+        let (a1, b1, c1, d1) = (pos(0, 0), pos(1, 0), pos(2, 0), pos(3, 0));
+        let other_a1 = pos(0, 1);
+
+        let mut graph = DependencyGraph::new();
+        graph.add_cell(&b1, &a1);
+        graph.add_area(&c1, 0, &Area{cols: Some((1, 1)), rows: None});
+        graph.add_cell(&d1, &d1);
+        graph.add_area(&d1, 1, &Area{cols: Some((0, 0)), rows: None});
+
+        assert_eq!(graph.affected(&a1), [a1.clone(), b1.clone(), c1.clone()].into_iter().collect());
+        assert_eq!(graph.affected(&d1), [d1.clone()].into_iter().collect());
+        assert_eq!(graph.affected(&other_a1), [other_a1.clone(), d1.clone()].into_iter().collect());
+
+        graph.clear(&c1);
+        assert_eq!(graph.affected(&a1), [a1.clone(), b1.clone()].into_iter().collect());
+    }
+
+    #[test]
+    fn area_index() {
+        let pos = |col, row| CellPos{sheet: 0, idx: CellIdx{col, row}};
+        let reader = pos(500, 500);
+        let mut graph = DependencyGraph::new();
+        graph.add_area(&reader, 0, &Area{cols: Some((10, 200)), rows: Some((0, 9))});
+        graph.add_area(&pos(501, 500), 0, &Area{cols: None, rows: Some((70, 70))});
+        graph.add_area(&pos(502, 500), 0, &Area{cols: None, rows: None});
+
+        let readers = |graph: &DependencyGraph, col, row| {
+            let mut readers = graph.dependents(&pos(col, row)).into_iter().map(|pos| pos.idx.col).collect::<Vec<_>>();
+            readers.sort();
+            readers
+        };
+        assert_eq!(readers(&graph, 150, 5), vec![500, 502]);
+        assert_eq!(readers(&graph, 150, 70), vec![501, 502]);
+        assert_eq!(readers(&graph, 5, 5), vec![502]);
+
+        graph.clear(&reader);
+        graph.clear(&pos(502, 500));
+        assert_eq!(readers(&graph, 150, 5), Vec::<u32>::new());
+        assert!(graph.areas[&0].cols.is_empty() && graph.areas[&0].whole.is_empty());
+    }
+}
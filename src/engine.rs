@@ -0,0 +1,171 @@
+use std::{collections::HashMap, fmt, sync::{Arc, OnceLock, RwLock}};
+
+use crate::{sheet_state::SheetState, value::Value, error::CellError, dependencies::Area, engine_simple::SimpleEngine, workbook::WorkbookId};
+#[cfg(feature = "python")]
+use crate::engine_python::PythonEngine;
+
+/// Engine of a cell, by the name it is registered under
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct EngineId(&'static str);
+
+impl EngineId {
+    pub const SIMPLE: EngineId = EngineId("simple");
+    #[cfg(feature = "python")]
+    pub const PYTHON: EngineId = EngineId("python");
+
+    pub const fn new(name: &'static str) -> Self {
+        EngineId(name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl fmt::Display for EngineId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Cells a formula refers to, on the sheet named or else the sheet of the formula
+#[derive(Clone, PartialEq, Debug)]
+pub struct Dependency {
+    pub sheet: Option<String>,
+    pub area: Area,
+}
+
+/// Turns the text of the cells using it into formulas
+pub trait Engine: Send + Sync {
+    fn id(&self) -> EngineId;
+
+    /// Formula of the (trimmed, non-empty) text of a cell, or the error shown when it does not parse
+    fn parse(&self, text: &str) -> Result<Box<dyn Formula>, CellError>;
+
+    /// Call a function the engine offers to formulas of other engines, by its upper case name.
+    /// `None` when the engine has no such function.
+    fn call(&self, _state: &mut SheetState, _name: &str, _args: &[Value]) -> Option<Value> {
+        None
+    }
+
+    /// Forget anything kept about a workbook, which is closed
+    fn close(&self, _workbook: WorkbookId) {}
+}
+
+/// Text of a cell as prepared by its engine, kept until the text changes
+pub trait Formula {
+    /// Value of the formula. Cells read through the state are recorded as dependencies as they are read.
+    fn evaluate(&self, state: &mut SheetState) -> Value;
+
+    /// Cells the formula refers to, known without evaluating it. Needed for cells the formula
+    /// looks at other than through `SheetState::get_value_at` or `get_area`, like their text.
+    fn dependencies(&self) -> Vec<Dependency> {
+        vec![]
+    }
+
+    /// Whether an array value spills over the empty cells right and below, each showing one element
+    fn spills(&self) -> bool {
+        false
+    }
+}
+
+fn engines() -> &'static RwLock<HashMap<EngineId, Arc<dyn Engine>>> {
+    static ENGINES: OnceLock<RwLock<HashMap<EngineId, Arc<dyn Engine>>>> = OnceLock::new();
+    ENGINES.get_or_init(|| {
+        let mut engines: HashMap<EngineId, Arc<dyn Engine>> = HashMap::new();
+        engines.insert(EngineId::SIMPLE, Arc::new(SimpleEngine));
+        #[cfg(feature = "python")]
+        engines.insert(EngineId::PYTHON, Arc::new(PythonEngine));
+        RwLock::new(engines)
+    })
+}
+
+/// Make an engine available to cells and files, replacing any engine of the same name
+pub fn register(engine: Arc<dyn Engine>) {
+    engines().write().unwrap().insert(engine.id(), engine);
+}
+
+pub fn get(id: EngineId) -> Option<Arc<dyn Engine>> {
+    engines().read().unwrap().get(&id).cloned()
+}
+
+/// Engine registered under `name`, as stored in files
+pub fn find(name: &str) -> Option<Arc<dyn Engine>> {
+    engines().read().unwrap().values().find(|engine| engine.id().name() == name).cloned()
+}
+
+/// Call a function offered by any of the engines, `None` when none has it
+pub fn call_function(state: &mut SheetState, name: &str, args: &[Value]) -> Option<Value> {
+    // Not holding the lock while calling, the function may evaluate cells of any engine
+    let engines = engines().read().unwrap().values().cloned().collect::<Vec<_>>();
+    engines.iter().find_map(|engine| engine.call(state, name, args))
+}
+
+/// Let the engines know a workbook is closed
+pub fn close(workbook: WorkbookId) {
+    let engines = engines().read().unwrap().values().cloned().collect::<Vec<_>>();
+    for engine in engines {
+        engine.close(workbook);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{Cell, CellIdx};
+
+    /// Length of the text of another cell, which is not read through the state
+    struct LengthEngine;
+
+    struct Length(CellIdx);
+
+    impl Engine for LengthEngine {
+        fn id(&self) -> EngineId {
+            EngineId::new("length")
+        }
+
+        fn parse(&self, text: &str) -> Result<Box<dyn Formula>, CellError> {
+            let (col, row) = text.split_once(',').ok_or_else(|| CellError::new(crate::error::ErrorKind::Parse, "Expected col,row"))?;
+            let idx = CellIdx{col: col.parse().unwrap(), row: row.parse().unwrap()};
+            Ok(Box::new(Length(idx)))
+        }
+
+        fn call(&self, _state: &mut SheetState, name: &str, args: &[Value]) -> Option<Value> {
+            (name == "LENGTH").then(|| Value::Number(args.iter().map(|arg| arg.to_string().len()).sum::<usize>() as f64))
+        }
+    }
+
+    impl Formula for Length {
+        fn evaluate(&self, state: &mut SheetState) -> Value {
+            Value::Number(state.sheet().get_text(&self.0).len() as f64)
+        }
+
+        fn dependencies(&self) -> Vec<Dependency> {
+            let CellIdx{col, row} = self.0;
+            vec![Dependency{sheet: None, area: Area{cols: Some((col, col)), rows: Some((row, row))}}]
+        }
+    }
+
+    #[test]
+    fn third_party_engine() {
+        let length = EngineId::new("length");
+        let mut state = SheetState::new();
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 0}, "abc".to_string());
+        state.sheet_mut().insert(CellIdx{col: 1, row: 0}, Cell{engine: length, value: "0,0".to_string()});
+        assert_eq!(state.get_value(&CellIdx{col: 1, row: 0}).to_string(), "#NAME?");
+
+        register(Arc::new(LengthEngine));
+        assert_eq!(find("length").map(|engine| engine.id()), Some(length));
+        state.sheet_mut().insert(CellIdx{col: 1, row: 1}, Cell{engine: length, value: "0,0".to_string()});
+        assert_eq!(state.get_value(&CellIdx{col: 1, row: 1}).to_string(), "3");
+        // Known from the reported dependency only
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 0}, "abcdef".to_string());
+        assert_eq!(state.get_value(&CellIdx{col: 1, row: 1}).to_string(), "6");
+        state.sheet_mut().insert(CellIdx{col: 1, row: 2}, Cell{engine: length, value: "zero".to_string()});
+        assert_eq!(state.get_value(&CellIdx{col: 1, row: 2}).to_string(), "#ERROR!");
+        assert!(find("simple").is_some());
+
+        state.sheet_mut().set_text(CellIdx{col: 2, row: 0}, "=LENGTH(\"ab\", A1)+1".to_string());
+        assert_eq!(state.get_value(&CellIdx{col: 2, row: 0}).to_string(), "9");
+    }
+}
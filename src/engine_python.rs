@@ -1,52 +1,1228 @@
+use std::{cell::{Cell, RefCell}, collections::HashSet, os::raw::c_int, rc::Rc, thread};
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}, mpsc::{self, Receiver, RecvTimeoutError, Sender}};
+use std::time::{Duration, Instant};
 
-use crate::engine_simple;
+use crate::engine_simple::{self, Expr};
 use crate::sheet_state::SheetState;
+use crate::value::Value;
+use crate::workbook::{CellPos, PythonPolicy, SheetId, Workbook, WorkbookId};
+use crate::error::{CellError, ErrorKind, Exception};
+use crate::engine::{Engine, EngineId, Formula};
 
-use pyo3::prelude::*;
-//use pyo3::types::IntoPyDict;
-use pyo3::types::PyDict;
-use pyo3::types::PyString;
+use pyo3::{ffi, prelude::*};
+use pyo3::exceptions::{PyKeyError, PyRuntimeError, PyValueError};
+use pyo3::types::{PyBool, PyDict, PyList, PyString, PyTuple};
 
+/// What Python code reads of the workbook, answered by the thread evaluating the cell
+enum Read {
+    /// Value of a cell like `A1`
+    Cell(String),
+    /// Values of a range like `A1:C3`, as an array
+    Range(String),
+    /// Value of a formula of the simple engine, without its leading `=`
+    Formula(String),
+}
+
+/// Why a read failed, raised in Python as the exception of the same name
+enum ReadError {
+    Key(String),
+    Value(String),
+    Runtime(String),
+}
+
+impl From<ReadError> for PyErr {
+    fn from(err: ReadError) -> Self {
+        match err {
+            ReadError::Key(message) => PyKeyError::new_err(message),
+            ReadError::Value(message) => PyValueError::new_err(message),
+            ReadError::Runtime(message) => PyRuntimeError::new_err(message),
+        }
+    }
+}
+
+fn parse(text: &str) -> Result<Expr, ReadError> {
+    engine_simple::parse(&format!("={}", text.trim())).map_err(|err| ReadError::Value(err.message))
+}
+
+fn resolve(state: &SheetState, sheet: Option<String>) -> Result<SheetId, ReadError> {
+    state.resolve_sheet(sheet.as_deref()).map_err(|err| ReadError::Key(err.message))
+}
+
+/// Answer a read of Python code, on the thread evaluating its cell
+fn read(state: &mut SheetState, read: Read) -> Result<Value, ReadError> {
+    match read {
+        Read::Cell(reference) => match parse(&reference)? {
+            Expr::Reference(sheet, cell) => {
+                let sheet = resolve(state, sheet)?;
+                Ok(state.get_value_at(&CellPos{sheet, idx: cell.idx()}))
+            },
+            _ => Err(ReadError::Value(format!("\"{}\" is not a cell", reference))),
+        },
+        Read::Range(reference) => match parse(&reference)? {
+            Expr::Range(sheet, range) => {
+                let sheet = resolve(state, sheet)?;
+                Ok(Value::Array(state.get_area(sheet, &range.area())))
+            },
+            _ => Err(ReadError::Value(format!("\"{}\" is not a range", reference))),
+        },
+        Read::Formula(input) => {
+            let expr = parse(&input)?;
+            Ok(engine_simple::eval(state, &expr))
+        },
+    }
+}
+
+/// The cell evaluation Python code runs for, to send reads to
+struct Link {
+    events: Sender<Event>,
+    deadline: Option<Deadline>,
+}
+
+impl Link {
+    fn read(&self, py: Python<'_>, read: Read) -> PyResult<Value> {
+        static NEXT_READ: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_READ.fetch_add(1, Ordering::Relaxed);
+        self.events.send(Event::Read(id, read))
+            .map_err(|_| PyRuntimeError::new_err("The cell is no longer evaluated"))?;
+        // Without the GIL, the evaluating thread may have cells of its own run meanwhile
+        let deadline = self.deadline;
+        match py.allow_threads(move || wait_reply(id, deadline)) {
+            Some(value) => Ok(value?),
+            None => {
+                let limit = deadline.map_or(0.0, |deadline| deadline.limit.as_secs_f64());
+                Err(runner(py, "timed_out")?.call1((limit,)).expect_err("timed_out returned"))
+            },
+        }
+    }
+}
+
+/// The `sheet` of Python cells, one for all of them, reading the workbook only while a cell is evaluated
+#[pyclass(unsendable, name = "Sheet")]
+struct SheetHandle {
+    /// The evaluation under way, taken out while Python code waits for what it read
+    link: RefCell<Option<Link>>,
+}
+
+impl SheetHandle {
+    fn read(&self, py: Python<'_>, read: Read) -> PyResult<Value> {
+        let link = self.link.borrow_mut().take()
+            .ok_or_else(|| PyRuntimeError::new_err("The sheet is only available while its cell is evaluated"))?;
+        let value = link.read(py, read);
+        *self.link.borrow_mut() = Some(link);
+        value
+    }
+}
+
+/// The evaluation the handle reads for, giving back what the handle held before (nothing, or the
+/// link of an outer evaluation taken out) when dropped
+struct Lend<'a> {
+    handle: &'a SheetHandle,
+    previous: Option<Link>,
+}
+
+impl<'a> Lend<'a> {
+    fn new(handle: &'a SheetHandle, link: Link) -> Self {
+        let previous = handle.link.replace(Some(link));
+        Lend{handle, previous}
+    }
+}
+
+impl Drop for Lend<'_> {
+    fn drop(&mut self) {
+        *self.handle.link.borrow_mut() = self.previous.take();
+    }
+}
+
+/// Error value of a cell as seen from Python
+#[pyclass(name = "CellError")]
+struct PyCellError {
+    /// Spreadsheet code like `#DIV/0!`
+    #[pyo3(get)]
+    code: String,
+    #[pyo3(get)]
+    message: String,
+}
+
+#[pymethods]
+impl PyCellError {
+    fn __repr__(&self) -> String {
+        self.code.clone()
+    }
+}
+
+/// Python object for a value: `None`, `float`, `str`, `bool`, a `CellError` or rows of lists
+fn to_py(py: Python<'_>, value: &Value) -> PyResult<PyObject> {
+    Ok(match value {
+        Value::Empty => py.None(),
+        Value::Number(n) => n.into_py(py),
+        Value::Text(text) => text.into_py(py),
+        Value::Boolean(b) => b.into_py(py),
+        Value::Error(err) => Py::new(py, PyCellError{code: err.kind.code().to_string(), message: err.message.clone()})?.into_py(py),
+        Value::Array(rows) => rows_to_py(py, rows)?.into_py(py),
+    })
+}
+
+fn rows_to_py<'py>(py: Python<'py>, rows: &[Vec<Value>]) -> PyResult<&'py PyList> {
+    let rows = rows.iter()
+        .map(|row| Ok(PyList::new(py, row.iter().map(|value| to_py(py, value)).collect::<PyResult<Vec<_>>>()?)))
+        .collect::<PyResult<Vec<_>>>()?;
+    Ok(PyList::new(py, rows))
+}
+
+/// Items of a list or tuple, `None` for anything else
+fn items(obj: &PyAny) -> Option<Vec<&PyAny>> {
+    if let Ok(list) = obj.downcast::<PyList>() {
+        Some(list.iter().collect())
+    } else if let Ok(tuple) = obj.downcast::<PyTuple>() {
+        Some(tuple.iter().collect())
+    } else {
+        None
+    }
+}
+
+/// Value of a Python object. Lists are a column, lists of lists rows, a pandas frame rows under its
+/// column names, and anything unknown its `str()`.
+fn from_py(obj: &PyAny) -> PyResult<Value> {
+    if obj.is_none() {
+        return Ok(Value::Empty);
+    }
+    if let Ok(b) = obj.downcast::<PyBool>() {
+        return Ok(Value::Boolean(b.is_true()));
+    }
+    if let Ok(text) = obj.downcast::<PyString>() {
+        return Ok(Value::Text(text.to_str()?.to_string()));
+    }
+    if let Ok(err) = obj.extract::<PyRef<PyCellError>>() {
+        let kind = ErrorKind::from_code(&err.code).unwrap_or(ErrorKind::Value);
+        return Ok(Value::error(kind, err.message.clone()));
+    }
+    if let Ok(n) = obj.extract::<f64>() {
+        if !n.is_finite() {
+            return Ok(Value::error(ErrorKind::Num, "Result is not a finite number"));
+        }
+        return Ok(Value::Number(n));
+    }
+    if let Some(list) = items(obj) {
+        let rows = list.into_iter().map(|item| match items(item) {
+            Some(row) => row.into_iter().map(from_py).collect(),
+            None => Ok(vec![from_py(item)?]),
+        }).collect::<PyResult<Vec<_>>>()?;
+        return Ok(Value::Array(rows));
+    }
+    if obj.hasattr("columns")? && obj.hasattr("to_numpy")? {
+        let columns = obj.getattr("columns")?.call_method0("tolist")?;
+        let header = items(columns).unwrap_or_default().into_iter().map(from_py).collect::<PyResult<Vec<_>>>()?;
+        let mut rows = vec![header];
+        if let Value::Array(body) = from_py(obj.call_method0("to_numpy")?.call_method0("tolist")?)? {
+            rows.extend(body);
+        }
+        return Ok(Value::Array(rows));
+    }
+    // numpy arrays and scalars, pandas series
+    if obj.hasattr("tolist")? {
+        return from_py(obj.call_method0("tolist")?);
+    }
+    Ok(Value::Text(obj.str()?.to_string()))
+}
+
+impl SheetHandle {
+    fn values(&self, py: Python<'_>, reference: &str) -> PyResult<Vec<Vec<Value>>> {
+        match self.read(py, Read::Range(reference.to_string()))? {
+            Value::Array(rows) => Ok(rows),
+            _ => Ok(vec![]),
+        }
+    }
+}
+
+#[pymethods]
+impl SheetHandle {
+    /// Value of a cell like `A1` or `'Q1 Data'!B4`
+    fn cell(&self, py: Python<'_>, reference: &str) -> PyResult<PyObject> {
+        let value = self.read(py, Read::Cell(reference.to_string()))?;
+        to_py(py, &value)
+    }
+
+    /// Values of a range like `A1:C3`, as a list of rows
+    fn range<'py>(&self, py: Python<'py>, reference: &str) -> PyResult<&'py PyList> {
+        rows_to_py(py, &self.values(py, reference)?)
+    }
+
+    /// Values of a range as a numpy array, when numpy is installed
+    fn array<'py>(&self, py: Python<'py>, reference: &str) -> PyResult<&'py PyAny> {
+        let rows = self.range(py, reference)?;
+        py.import("numpy")?.call_method1("array", (rows,))
+    }
+
+    /// Values of a range as a pandas DataFrame, when pandas is installed.
+    /// The first row names the columns unless `header` is false.
+    fn frame<'py>(&self, py: Python<'py>, reference: &str, header: Option<bool>) -> PyResult<&'py PyAny> {
+        let values = self.values(py, reference)?;
+        let pandas = py.import("pandas")?;
+        match values.split_first() {
+            Some((columns, rows)) if header.unwrap_or(true) => {
+                let columns = columns.iter().map(|value| to_py(py, value)).collect::<PyResult<Vec<_>>>()?;
+                let kwargs = PyDict::new(py);
+                kwargs.set_item("columns", columns)?;
+                pandas.call_method("DataFrame", (rows_to_py(py, rows)?,), Some(kwargs))
+            },
+            _ => pandas.call_method1("DataFrame", (rows_to_py(py, &values)?,)),
+        }
+    }
+}
+
+/// Value of a formula of the simple engine, without its leading `=`, as text
 #[pyfunction]
-fn cell(py: Python<'_>, sheet: SheetWrapper, input: String) -> PyResult<&PyString> {
-    let text;
-    unsafe {
-        let ref_sheet: &mut SheetState = &mut *sheet.state_ptr;
-        let str = "=".to_string() + &input;
-        text = engine_simple::calc(ref_sheet, str.as_str())
+fn cell(py: Python<'_>, sheet: PyRef<SheetHandle>, input: &str) -> PyResult<String> {
+    Ok(sheet.read(py, Read::Formula(input.to_string()))?.to_string())
+}
+
+/// Helpers running cell code and the workbook module, with `<cell>` / `<workbook>` in tracebacks
+const RUNNER: &str = r#"
+import ast, builtins, linecache, traceback, types
+
+class CellTimeout(BaseException):
+    """Raised in code running for too long, not an Exception so that the code does not catch it by accident"""
+
+def timed_out(limit):
+    raise CellTimeout(f'Stopped after running for {limit:g} seconds')
+
+def call(func, *args):
+    try:
+        return func(*args)
+    except BaseException:
+        # Caught on the way out, so that the exception keeps its traceback for `describe`
+        raise
+
+# Pure modules, without anything looking up attributes by name like operator.attrgetter or string.Formatter
+SAFE_MODULES = {
+    'bisect', 'cmath', 'collections', 'datetime', 'decimal', 'fractions', 'functools', 'heapq',
+    'itertools', 'json', 'math', 're', 'statistics',
+}
+
+SAFE_BUILTINS = {
+    'abs', 'all', 'any', 'ascii', 'bin', 'bool', 'callable', 'chr', 'complex', 'dict', 'divmod',
+    'enumerate', 'filter', 'float', 'format', 'frozenset', 'hash', 'hex', 'int', 'isinstance',
+    'issubclass', 'iter', 'len', 'list', 'map', 'max', 'min', 'next', 'oct', 'ord', 'pow', 'print',
+    'range', 'repr', 'reversed', 'round', 'set', 'slice', 'sorted', 'str', 'sum', 'tuple', 'zip',
+}
+
+# Attributes leading to frames, and from there to the globals and builtins of other code
+FRAME_ATTRIBUTES = ('gi_', 'cr_', 'ag_', 'f_', 'tb_', 'co_')
+
+PROXIES = {}
+
+def proxy(module):
+    """The public names of a module, without the modules it imports for itself"""
+    if module.__name__ in PROXIES:
+        return PROXIES[module.__name__]
+    public = PROXIES[module.__name__] = types.SimpleNamespace()
+    names = getattr(module, '__all__', None) or [name for name in vars(module) if not name.startswith('_')]
+    for name in names:
+        value = getattr(module, name, None)
+        if isinstance(value, types.ModuleType):
+            if value.__name__.partition('.')[0] not in SAFE_MODULES:
+                continue
+            value = proxy(value)
+        setattr(public, name, value)
+    return public
+
+def safe_import(name, globals=None, locals=None, fromlist=(), level=0):
+    if level == 0 and name.partition('.')[0] in SAFE_MODULES:
+        return proxy(builtins.__import__(name, globals, locals, fromlist, level))
+    raise ImportError(f'Importing {name} is not allowed for restricted Python')
+
+RESTRICTED = {name: value for name, value in vars(builtins).items()
+    if name in SAFE_BUILTINS or isinstance(value, type) and issubclass(value, BaseException)}
+RESTRICTED['__import__'] = safe_import
+
+def check_restricted(tree):
+    """Refuse code reaching what restricted Python leaves out: names and attributes starting with
+    underscores, which lead to classes, globals and the real builtins, and frames"""
+    for node in ast.walk(tree):
+        if isinstance(node, ast.Name) and node.id.startswith('__'):
+            raise NameError(f'{node.id} is not available to restricted Python (line {node.lineno})')
+        if isinstance(node, ast.Attribute):
+            attributes = [node.attr]
+        elif isinstance(node, getattr(ast, 'MatchClass', ())):
+            attributes = node.kwd_attrs
+        elif isinstance(node, (ast.Import, ast.ImportFrom)):
+            attributes = [part for alias in node.names for part in alias.name.split('.')]
+            attributes += (node.module or '').split('.') if isinstance(node, ast.ImportFrom) else []
+        else:
+            continue
+        for attribute in attributes:
+            if attribute.startswith('_') or attribute.startswith(FRAME_ATTRIBUTES):
+                raise AttributeError(f'{attribute} is not available to restricted Python (line {node.lineno})')
+
+class RestrictedSheet:
+    """The sheet of restricted code, without numpy and pandas objects that write files"""
+
+    def __init__(self, sheet):
+        self._sheet = sheet
+
+    def cell(self, reference):
+        return self._sheet.cell(reference)
+
+    def range(self, reference):
+        return self._sheet.range(reference)
+
+def run_cell(code, scope, restricted):
+    tree = ast.parse(code, '<cell>', 'exec')
+    if restricted:
+        check_restricted(tree)
+        sheet, cell = scope['sheet'], scope['cell']
+        scope['sheet'] = RestrictedSheet(sheet)
+        scope['cell'] = lambda _, formula: cell(sheet, formula)
+    last = tree.body.pop() if tree.body and isinstance(tree.body[-1], ast.Expr) else None
+
+    def run():
+        exec(compile(tree, '<cell>', 'exec'), scope)
+        if last is not None:
+            return eval(compile(ast.Expression(last.value), '<cell>', 'eval'), scope)
+
+    return call(run)
+
+def describe(error, code, module):
+    """Type name, message and traceback of an exception, leaving out the calls of these helpers"""
+    # Lines of the cell and the module for the traceback, the cell's code may have changed since
+    for filename, source in (('<cell>', code), ('<workbook>', module)):
+        linecache.cache[filename] = (len(source), None, source.splitlines(True), filename)
+    here = describe.__code__.co_filename
+    frames = [frame for frame in traceback.extract_tb(error.__traceback__) if frame.filename != here]
+    lines = traceback.format_list(frames) + traceback.format_exception_only(type(error), error)
+    if frames:
+        lines.insert(0, 'Traceback (most recent call last):\n')
+    return type(error).__name__, str(error), ''.join(lines).rstrip()
+
+def run_module(source, scope, restricted):
+    functions = {}
+
+    def sheet_function(func=None, *, name=None):
+        """Make a function callable from formulas, by its name in upper case unless `name` is given"""
+        def register(func):
+            functions[(name or func.__name__).upper()] = func
+            return func
+        return register if func is None else register(func)
+
+    scope['sheet_function'] = sheet_function
+    scope['__sheet_functions__'] = functions
+    tree = ast.parse(source, '<workbook>', 'exec')
+    if restricted:
+        check_restricted(tree)
+    call(exec, compile(tree, '<workbook>', 'exec'), scope)
+
+def sheet_function_names(source):
+    """Names `@sheet_function` gives to functions of the module, read from its source without running it"""
+    try:
+        tree = ast.parse(source, '<workbook>')
+    except SyntaxError:
+        return []
+    names = []
+    for node in ast.walk(tree):
+        if not isinstance(node, (ast.FunctionDef, ast.AsyncFunctionDef)):
+            continue
+        for decorator in node.decorator_list:
+            call = decorator if isinstance(decorator, ast.Call) else None
+            target = call.func if call else decorator
+            if not isinstance(target, ast.Name) or target.id != 'sheet_function':
+                continue
+            name = node.name
+            for keyword in call.keywords if call else ():
+                if keyword.arg == 'name' and isinstance(keyword.value, ast.Constant) and isinstance(keyword.value.value, str):
+                    name = keyword.value.value
+            names.append(name.upper())
+    return names
+"#;
+
+/// Workbook, source and whether restricted of a module run, along with its globals
+type CachedModule = (WorkbookId, String, bool, Py<PyDict>);
+
+thread_local! {
+    static RUNNER_SCOPE: RefCell<Option<Py<PyDict>>> = const { RefCell::new(None) };
+    static HANDLE: RefCell<Option<Py<SheetHandle>>> = const { RefCell::new(None) };
+    /// The last workbook module run
+    static MODULE: RefCell<Option<CachedModule>> = const { RefCell::new(None) };
+    /// Names of the `@sheet_function`s of the last workbook module looked at, along with its source
+    static FUNCTIONS: RefCell<Option<(String, Rc<HashSet<String>>)>> = const { RefCell::new(None) };
+    /// On a Python thread, the deadline of the code running
+    static DEADLINE: Cell<Option<Deadline>> = const { Cell::new(None) };
+    /// On a Python thread, its end of the requests
+    static INBOX: RefCell<Option<Rc<Inbox>>> = const { RefCell::new(None) };
+    /// On a thread evaluating cells, the Python thread running their code
+    static WORKER: RefCell<Option<Worker>> = const { RefCell::new(None) };
+    /// On a thread evaluating cells, the top level evaluation under way and its deadline, kept by
+    /// the cells and functions it calls
+    static EVALUATION: Cell<Option<(u64, Option<Deadline>)>> = const { Cell::new(None) };
+}
+
+fn runner<'py>(py: Python<'py>, name: &str) -> PyResult<&'py PyAny> {
+    let scope = match RUNNER_SCOPE.with(|scope| scope.borrow().as_ref().map(|scope| scope.clone_ref(py))) {
+        Some(scope) => scope,
+        None => {
+            let scope = PyDict::new(py);
+            py.run(RUNNER, Some(scope), None)?;
+            let scope: Py<PyDict> = scope.into();
+            RUNNER_SCOPE.with(|cached| *cached.borrow_mut() = Some(scope.clone_ref(py)));
+            scope
+        },
+    };
+    Ok(scope.into_ref(py).get_item(name).expect("Missing Python helper"))
+}
+
+fn handle(py: Python<'_>) -> PyResult<Py<SheetHandle>> {
+    if let Some(handle) = HANDLE.with(|handle| handle.borrow().as_ref().map(|handle| handle.clone_ref(py))) {
+        return Ok(handle);
+    }
+    let handle = Py::new(py, SheetHandle{link: RefCell::new(None)})?;
+    HANDLE.with(|cached| *cached.borrow_mut() = Some(handle.clone_ref(py)));
+    Ok(handle)
+}
+
+const UNTRUSTED: &str = "Python code of the workbook does not run until it is trusted";
+
+/// The Python module of a workbook, and how its code may run
+struct Module {
+    workbook: WorkbookId,
+    source: String,
+    policy: PythonPolicy,
+}
+
+impl Module {
+    fn of(workbook: &Workbook) -> Self {
+        Module{workbook: workbook.id(), source: workbook.python_module().to_string(), policy: workbook.python_policy()}
     }
+}
+
+/// When a top level evaluation, with all the cells and functions it calls, has to be done
+#[derive(Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    limit: Duration,
+}
+
+impl Deadline {
+    fn left(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+}
 
-    let str = PyString::new(py, text.as_str());
+/// Raise `CellTimeout` in Python code past the deadline, checked on each line. Unlike a function of
+/// `sys.settrace`, it stays in place once it raised: code catching the exception is stopped again.
+unsafe extern "C" fn check_deadline(_: *mut ffi::PyObject, _: *mut ffi::PyFrameObject, what: c_int, _: *mut ffi::PyObject) -> c_int {
+    match DEADLINE.with(Cell::get) {
+        Some(deadline) if what == ffi::PyTrace_LINE && Instant::now() > deadline.at => {
+            let py = Python::assume_gil_acquired();
+            match runner(py, "timed_out").and_then(|timed_out| timed_out.call1((deadline.limit.as_secs_f64(),))) {
+                Err(err) => err.restore(py),
+                Ok(_) => unreachable!("timed_out returned"),
+            }
+            -1
+        },
+        _ => 0,
+    }
+}
 
-    Ok(str)
+/// The deadline Python code is held to while alive, giving back the one before when dropped
+struct Watch<'py> {
+    py: Python<'py>,
+    previous: Option<Deadline>,
 }
 
-#[pyclass(unsendable)]
+impl<'py> Watch<'py> {
+    fn new(py: Python<'py>, deadline: Option<Deadline>) -> Self {
+        trace(py, deadline);
+        Watch{py, previous: DEADLINE.with(|checked| checked.replace(deadline))}
+    }
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        DEADLINE.with(|checked| checked.set(self.previous));
+        trace(self.py, self.previous);
+    }
+}
+
+/// Check the deadline in Python code of this thread, if there is one
+fn trace(_py: Python<'_>, deadline: Option<Deadline>) {
+    unsafe { ffi::PyEval_SetTrace(deadline.map(|_| check_deadline as ffi::Py_tracefunc), std::ptr::null_mut()) };
+}
+
+/// Globals defined by the workbook module, run again only when its workbook, source or restriction
+/// changes. Cells run in a copy of them, so names a cell assigns stay its own. The objects named are
+/// the module's though: a list or dict of the module changed by one cell is changed for all of them.
+fn module_scope<'py>(py: Python<'py>, module: &Module) -> PyResult<&'py PyDict> {
+    let cached = MODULE.with(|cached| match cached.borrow().as_ref() {
+        Some((workbook, source, restricted, scope))
+            if *workbook == module.workbook && *source == module.source && *restricted == module.policy.restricted => Some(scope.clone_ref(py)),
+        _ => None,
+    });
+    if let Some(scope) = cached {
+        return Ok(scope.into_ref(py));
+    }
+
+    let scope = PyDict::new(py);
+    scope.set_item("__name__", "workbook")?;
+    if module.policy.restricted {
+        scope.set_item("__builtins__", runner(py, "RESTRICTED")?)?;
+    }
+    // A module that fails is run again next time, without leaving the one of another workbook in place
+    MODULE.with(|cached| *cached.borrow_mut() = None);
+    runner(py, "run_module")?.call1((&module.source, scope, module.policy.restricted))?;
+    let owned: Py<PyDict> = scope.into();
+    MODULE.with(|cached| *cached.borrow_mut() = Some((module.workbook, module.source.clone(), module.policy.restricted, owned)));
+    Ok(scope)
+}
+
+/// Upper case names of the `@sheet_function`s of a module, none when it does not parse
+fn function_names(py: Python<'_>, source: &str) -> PyResult<Rc<HashSet<String>>> {
+    let cached = FUNCTIONS.with(|cached| match cached.borrow().as_ref() {
+        Some((cached, names)) if cached == source => Some(names.clone()),
+        _ => None,
+    });
+    if let Some(names) = cached {
+        return Ok(names);
+    }
+    let names = runner(py, "sheet_function_names")?.call1((source,))?.extract::<Vec<String>>()?;
+    let names = Rc::new(names.into_iter().collect::<HashSet<_>>());
+    FUNCTIONS.with(|cached| *cached.borrow_mut() = Some((source.to_string(), names.clone())));
+    Ok(names)
+}
+
+/// Call a `@sheet_function` of the workbook module, `None` when there is none of that name
+fn call_function(py: Python<'_>, module: &Module, name: &str, args: &[Value]) -> PyResult<Option<Value>> {
+    let functions = match module_scope(py, module)?.get_item("__sheet_functions__") {
+        Some(functions) => functions.downcast::<PyDict>()?,
+        None => return Ok(None),
+    };
+    let function = match functions.get_item(name) {
+        Some(function) => function,
+        None => return Ok(None),
+    };
+    let mut call = vec![function.into_py(py)];
+    for arg in args {
+        call.push(to_py(py, arg)?);
+    }
+    from_py(runner(py, "call")?.call1(PyTuple::new(py, call))?).map(Some)
+}
+
+/// Run the code of a cell, valued by its last expression
+fn eval(py: Python<'_>, sheet: &Py<SheetHandle>, module: &Module, text: &str) -> PyResult<Value> {
+    // Names the cell assigns go to its copy of the module's globals, the objects they held stay shared
+    let scope = module_scope(py, module)?.copy()?;
+
+    let fun = pyo3::wrap_pyfunction!(cell, py)?;
+    scope.set_item("cell", fun)?;
+    scope.set_item("sheet", sheet)?;
+
+    from_py(runner(py, "run_cell")?.call1((text, scope, module.policy.restricted))?)
+}
+
+/// Error value of an exception raised running `code` of a cell, or a function of `module`
+fn error_value(py: Python<'_>, err: PyErr, code: &str, module: &str) -> Value {
+    // Described however late it is
+    let _watch = Watch::new(py, None);
+    let error = CellError::new(ErrorKind::Python, err.to_string());
+    let described = runner(py, "describe")
+        .and_then(|describe| describe.call1((err.into_py(py), code, module))?.extract::<(String, String, String)>());
+    match described {
+        Ok((kind, message, traceback)) => Value::Error(error.with_exception(Exception{kind, message, traceback})),
+        Err(_) => Value::Error(error),
+    }
+}
+
+/// A cell or call for the Python thread, with where its reads and value go
+struct Job {
+    /// Top level evaluation it is part of
+    top: u64,
+    module: Module,
+    deadline: Option<Deadline>,
+    events: Sender<Event>,
+}
+
+enum Task {
+    /// Code of a Python cell
+    Cell(String),
+    /// A `@sheet_function` by its upper case name, with its arguments
+    Call(String, Vec<Value>),
+}
+
+/// Messages to a Python thread
+enum Request {
+    Run(Job, Task),
+    /// Answer to the read of that number
+    Reply(u64, Result<Value, ReadError>),
+    /// A workbook was closed
+    Close(WorkbookId),
+    #[cfg(test)]
+    Inspect(Box<dyn FnOnce() + Send>),
+}
+
+/// Messages from a Python thread about a job
+enum Event {
+    Read(u64, Read),
+    /// The value, `None` for a function the module does not have
+    Done(Option<Value>),
+}
+
+/// What a thread evaluating cells and its Python thread know of each other, for giving up on it
+#[derive(Default)]
+struct Shared {
+    /// Top level evaluation the Python thread is working on, 0 for none
+    running: AtomicU64,
+    /// Latest top level evaluation given up on. Those before it are over or given up on too.
+    expired: AtomicU64,
+    /// Top level evaluation given up on while running, and when
+    stuck: Mutex<Option<(u64, Instant)>>,
+}
+
+/// How long code given up on gets to stop before its Python thread is left to it and replaced
+const WEDGED: Duration = Duration::from_secs(1);
+
+impl Shared {
+    /// Still running code given up on a while ago, which may never stop, like a loop around a long call
+    fn wedged(&self) -> bool {
+        let running = self.running.load(Ordering::SeqCst);
+        matches!(*self.stuck.lock().unwrap(), Some((top, since)) if top == running && since.elapsed() > WEDGED)
+    }
+}
+
+/// The Python thread's end
+struct Inbox {
+    requests: Receiver<Request>,
+    shared: Arc<Shared>,
+}
+
+/// The Python thread's end, on the thread evaluating cells
 #[derive(Clone)]
-struct SheetWrapper {
-     state_ptr: *mut SheetState
+struct Worker {
+    requests: Sender<Request>,
+    shared: Arc<Shared>,
 }
 
-pub fn calc(sheet_state: &mut SheetState, text: &str) -> String {
-    let res: PyResult<String> = Python::with_gil(|py| {
-        let locals = PyDict::new(py);
+/// The Python thread of this thread, started on first use and stopping when this thread does.
+/// Code runs there so that a cell running too long holds up nothing but Python. A thread stuck in
+/// code given up on is left to it between evaluations, for a new one with modules of its own.
+fn worker() -> Worker {
+    WORKER.with(|worker| {
+        let mut worker = worker.borrow_mut();
+        if EVALUATION.with(Cell::get).is_none() && worker.as_ref().is_some_and(|worker| worker.shared.wedged()) {
+            *worker = None;
+        }
+        worker.get_or_insert_with(spawn).clone()
+    })
+}
 
-        let fun = pyo3::wrap_pyfunction!(cell, py)?;
-        locals.set_item("cell", fun)?;
+fn spawn() -> Worker {
+    let (requests, inbox) = mpsc::channel();
+    let shared = Arc::new(Shared::default());
+    let inbox = Inbox{requests: inbox, shared: shared.clone()};
+    thread::Builder::new().name("python".to_string()).spawn(move || {
+        let inbox = Rc::new(inbox);
+        INBOX.with(|cached| *cached.borrow_mut() = Some(inbox.clone()));
+        while let Ok(request) = inbox.requests.recv() {
+            serve(request);
+        }
+    }).expect("Could not start the Python thread");
+    Worker{requests, shared}
+}
+
+/// Handle a request on the Python thread
+fn serve(request: Request) {
+    match request {
+        Request::Run(job, task) => run(job, task),
+        // For a read given up on
+        Request::Reply(..) => {},
+        Request::Close(workbook) => MODULE.with(|cached| {
+            let mut cached = cached.borrow_mut();
+            if cached.as_ref().is_some_and(|(id, ..)| *id == workbook) {
+                *cached = None;
+            }
+        }),
+        #[cfg(test)]
+        Request::Inspect(func) => func(),
+    }
+}
+
+fn run(job: Job, task: Task) {
+    let inbox = INBOX.with(|inbox| inbox.borrow().clone()).expect("Not a Python thread");
+    // Nobody waits for an evaluation given up on before it got its turn
+    if job.top <= inbox.shared.expired.load(Ordering::SeqCst) {
+        let _ = job.events.send(Event::Done(None));
+        return;
+    }
+    let value = Python::with_gil(|py| {
+        let previous = inbox.shared.running.swap(job.top, Ordering::SeqCst);
+        let value = match task {
+            Task::Cell(code) => {
+                let value = handle(py).and_then(|sheet| {
+                    // Lent for this evaluation only, a handle Python holds on to reaches nothing afterwards
+                    let handle = sheet.borrow(py);
+                    let _lend = Lend::new(&handle, Link{events: job.events.clone(), deadline: job.deadline});
+                    let _watch = Watch::new(py, job.deadline);
+                    eval(py, &sheet, &job.module, &code)
+                });
+                Some(value.unwrap_or_else(|err| error_value(py, err, &code, &job.module.source)))
+            },
+            Task::Call(name, args) => {
+                // Other names are unknown functions, whatever the module or its trust
+                if !function_names(py, &job.module.source).is_ok_and(|names| names.contains(&name)) {
+                    None
+                } else if !job.module.policy.trusted {
+                    Some(Value::error(ErrorKind::Python, UNTRUSTED))
+                } else {
+                    let value = {
+                        let _watch = Watch::new(py, job.deadline);
+                        call_function(py, &job.module, &name, &args)
+                    };
+                    value.unwrap_or_else(|err| Some(error_value(py, err, "", &job.module.source)))
+                }
+            },
+        };
+        inbox.shared.running.store(previous, Ordering::SeqCst);
+        value
+    });
+    let _ = job.events.send(Event::Done(value));
+}
 
-        //let obj: &PyAny = Py::new(py, SheetWrapper { &state })?.into_ref(py);
-        let obj = SheetWrapper{state_ptr: sheet_state as *mut SheetState};
-        locals.set_item("sheet", obj.into_py(py))?;
-        //let res = fun.call1((vec![1_u32, 2, 3],))?;
-        println!("Evaluating \"{:}\"", text);
-        let res: String = py.eval(text, None, Some(locals))?.str()?.to_string();
+/// Wait on the Python thread for the answer to a read, running the cells and calls it takes
+/// meanwhile. `None` once the deadline passed.
+fn wait_reply(id: u64, deadline: Option<Deadline>) -> Option<Result<Value, ReadError>> {
+    let inbox = INBOX.with(|inbox| inbox.borrow().clone()).expect("Not a Python thread");
+    loop {
+        let request = match deadline {
+            Some(deadline) => inbox.requests.recv_timeout(deadline.left()),
+            None => inbox.requests.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match request {
+            Ok(Request::Reply(read, value)) if read == id => return Some(value),
+            Ok(request) => serve(request),
+            Err(RecvTimeoutError::Timeout) => return None,
+            Err(RecvTimeoutError::Disconnected) => return Some(Err(ReadError::Runtime("The workbook is gone".to_string()))),
+        }
+    }
+}
 
-        Ok(res)
+/// Have the Python thread run a task, answering its reads from `state` meanwhile, until the
+/// deadline of the top level evaluation
+fn submit(mut state: Option<&mut SheetState>, module: Module, task: Task) -> Option<Value> {
+    static NEXT_TOP: AtomicU64 = AtomicU64::new(1);
+    let outer = EVALUATION.with(Cell::get);
+    let (top, deadline) = outer.unwrap_or_else(|| {
+        let deadline = module.policy.time_limit.map(|limit| Deadline{at: Instant::now() + limit, limit});
+        (NEXT_TOP.fetch_add(1, Ordering::Relaxed), deadline)
     });
+    let worker = worker();
+    let (events, received) = mpsc::channel();
+    if worker.requests.send(Request::Run(Job{top, module, deadline, events}, task)).is_err() {
+        return Some(Value::error(ErrorKind::Python, "Python stopped"));
+    }
 
-    match res {
-        Ok(str) => str,
-        _ => "Error".to_string()
+    EVALUATION.with(|evaluation| evaluation.set(Some((top, deadline))));
+    let value = loop {
+        let event = match deadline {
+            Some(deadline) => received.recv_timeout(deadline.left()),
+            None => received.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Ok(Event::Done(value)) => break value,
+            Ok(Event::Read(id, request)) => {
+                let value = match state.as_deref_mut() {
+                    Some(state) => read(state, request),
+                    None => Err(ReadError::Runtime("The sheet is only available while its cell is evaluated".to_string())),
+                };
+                let _ = worker.requests.send(Request::Reply(id, value));
+            },
+            Err(RecvTimeoutError::Timeout) => break Some(give_up(&worker, top, deadline.unwrap())),
+            Err(RecvTimeoutError::Disconnected) => break Some(Value::error(ErrorKind::Python, "Python stopped")),
+        }
+    };
+    EVALUATION.with(|evaluation| evaluation.set(outer));
+    value
+}
+
+/// Error of an evaluation past its deadline, rather than holding up this thread. Its code stops on
+/// the next line it runs, once back in Python from a long call.
+fn give_up(worker: &Worker, top: u64, deadline: Deadline) -> Value {
+    let shared = &worker.shared;
+    let started = shared.running.load(Ordering::SeqCst) == top;
+    shared.expired.fetch_max(top, Ordering::SeqCst);
+    if started {
+        let mut stuck = shared.stuck.lock().unwrap();
+        if !stuck.is_some_and(|(stuck, _)| stuck == top) {
+            *stuck = Some((top, Instant::now()));
+        }
     }
+    let message = if started {
+        format!("Stopped after running for {} seconds", deadline.limit.as_secs_f64())
+    } else {
+        "Not started, Python is still busy with code that ran out of time".to_string()
+    };
+    let error = CellError::new(ErrorKind::Python, format!("CellTimeout: {}", message));
+    let traceback = error.message.clone();
+    Value::Error(error.with_exception(Exception{kind: "CellTimeout".to_string(), message, traceback}))
+}
 
+pub fn calc(sheet_state: &mut SheetState, text: &str) -> Value {
+    let module = Module::of(&sheet_state.workbook);
+    if !module.policy.trusted {
+        return Value::error(ErrorKind::Python, UNTRUSTED);
+    }
+    submit(Some(sheet_state), module, Task::Cell(text.to_string())).unwrap_or(Value::Empty)
+}
+
+/// Cells holding Python code, valued by its last line and spilling lists and frames
+pub struct PythonEngine;
+
+struct PythonFormula(String);
+
+impl Engine for PythonEngine {
+    fn id(&self) -> EngineId {
+        EngineId::PYTHON
+    }
+
+    fn parse(&self, text: &str) -> Result<Box<dyn Formula>, CellError> {
+        Ok(Box::new(PythonFormula(text.to_string())))
+    }
+
+    fn call(&self, state: &mut SheetState, name: &str, args: &[Value]) -> Option<Value> {
+        // No need to start Python for a workbook without functions of its own
+        if state.workbook.python_module().is_empty() {
+            return None;
+        }
+        submit(None, Module::of(&state.workbook), Task::Call(name.to_string(), args.to_vec()))
+    }
+
+    fn close(&self, workbook: WorkbookId) {
+        // Nothing to tell a Python thread never started, or gone with this thread already
+        let _ = WORKER.try_with(|worker| {
+            if let Some(worker) = worker.borrow().as_ref() {
+                let _ = worker.requests.send(Request::Close(workbook));
+            }
+        });
+    }
+}
+
+impl Formula for PythonFormula {
+    fn evaluate(&self, sheet_state: &mut SheetState) -> Value {
+        calc(sheet_state, &self.0)
+    }
+
+    fn spills(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{Cell, CellIdx};
+
+    fn python(state: &mut SheetState, idx: CellIdx, code: &str) -> Value {
+        state.sheet_mut().insert(idx.clone(), Cell{engine: EngineId::PYTHON, value: code.to_string()});
+        state.get_value(&idx)
+    }
+
+    /// Run `func` on the Python thread of this thread
+    fn on_python_thread<T: Send + 'static>(func: impl FnOnce() -> T + Send + 'static) -> T {
+        let (sender, result) = mpsc::channel();
+        worker().requests.send(Request::Inspect(Box::new(move || { let _ = sender.send(func()); }))).unwrap();
+        result.recv().unwrap()
+    }
+
+    #[test]
+    fn python_sheet_handle() {
+        let mut state = SheetState::new();
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 0}, "1".to_string());
+        state.sheet_mut().set_text(CellIdx{col: 1, row: 0}, "=A1*2".to_string());
+
+        assert_eq!(python(&mut state, CellIdx{col: 2, row: 0}, "sheet.cell('B1')"), Value::Number(2.0));
+        assert_eq!(python(&mut state, CellIdx{col: 2, row: 2}, "cell(sheet, 'A1+B1')"), Value::from("3"));
+        // Read through the handle, so a change is seen
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 0}, "5".to_string());
+        assert_eq!(state.get_value(&CellIdx{col: 2, row: 0}), Value::Number(10.0));
+
+        let error = |value: Value| match value {
+            Value::Error(err) if err.kind == ErrorKind::Python => err.message,
+            value => panic!("Expected an error, got {:?}", value),
+        };
+        assert!(error(python(&mut state, CellIdx{col: 3, row: 0}, "sheet.cell('A1:B1')")).contains("ValueError"));
+        assert!(error(python(&mut state, CellIdx{col: 3, row: 3}, "sheet.range('A1')")).contains("ValueError"));
+        assert!(error(python(&mut state, CellIdx{col: 3, row: 1}, "sheet.cell('Nope!A1')")).contains("KeyError"));
+        assert!(error(python(&mut state, CellIdx{col: 3, row: 2}, "cell(sheet, '1+')")).contains("ValueError"));
+
+        // A handle kept past its evaluation no longer reaches the workbook, like from a function called by a formula
+        state.workbook.set_python_module("kept = []\n@sheet_function\ndef peek():\n    return kept[0].cell('A1')\n");
+        python(&mut state, CellIdx{col: 4, row: 0}, "kept.append(sheet)");
+        state.sheet_mut().set_text(CellIdx{col: 4, row: 1}, "=PEEK()".to_string());
+        let kept = error(state.get_value(&CellIdx{col: 4, row: 1}));
+        assert!(kept.contains("RuntimeError"), "{}", kept);
+        python(&mut state, CellIdx{col: 4, row: 0}, "kept.clear()");
+        state.workbook.set_python_module("");
+    }
+
+    #[test]
+    fn python_ranges() {
+        let mut state = SheetState::new();
+        for (col, row, text) in [(0, 0, "name"), (1, 0, "price"), (0, 1, "tea"), (1, 1, "2.5"), (0, 2, "TRUE"), (1, 2, "=1/0")] {
+            state.sheet_mut().set_text(CellIdx{col, row}, text.to_string());
+        }
+        assert_eq!(python(&mut state, CellIdx{col: 3, row: 0}, "str(sheet.range('A1:C3'))"),
+            Value::from("[['name', 'price', None], ['tea', 2.5, None], [True, #DIV/0!, None]]"));
+        assert_eq!(python(&mut state, CellIdx{col: 3, row: 1}, "sheet.range('B2:B3')[1][0].message"), Value::from("Division by zero"));
+        assert_eq!(python(&mut state, CellIdx{col: 3, row: 2}, "sum(row[1] for row in sheet.range('A2:B2'))"), Value::Number(2.5));
+
+        // numpy and pandas are optional
+        let array = python(&mut state, CellIdx{col: 4, row: 0}, "str(sheet.array('B2:B2').tolist())");
+        let frame = python(&mut state, CellIdx{col: 4, row: 1}, "str(list(sheet.frame('A1:B2').columns))");
+        for (value, module, expected) in [(array, "numpy", "[[2.5]]"), (frame, "pandas", "['name', 'price']")] {
+            match value {
+                Value::Error(err) => assert!(err.message.contains(&format!("No module named '{}'", module)), "{}", err.message),
+                value => assert_eq!(value, Value::from(expected)),
+            }
+        }
+    }
+
+    #[test]
+    fn python_statements_and_module() {
+        let mut state = SheetState::new();
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 0}, "4".to_string());
+        let code = "total = 0\nfor n in range(int(sheet.cell('A1'))):\n    total += n\ntotal";
+        assert_eq!(python(&mut state, CellIdx{col: 1, row: 0}, code), Value::Number(6.0));
+        assert_eq!(python(&mut state, CellIdx{col: 1, row: 1}, "unused = 1"), Value::Empty);
+        // Names of one cell are not seen by another
+        let leaked = python(&mut state, CellIdx{col: 1, row: 2}, "total");
+        assert!(matches!(leaked, Value::Error(ref err) if err.message.contains("NameError")), "{:?}", leaked);
+
+        state.workbook.set_python_module("import math\n\ndef hypot(a, b):\n    return math.sqrt(a * a + b * b)\n");
+        assert_eq!(python(&mut state, CellIdx{col: 2, row: 0}, "hypot(sheet.cell('A1'), 3)"), Value::Number(5.0));
+        state.workbook.set_python_module("def hypot(a, b):\n    return a + b\n");
+        assert_eq!(state.get_value(&CellIdx{col: 2, row: 0}), Value::Number(7.0));
+
+        // Objects of the module are shared by the cells
+        state.workbook.set_python_module("totals = {}\n");
+        python(&mut state, CellIdx{col: 3, row: 0}, "totals['a'] = 1");
+        assert_eq!(python(&mut state, CellIdx{col: 3, row: 1}, "totals.get('a')"), Value::Number(1.0));
+
+        state.workbook.set_python_module("def broken(:\n");
+        match state.get_value(&CellIdx{col: 2, row: 0}) {
+            Value::Error(err) => assert!(err.message.contains("SyntaxError"), "{}", err.message),
+            value => panic!("Expected an error, got {:?}", value),
+        }
+        // Only the cells running the module see its error, unknown functions stay unknown
+        state.sheet_mut().set_text(CellIdx{col: 2, row: 1}, "=NOPE(1)".to_string());
+        assert_eq!(state.get_value(&CellIdx{col: 2, row: 1}).error_kind(), Some(ErrorKind::Name));
+    }
+
+    #[test]
+    fn python_module_per_workbook() {
+        let source = "seen = []\n";
+        let (mut first, mut second) = (SheetState::new(), SheetState::new());
+        first.workbook.set_python_module(source);
+        second.workbook.set_python_module(source);
+        python(&mut first, CellIdx{col: 0, row: 0}, "seen.append(1)");
+        assert_eq!(python(&mut first, CellIdx{col: 0, row: 1}, "len(seen)"), Value::Number(1.0));
+        assert_eq!(python(&mut second, CellIdx{col: 0, row: 1}, "len(seen)"), Value::Number(0.0));
+
+        // Closing the workbook lets go of its module
+        let id = second.workbook.id();
+        drop(second);
+        assert_ne!(on_python_thread(|| MODULE.with(|cached| cached.borrow().as_ref().map(|(workbook, ..)| *workbook))), Some(id));
+    }
+
+    #[test]
+    fn python_sheet_functions() {
+        let mut state = SheetState::new();
+        state.workbook.set_python_module(r#"
+@sheet_function
+def discount(price, rate):
+    return price * (1 - rate)
+
+@sheet_function(name="joined")
+def join_values(rows, sep):
+    return sep.join(str(value) for row in rows for value in row if value is not None)
+
+@sheet_function
+def table(n):
+    return [[i, i * i] for i in range(int(n))]
+
+@sheet_function
+def fails():
+    raise ValueError("no luck")
+"#);
+        for (row, text) in ["80", "tea", "", "TRUE"].iter().enumerate() {
+            state.sheet_mut().set_text(CellIdx{col: 0, row: row as u32}, text.to_string());
+        }
+        let mut eval = |text: &str| {
+            state.sheet_mut().set_text(CellIdx{col: 1, row: 0}, text.to_string());
+            state.get_value(&CellIdx{col: 1, row: 0})
+        };
+        assert_eq!(eval("=DISCOUNT(A1, 0.25)*2"), Value::Number(120.0));
+        assert_eq!(eval("=JOINED(A1:A4, \"-\")"), Value::from("80.0-tea-True"));
+        assert_eq!(eval("=SUM(TABLE(3))"), Value::Number(8.0));
+        assert_eq!(eval("=NOSUCH(1)").error_kind(), Some(ErrorKind::Name));
+        match eval("=FAILS()") {
+            Value::Error(err) => assert!(err.kind == ErrorKind::Python && err.message.contains("no luck"), "{:?}", err),
+            value => panic!("Expected an error, got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn python_typed_values_and_spilling() {
+        let mut state = SheetState::new();
+        let value = |state: &mut SheetState, col, row| state.get_value(&CellIdx{col, row});
+        assert_eq!(python(&mut state, CellIdx{col: 0, row: 0}, "5.2"), Value::Number(5.2));
+        assert_eq!(python(&mut state, CellIdx{col: 0, row: 0}, "1 > 0"), Value::Boolean(true));
+        assert_eq!(python(&mut state, CellIdx{col: 0, row: 0}, "float('nan')").error_kind(), Some(ErrorKind::Num));
+        assert_eq!(python(&mut state, CellIdx{col: 0, row: 0}, "None"), Value::Empty);
+
+        // A reader evaluated before the spill sees it once it happens
+        state.sheet_mut().set_text(CellIdx{col: 3, row: 0}, "=SUM(A1:B3)".to_string());
+        assert_eq!(value(&mut state, 3, 0), Value::Number(0.0));
+        python(&mut state, CellIdx{col: 0, row: 0}, "[[n, n * 10] for n in range(1, 4)]");
+        assert_eq!(value(&mut state, 1, 2), Value::Number(30.0));
+        assert_eq!(value(&mut state, 3, 0), Value::Number(66.0));
+
+        // A flat list is a column
+        python(&mut state, CellIdx{col: 0, row: 0}, "['a', 'b']");
+        assert_eq!(value(&mut state, 0, 1), Value::from("b"));
+        assert_eq!(value(&mut state, 1, 0), Value::Empty);
+        assert_eq!(value(&mut state, 0, 2), Value::Empty);
+
+        // Still known to spill when the sheets change, with the covered cell read first
+        state.workbook.add_sheet("Other").unwrap();
+        assert_eq!(value(&mut state, 0, 1), Value::from("b"));
+
+        // Cells in the way block the spill until cleared
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 1}, "x".to_string());
+        assert_eq!(value(&mut state, 0, 0), Value::error(ErrorKind::Spill, "A2 is in the way of the result"));
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 1}, "".to_string());
+        assert_eq!(value(&mut state, 0, 1), Value::from("b"));
+
+        // A cell can read the cells it spills over
+        assert_eq!(python(&mut state, CellIdx{col: 5, row: 0}, "[sheet.cell('F2'), 2]"), Value::Empty);
+        assert_eq!(value(&mut state, 5, 1), Value::Number(2.0));
+
+        // Frames spill with their column names on top, when pandas is there
+        let frame = python(&mut state, CellIdx{col: 7, row: 0}, "import pandas\npandas.DataFrame({'n': [1, 2]})");
+        match frame {
+            Value::Error(err) => assert!(err.message.contains("No module named 'pandas'"), "{}", err.message),
+            _ => assert_eq!(value(&mut state, 7, 2), Value::Number(2.0)),
+        }
+    }
+
+    #[test]
+    fn python_policy() {
+        let mut state = SheetState::new();
+        let message = |value: Value| match value {
+            Value::Error(err) if err.kind == ErrorKind::Python => err.message,
+            value => panic!("Expected an error, got {:?}", value),
+        };
+        state.workbook.set_python_module("@sheet_function\ndef spin():\n    while True:\n        pass\n");
+        state.sheet_mut().set_text(CellIdx{col: 1, row: 0}, "=SPIN()".to_string());
+        let policy = state.workbook.python_policy();
+
+        state.workbook.set_python_policy(PythonPolicy{trusted: false, ..policy});
+        assert_eq!(message(python(&mut state, CellIdx{col: 0, row: 0}, "1 + 1")), UNTRUSTED);
+        assert_eq!(message(state.get_value(&CellIdx{col: 1, row: 0})), UNTRUSTED);
+        // A typo is no function of the module
+        state.sheet_mut().set_text(CellIdx{col: 1, row: 1}, "=SPINN()".to_string());
+        assert_eq!(state.get_value(&CellIdx{col: 1, row: 1}).error_kind(), Some(ErrorKind::Name));
+        state.workbook.set_python_policy(policy);
+        assert_eq!(state.get_value(&CellIdx{col: 0, row: 0}), Value::Number(2.0));
+
+        // Stopped even when the code catches exceptions
+        let limit = PythonPolicy{time_limit: Some(std::time::Duration::from_millis(100)), ..policy};
+        state.workbook.set_python_policy(limit);
+        let code = "while True:\n    try:\n        pass\n    except Exception:\n        pass";
+        assert!(message(python(&mut state, CellIdx{col: 0, row: 0}, code)).contains("CellTimeout"));
+        assert!(message(state.get_value(&CellIdx{col: 1, row: 0})).contains("CellTimeout"));
+        assert_eq!(python(&mut state, CellIdx{col: 0, row: 0}, "sum(range(10))"), Value::Number(45.0));
+
+        state.workbook.set_python_policy(PythonPolicy{restricted: true, ..limit});
+        assert_eq!(python(&mut state, CellIdx{col: 0, row: 0}, "import math\nmath.floor(2.5)"), Value::Number(2.0));
+        assert!(message(python(&mut state, CellIdx{col: 0, row: 0}, "import os")).contains("ImportError"));
+        assert!(message(python(&mut state, CellIdx{col: 0, row: 0}, "__import__('subprocess')")).contains("NameError"));
+        assert!(message(python(&mut state, CellIdx{col: 0, row: 0}, "open('/etc/hostname')")).contains("NameError"));
+        // Nothing reaches the modules others import, nor the real builtins
+        let escapes = [
+            "import collections\ncollections._sys.modules['os'].system('true')",
+            "import statistics\nstatistics.sys.modules['os']",
+            "from collections import _sys",
+            "().__class__.__base__.__subclasses__()",
+            "eval('1')",
+            "getattr((), '__class__')",
+            "(lambda: (yield))().gi_frame",
+            "sheet.array('A1')",
+        ];
+        for code in escapes {
+            let escaped = message(python(&mut state, CellIdx{col: 0, row: 0}, code));
+            assert!(["AttributeError", "NameError"].iter().any(|kind| escaped.contains(kind)), "{}: {}", code, escaped);
+        }
+        assert_eq!(python(&mut state, CellIdx{col: 0, row: 0}, "import json\nsheet.cell('B3') or json.dumps([1])"), Value::from("[1]"));
+        state.workbook.set_python_policy(limit);
+        assert_eq!(python(&mut state, CellIdx{col: 0, row: 0}, "import os\nos.name != ''"), Value::Boolean(true));
+    }
+
+    #[test]
+    fn python_time_limit() {
+        let mut state = SheetState::new();
+        let policy = state.workbook.python_policy();
+        let message = |value: Value| match value {
+            Value::Error(err) if err.kind == ErrorKind::Python => err.message,
+            value => panic!("Expected an error, got {:?}", value),
+        };
+
+        // Stopped again when catching the timeout
+        state.workbook.set_python_policy(PythonPolicy{time_limit: Some(Duration::from_millis(100)), ..policy});
+        let code = "while True:\n    try:\n        while True:\n            pass\n    except BaseException:\n        pass";
+        assert!(message(python(&mut state, CellIdx{col: 2, row: 0}, code)).contains("CellTimeout"));
+        assert_eq!(python(&mut state, CellIdx{col: 2, row: 1}, "1"), Value::Number(1.0));
+
+        // Given up on while in a call that does not come back, without holding up this thread,
+        // and left to it on a thread of its own after a while
+        let started = Instant::now();
+        assert!(message(python(&mut state, CellIdx{col: 1, row: 0}, "import time\ntime.sleep(3600)")).contains("Stopped after"));
+        assert!(message(python(&mut state, CellIdx{col: 1, row: 1}, "1")).contains("busy"));
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+        thread::sleep(WEDGED + Duration::from_millis(200));
+        assert_eq!(python(&mut state, CellIdx{col: 1, row: 2}, "1"), Value::Number(1.0));
+
+        // Cells read by a cell share its deadline
+        state.workbook.set_python_policy(PythonPolicy{time_limit: Some(Duration::from_millis(300)), ..policy});
+        for (row, code) in [(1, "import time\ntime.sleep(0.25)\nsheet.cell('A3')"), (2, "import time\ntime.sleep(0.25)\n3")] {
+            state.sheet_mut().insert(CellIdx{col: 0, row}, Cell{engine: EngineId::PYTHON, value: code.to_string()});
+        }
+        let started = Instant::now();
+        assert!(message(python(&mut state, CellIdx{col: 0, row: 0}, "sheet.cell('A2')")).contains("CellTimeout"));
+        assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn python_tracebacks() {
+        let mut state = SheetState::new();
+        state.workbook.set_python_module("def check(n):\n    if n > 2:\n        raise ValueError(f'{n} is too big')\n    return n\n");
+
+        python(&mut state, CellIdx{col: 0, row: 0}, "x = 1\ny = x / 0");
+        let exception = state.exception(&CellIdx{col: 0, row: 0}).unwrap();
+        assert_eq!((exception.kind.as_str(), exception.message.as_str()), ("ZeroDivisionError", "division by zero"));
+        assert!(exception.traceback.starts_with("Traceback (most recent call last):"), "{}", exception.traceback);
+        assert!(exception.traceback.contains("File \"<cell>\", line 2, in <module>\n    y = x / 0"), "{}", exception.traceback);
+        assert!(!exception.traceback.contains("run_cell"), "{}", exception.traceback);
+        assert!(exception.traceback.ends_with("ZeroDivisionError: division by zero"), "{}", exception.traceback);
+
+        // Through the module and into the cells reading the failed one
+        python(&mut state, CellIdx{col: 0, row: 0}, "check(3)");
+        state.sheet_mut().set_text(CellIdx{col: 1, row: 0}, "=A1*2".to_string());
+        let exception = state.exception(&CellIdx{col: 1, row: 0}).unwrap();
+        assert!(exception.traceback.contains("line 3, in check\n    raise ValueError"), "{}", exception.traceback);
+        state.sheet_mut().set_text(CellIdx{col: 1, row: 0}, "=CHECK(4)".to_string());
+        state.workbook.set_python_module("@sheet_function\ndef check(n):\n    raise KeyError(n)\n");
+        assert_eq!(state.exception(&CellIdx{col: 1, row: 0}).unwrap().kind, "KeyError");
+
+        assert!(state.exception(&CellIdx{col: 0, row: 0}).unwrap().traceback.contains("line 3, in check"));
+        python(&mut state, CellIdx{col: 0, row: 0}, "1 +");
+        assert_eq!(state.exception(&CellIdx{col: 0, row: 0}).unwrap().kind, "SyntaxError");
+        state.sheet_mut().set_text(CellIdx{col: 2, row: 0}, "=1/0".to_string());
+        assert_eq!(state.exception(&CellIdx{col: 2, row: 0}), None);
+    }
 }
@@ -1,54 +1,529 @@
 
+use std::{cmp::Ordering, fmt};
 
-use pest::{self, Parser};
+use pest::{self, Parser, iterators::Pair, error::LineColLocation};
 
-use crate::{sheet_state::SheetState, sheet::CellIdx};
+use crate::{
+    sheet_state::SheetState, value::Value, functions, error::{CellError, ErrorKind},
+    reference::{Axis, CellRef, Coord, RangeRef, MAX_ROWS, str_to_col, sheet_to_str}, workbook::CellPos,
+    engine::{Engine, EngineId, Formula, Dependency}, dependencies::Area, sheet::CellIdx,
+};
 
 
 #[derive(pest_derive::Parser)]
 #[grammar = "simple.pest"] // relative to src
 struct SimpleParser;
 
-fn str_to_col(s: &str) -> u32 {
-    let mut col: u32 = 0;
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnaryOp {
+    Negate,
+    Plus,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+    Concat,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expr {
+    Number(f64),
+    Text(String),
+    Boolean(bool),
+    /// Error literal, also left behind by references that fell off the sheet
+    Error(CellError),
+    /// Reference, to another sheet when named
+    Reference(Option<String>, CellRef),
+    Range(Option<String>, RangeRef),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// Upper case function name and its arguments
+    Function(String, Vec<Expr>),
+}
+
+/// Parts of a reference in order of appearance, coordinates with their `$` anchors
+#[derive(Default)]
+struct RefParts {
+    sheet: Option<String>,
+    cols: Vec<Coord>,
+    rows: Vec<Coord>,
+}
 
-    let mut mult = s.len() as u32 - 1;
-    for c in s.chars() {
-        let val = c as u32 - 'A' as u32;
-        if mult == 0 {
-            col += val;
-        } else {
-            col += (val+1) * mult * 26;
-            mult -= 1;
+fn build_parts(pair: Pair<Rule>, parts: &mut RefParts) -> Result<(), CellError> {
+    let mut absolute = false;
+    for part in pair.into_inner() {
+        match part.as_rule() {
+            Rule::SheetPrefix => {
+                let name = part.into_inner().next().unwrap();
+                parts.sheet = Some(match name.as_rule() {
+                    Rule::QuotedSheetName => name.as_str().replace("''", "'"),
+                    _ => name.as_str().to_string(),
+                });
+            },
+            Rule::Cell => build_parts(part, parts)?,
+            Rule::ColAbs | Rule::RowAbs => { absolute = true; continue; },
+            Rule::Alphas => {
+                let col = str_to_col(part.as_str())
+                    .ok_or_else(|| CellError::new(ErrorKind::Ref, format!("Column {} does not exist", part.as_str())))?;
+                parts.cols.push(Coord{index: col, absolute});
+            },
+            _ => {
+                let row = match part.as_str().parse::<u32>() {
+                    Ok(0) => return Err(CellError::new(ErrorKind::Ref, "Row 0 does not exist")),
+                    Ok(row) if row <= MAX_ROWS => row,
+                    _ => return Err(CellError::new(ErrorKind::Ref, format!("Row {} does not exist", part.as_str()))),
+                };
+                parts.rows.push(Coord{index: row - 1, absolute});
+            },
         }
+        absolute = false;
     }
-    col
+    Ok(())
 }
 
-pub fn calc(sheet_state: &mut SheetState, text: &str) -> String
-{
-    let parsed = SimpleParser::parse(Rule::Expr, text);
-    match parsed {
-        Ok(pairs) => {
-            for pair in pairs {
-                if let Rule::Expr = pair.as_rule() {
-                    let pair = pair.into_inner().next().unwrap();
-                    let mut pair = pair.into_inner();
-                    let alphas = pair.next().unwrap();
-                    let digits = pair.next().unwrap();
-
-                    let col = str_to_col(alphas.as_str());
-                    let row = digits.as_str().parse::<u32>().unwrap();
-                    if row != 0 {
-                        let crow = row - 1;
-                        return sheet_state.get_value(&CellIdx{row: crow, col});
-                    }
-                    //calc_value(sheet, sheet.get_text(CellIdx{row: }))
+fn build_reference(pair: Pair<Rule>) -> Result<Expr, CellError> {
+    let rule = pair.as_rule();
+    let mut parts = RefParts::default();
+    build_parts(pair, &mut parts)?;
+    let RefParts{sheet, cols, rows} = parts;
+    Ok(match rule {
+        Rule::Reference => Expr::Reference(sheet, CellRef::new(cols[0], rows[0])),
+        Rule::CellRange => Expr::Range(sheet, RangeRef::cells(CellRef::new(cols[0], rows[0]), CellRef::new(cols[1], rows[1]))),
+        Rule::ColumnRange => Expr::Range(sheet, RangeRef::cols(cols[0], cols[1])),
+        _ => Expr::Range(sheet, RangeRef::rows(rows[0], rows[1])),
+    })
+}
+
+fn build_op(pair: &Pair<Rule>) -> BinaryOp {
+    match pair.as_rule() {
+        Rule::Add => BinaryOp::Add,
+        Rule::Subtract => BinaryOp::Subtract,
+        Rule::Multiply => BinaryOp::Multiply,
+        Rule::Divide => BinaryOp::Divide,
+        Rule::Power => BinaryOp::Power,
+        Rule::Concat => BinaryOp::Concat,
+        Rule::Equal => BinaryOp::Equal,
+        Rule::NotEqual => BinaryOp::NotEqual,
+        Rule::Less => BinaryOp::Less,
+        Rule::LessEqual => BinaryOp::LessEqual,
+        Rule::Greater => BinaryOp::Greater,
+        Rule::GreaterEqual => BinaryOp::GreaterEqual,
+        rule => unreachable!("Unexpected operator {:?}", rule),
+    }
+}
+
+fn build_expr(pair: Pair<Rule>) -> Expr {
+    match pair.as_rule() {
+        Rule::Number => Expr::Number(pair.as_str().parse::<f64>().unwrap()),
+        Rule::Boolean => Expr::Boolean(pair.as_str().eq_ignore_ascii_case("TRUE")),
+        Rule::Text => {
+            let inner = pair.into_inner().next().unwrap();
+            Expr::Text(inner.as_str().replace("\"\"", "\""))
+        },
+        Rule::Function => {
+            let mut inner = pair.into_inner();
+            let name = inner.next().unwrap().as_str().to_uppercase();
+            Expr::Function(name, inner.map(build_expr).collect())
+        },
+        Rule::ErrorLiteral => {
+            let kind = ErrorKind::from_code(pair.as_str()).unwrap();
+            Expr::Error(CellError::new(kind, "Error value in formula"))
+        },
+        Rule::Reference | Rule::CellRange | Rule::ColumnRange | Rule::RowRange => {
+            build_reference(pair).unwrap_or_else(Expr::Error)
+        },
+        Rule::Unary => {
+            let mut inner = pair.into_inner().collect::<Vec<_>>();
+            let mut expr = build_expr(inner.pop().unwrap());
+            for op in inner.into_iter().rev() {
+                let op = match op.as_rule() {
+                    Rule::Negate => UnaryOp::Negate,
+                    _ => UnaryOp::Plus,
+                };
+                expr = Expr::Unary(op, Box::new(expr));
+            }
+            expr
+        },
+        Rule::Comparison | Rule::Concatenation | Rule::Sum | Rule::Product | Rule::Exponent => {
+            // All binary operators are left associative
+            let mut inner = pair.into_inner();
+            let mut expr = build_expr(inner.next().unwrap());
+            while let Some(op) = inner.next() {
+                let rhs = build_expr(inner.next().unwrap());
+                expr = Expr::Binary(build_op(&op), Box::new(expr), Box::new(rhs));
+            }
+            expr
+        },
+        rule => unreachable!("Unexpected rule {:?}", rule),
+    }
+}
+
+pub fn parse(text: &str) -> Result<Expr, CellError> {
+    let mut pairs = SimpleParser::parse(Rule::Expr, text).map_err(|err| {
+        let col = match err.line_col {
+            LineColLocation::Pos((_, col)) => col,
+            LineColLocation::Span((_, col), _) => col,
+        };
+        CellError::new(ErrorKind::Parse, format!("Syntax error at position {}", col))
+    })?;
+    let comparison = pairs.next().unwrap().into_inner().next().unwrap();
+    Ok(build_expr(comparison))
+}
+
+impl BinaryOp {
+    pub(crate) fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Power => "^",
+            BinaryOp::Concat => "&",
+            BinaryOp::Equal => "=",
+            BinaryOp::NotEqual => "<>",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+        }
+    }
+
+    /// Binding strength, matching the grammar layers
+    pub(crate) fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Power => 5,
+            BinaryOp::Multiply | BinaryOp::Divide => 4,
+            BinaryOp::Add | BinaryOp::Subtract => 3,
+            BinaryOp::Concat => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl Expr {
+    pub(crate) fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary(op, _, _) => op.precedence(),
+            Expr::Unary(_, _) => 6,
+            _ => 7,
+        }
+    }
+
+    /// Call `func` on every sub expression, then on the expression itself
+    pub(crate) fn visit<F>(&self, func: &mut F) where F: FnMut(&Expr) {
+        match self {
+            Expr::Unary(_, expr) => expr.visit(func),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.visit(func);
+                rhs.visit(func);
+            },
+            Expr::Function(_, args) => args.iter().for_each(|arg| arg.visit(func)),
+            _ => (),
+        }
+        func(self);
+    }
+
+    pub(crate) fn visit_mut<F>(&mut self, func: &mut F) where F: FnMut(&mut Expr) {
+        match self {
+            Expr::Unary(_, expr) => expr.visit_mut(func),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.visit_mut(func);
+                rhs.visit_mut(func);
+            },
+            Expr::Function(_, args) => args.iter_mut().for_each(|arg| arg.visit_mut(func)),
+            _ => (),
+        }
+        func(self);
+    }
+}
+
+/// Formula text without redundant parentheses
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(n) => {
+                let text = n.to_string();
+                if text.len() > 15 { write!(f, "{:e}", n) } else { write!(f, "{}", text) }
+            },
+            Expr::Text(text) => write!(f, "\"{}\"", text.replace('"', "\"\"")),
+            Expr::Boolean(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Expr::Error(err) => write!(f, "{}", err),
+            Expr::Reference(sheet, reference) => {
+                if let Some(sheet) = sheet { write!(f, "{}!", sheet_to_str(sheet))?; }
+                write!(f, "{}", reference)
+            },
+            Expr::Range(sheet, range) => {
+                if let Some(sheet) = sheet { write!(f, "{}!", sheet_to_str(sheet))?; }
+                write!(f, "{}", range)
+            },
+            Expr::Unary(op, expr) => {
+                write!(f, "{}", if *op == UnaryOp::Negate { "-" } else { "+" })?;
+                if expr.precedence() < 6 { write!(f, "({})", expr) } else { write!(f, "{}", expr) }
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                if lhs.precedence() < op.precedence() { write!(f, "({})", lhs)?; } else { write!(f, "{}", lhs)?; }
+                write!(f, "{}", op.symbol())?;
+                if rhs.precedence() <= op.precedence() { write!(f, "({})", rhs) } else { write!(f, "{}", rhs) }
+            },
+            Expr::Function(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 { write!(f, ", ")?; }
+                    write!(f, "{}", arg)?;
                 }
+                write!(f, ")")
+            },
+        }
+    }
+}
+
+/// Rewrite a formula through `func`, called on every sub expression.
+/// Plain values and formulas that do not parse are returned as they are.
+fn rewrite<F>(text: &str, mut func: F) -> String where F: FnMut(&mut Expr) {
+    if !text.starts_with('=') {
+        return text.to_string();
+    }
+    match parse(text) {
+        Ok(mut expr) => {
+            expr.visit_mut(&mut func);
+            format!("={}", expr)
+        },
+        Err(_) => text.to_string(),
+    }
+}
+
+/// Formula as the engine writes it, `None` when it does not parse or calls a function the engine lacks.
+/// `TRUE()` and `FALSE()`, as OpenFormula writes booleans, become plain booleans.
+pub fn canonical(text: &str) -> Option<String> {
+    let mut expr = parse(text).ok()?;
+    let mut known = true;
+    expr.visit_mut(&mut |expr| match expr {
+        Expr::Function(name, args) if args.is_empty() && (name == "TRUE" || name == "FALSE") => {
+            let value = name == "TRUE";
+            *expr = Expr::Boolean(value);
+        },
+        Expr::Function(name, _) => known &= functions::BUILTINS.contains(&name.as_str()),
+        _ => (),
+    });
+    known.then(|| format!("={}", expr))
+}
+
+/// Parsed formula, `None` when it does not parse or holds errors other applications do not know
+pub fn parse_portable(text: &str) -> Option<Expr> {
+    let mut expr = parse(text).ok()?;
+    let mut portable = true;
+    expr.visit_mut(&mut |expr| {
+        if let Expr::Error(err) = expr {
+            portable &= err.kind.is_standard();
+        }
+    });
+    portable.then_some(expr)
+}
+
+fn lost_reference() -> Expr {
+    Expr::Error(CellError::new(ErrorKind::Ref, "Referenced cell was removed"))
+}
+
+/// Formula as it reads after being copied `dcol` columns and `drow` rows away.
+/// `$` anchored parts stay put, references pushed off the sheet become `#REF!`.
+pub fn translate(text: &str, dcol: i64, drow: i64) -> String {
+    rewrite(text, |expr| {
+        let moved = match expr {
+            Expr::Reference(sheet, cell) => cell.offset(dcol, drow).map(|cell| Expr::Reference(sheet.clone(), cell)),
+            Expr::Range(sheet, range) => range.offset(dcol, drow).map(|range| Expr::Range(sheet.clone(), range)),
+            _ => return,
+        };
+        *expr = moved.unwrap_or_else(lost_reference);
+    })
+}
+
+/// Formula on sheet `own` following `count` rows / columns inserted at `at` on sheet `target`,
+/// or removed when negative
+pub fn shift(text: &str, own: &str, target: &str, axis: Axis, at: u32, count: i64) -> String {
+    let on_target = |sheet: &Option<String>| sheet.as_deref().unwrap_or(own).eq_ignore_ascii_case(target);
+    rewrite(text, |expr| {
+        let moved = match expr {
+            Expr::Reference(sheet, cell) if on_target(sheet) => {
+                cell.shift(axis, at, count).map(|cell| Expr::Reference(sheet.clone(), cell))
+            },
+            Expr::Range(sheet, range) if on_target(sheet) => {
+                range.shift(axis, at, count).map(|range| Expr::Range(sheet.clone(), range))
+            },
+            _ => return,
+        };
+        *expr = moved.unwrap_or_else(lost_reference);
+    })
+}
+
+/// Formula with references to sheet `from` pointing at `to` instead,
+/// or turned into `#REF!` when there is no `to` as the sheet was deleted
+pub fn rename_sheet(text: &str, from: &str, to: Option<&str>) -> String {
+    rewrite(text, |expr| {
+        let sheet = match expr {
+            Expr::Reference(Some(sheet), _) | Expr::Range(Some(sheet), _) if sheet.eq_ignore_ascii_case(from) => sheet,
+            _ => return,
+        };
+        match to {
+            Some(to) => *sheet = to.to_string(),
+            None => *expr = lost_reference(),
+        }
+    })
+}
+
+fn eval_binary(op: BinaryOp, lhs: Value, rhs: Value) -> Value {
+    let compare = |lhs: &Value, rhs: &Value| {
+        let ordering = lhs.compare(rhs);
+        Value::Boolean(match op {
+            BinaryOp::Equal => ordering == Ordering::Equal,
+            BinaryOp::NotEqual => ordering != Ordering::Equal,
+            BinaryOp::Less => ordering == Ordering::Less,
+            BinaryOp::LessEqual => ordering != Ordering::Greater,
+            BinaryOp::Greater => ordering == Ordering::Greater,
+            _ => ordering != Ordering::Less,
+        })
+    };
+
+    let (lhs, rhs) = (lhs.to_scalar(), rhs.to_scalar());
+    match op {
+        BinaryOp::Concat => {
+            for val in [&lhs, &rhs] {
+                if val.is_error() { return val.clone(); }
+            }
+            Value::Text(format!("{}{}", lhs, rhs))
+        },
+        BinaryOp::Equal | BinaryOp::NotEqual | BinaryOp::Less |
+        BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => {
+            for val in [&lhs, &rhs] {
+                if val.is_error() { return val.clone(); }
+            }
+            compare(&lhs, &rhs)
+        },
+        _ => {
+            let lhs = match lhs.to_number() {
+                Ok(val) => val,
+                Err(err) => return err,
+            };
+            let rhs = match rhs.to_number() {
+                Ok(val) => val,
+                Err(err) => return err,
+            };
+            let res = match op {
+                BinaryOp::Add => lhs + rhs,
+                BinaryOp::Subtract => lhs - rhs,
+                BinaryOp::Multiply => lhs * rhs,
+                BinaryOp::Divide => {
+                    if rhs == 0.0 { return Value::error(ErrorKind::DivZero, "Division by zero"); }
+                    lhs / rhs
+                },
+                _ => lhs.powf(rhs),
+            };
+
+            if res.is_finite() {
+                Value::Number(res)
+            } else {
+                Value::error(ErrorKind::Num, "Result is not a finite number")
             }
-            "Error".to_string()
         },
-        _  => { text.to_string() }
     }
 }
 
+pub(crate) fn eval(sheet_state: &mut SheetState, expr: &Expr) -> Value {
+    match expr {
+        Expr::Number(n) => Value::Number(*n),
+        Expr::Text(text) => Value::Text(text.clone()),
+        Expr::Boolean(b) => Value::Boolean(*b),
+        Expr::Error(err) => Value::Error(err.clone()),
+        Expr::Reference(sheet, reference) => match sheet_state.resolve_sheet(sheet.as_deref()) {
+            Ok(sheet) => sheet_state.get_value_at(&CellPos{sheet, idx: reference.idx()}),
+            Err(err) => Value::Error(err),
+        },
+        Expr::Range(sheet, range) => match sheet_state.resolve_sheet(sheet.as_deref()) {
+            Ok(sheet) => Value::Array(sheet_state.get_area(sheet, &range.area())),
+            Err(err) => Value::Error(err),
+        },
+        Expr::Unary(op, expr) => {
+            let val = match eval(sheet_state, expr).to_number() {
+                Ok(val) => val,
+                Err(err) => return err,
+            };
+            match op {
+                UnaryOp::Negate => Value::Number(-val),
+                UnaryOp::Plus => Value::Number(val),
+            }
+        },
+        Expr::Binary(op, lhs, rhs) => {
+            let lhs = eval(sheet_state, lhs);
+            let rhs = eval(sheet_state, rhs);
+            eval_binary(*op, lhs, rhs)
+        },
+        Expr::Function(name, args) => functions::call(sheet_state, name, args),
+    }
+}
+
+pub fn calc(sheet_state: &mut SheetState, text: &str) -> Value
+{
+    if !text.starts_with('=') {
+        return Value::from_input(text);
+    }
+
+    match parse(text) {
+        Ok(expr) => eval(sheet_state, &expr),
+        Err(err) => Value::Error(err),
+    }
+}
+
+/// Formulas starting with `=`, any other text is a plain value
+pub struct SimpleEngine;
+
+/// Text of a cell that is not a formula
+struct Literal(Value);
+
+impl Engine for SimpleEngine {
+    fn id(&self) -> EngineId {
+        EngineId::SIMPLE
+    }
+
+    fn parse(&self, text: &str) -> Result<Box<dyn Formula>, CellError> {
+        if !text.starts_with('=') {
+            return Ok(Box::new(Literal(Value::from_input(text))));
+        }
+        Ok(Box::new(parse(text)?))
+    }
+}
+
+impl Formula for Literal {
+    fn evaluate(&self, _: &mut SheetState) -> Value {
+        self.0.clone()
+    }
+}
+
+impl Formula for Expr {
+    fn evaluate(&self, sheet_state: &mut SheetState) -> Value {
+        eval(sheet_state, self)
+    }
+
+    fn dependencies(&self) -> Vec<Dependency> {
+        let mut dependencies = vec![];
+        self.visit(&mut |expr| match expr {
+            Expr::Reference(sheet, reference) => {
+                let CellIdx{col, row} = reference.idx();
+                let area = Area{cols: Some((col, col)), rows: Some((row, row))};
+                dependencies.push(Dependency{sheet: sheet.clone(), area});
+            },
+            Expr::Range(sheet, range) => dependencies.push(Dependency{sheet: sheet.clone(), area: range.area()}),
+            _ => (),
+        });
+        dependencies
+    }
+}
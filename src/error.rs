@@ -0,0 +1,124 @@
+use std::fmt;
+
+/// Kind of a failed evaluation, shown in the cell as its spreadsheet error code
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ErrorKind {
+    /// Empty intersection of ranges
+    Null,
+    DivZero,
+    /// Wrong type of argument or operand
+    Value,
+    /// Reference to a cell that does not exist
+    Ref,
+    /// Unknown function or name
+    Name,
+    /// Result is not a valid number
+    Num,
+    /// Value not available
+    NA,
+    /// Formula could not be parsed
+    Parse,
+    /// Cell takes part in a reference cycle
+    Circular,
+    /// Python code raised an exception
+    Python,
+    /// Array result with cells in the way of spilling it
+    Spill,
+}
+
+const KINDS: [ErrorKind; 11] = [
+    ErrorKind::Null,
+    ErrorKind::DivZero,
+    ErrorKind::Value,
+    ErrorKind::Ref,
+    ErrorKind::Name,
+    ErrorKind::Num,
+    ErrorKind::NA,
+    ErrorKind::Parse,
+    ErrorKind::Circular,
+    ErrorKind::Python,
+    ErrorKind::Spill,
+];
+
+impl ErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::Null => "#NULL!",
+            ErrorKind::DivZero => "#DIV/0!",
+            ErrorKind::Value => "#VALUE!",
+            ErrorKind::Ref => "#REF!",
+            ErrorKind::Name => "#NAME?",
+            ErrorKind::Num => "#NUM!",
+            ErrorKind::NA => "#N/A",
+            ErrorKind::Parse => "#ERROR!",
+            ErrorKind::Circular => "#CIRCULAR!",
+            ErrorKind::Python => "#PYTHON!",
+            ErrorKind::Spill => "#SPILL!",
+        }
+    }
+
+    /// One of the errors other spreadsheet applications know as well
+    pub fn is_standard(&self) -> bool {
+        !matches!(self, ErrorKind::Parse | ErrorKind::Circular | ErrorKind::Python | ErrorKind::Spill)
+    }
+
+    /// Code for files of other applications, `#VALUE!` standing in for the errors they lack
+    pub fn standard_code(&self) -> &'static str {
+        if self.is_standard() { self.code() } else { ErrorKind::Value.code() }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        KINDS.iter().find(|kind| kind.code().eq_ignore_ascii_case(code)).cloned()
+    }
+}
+
+/// Exception raised by the code of a cell, as its engine reports it
+#[derive(Clone, PartialEq, Debug)]
+pub struct Exception {
+    /// Type of the exception, like `ZeroDivisionError`
+    pub kind: String,
+    pub message: String,
+    /// Calls leading up to the exception, innermost last
+    pub traceback: String,
+}
+
+/// Error value of a cell, with a human readable reason
+#[derive(Clone, PartialEq, Debug)]
+pub struct CellError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Exception behind the error, kept by the cells the error spreads to as well
+    pub exception: Option<Box<Exception>>,
+}
+
+impl CellError {
+    pub fn new<S: Into<String>>(kind: ErrorKind, message: S) -> Self {
+        CellError{kind, message: message.into(), exception: None}
+    }
+
+    pub fn with_exception(self, exception: Exception) -> Self {
+        CellError{exception: Some(Box::new(exception)), ..self}
+    }
+}
+
+impl fmt::Display for CellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes() {
+        for kind in KINDS {
+            assert_eq!(ErrorKind::from_code(kind.code()), Some(kind));
+        }
+        assert_eq!(ErrorKind::from_code("#div/0!"), Some(ErrorKind::DivZero));
+        assert_eq!(ErrorKind::from_code("#OOPS!"), None);
+        assert_eq!(ErrorKind::NA.standard_code(), "#N/A");
+        assert_eq!(ErrorKind::Circular.standard_code(), "#VALUE!");
+    }
+}
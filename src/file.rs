@@ -0,0 +1,148 @@
+use std::{fmt, io::{self, Cursor, Read}, path::Path};
+
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{rsheet, reference, xlsx, ods, csv::{self, CsvOptions}, sheet_state::SheetState, value::Value, sheet::{Cell, CellIdx}, engine::EngineId, workbook::PythonPolicy, xml::{self, Element}};
+
+#[derive(Debug)]
+pub enum FileError {
+    Io(io::Error),
+    /// Not a native workbook or a damaged one
+    Format(String),
+    /// Written by a later version
+    Version(u32),
+    /// Cells of an engine this build does not have, like Python without the `python` feature
+    Engine(String),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::Io(err) => write!(f, "{}", err),
+            FileError::Format(reason) => write!(f, "Not a valid workbook: {}", reason),
+            FileError::Version(version) => write!(f, "Workbook version {} is newer than supported ({})", version, rsheet::VERSION),
+            FileError::Engine(name) => write!(f, "Workbook uses the {} engine, which is not available", name),
+        }
+    }
+}
+
+impl From<io::Error> for FileError {
+    fn from(err: io::Error) -> Self {
+        FileError::Io(err)
+    }
+}
+
+pub(crate) fn format_error<E: ToString>(err: E) -> FileError {
+    FileError::Format(err.to_string())
+}
+
+/// A formula the simple engine cannot evaluate, imported as the value last computed by the application that wrote the file
+#[derive(Clone, PartialEq, Debug)]
+pub struct Unsupported {
+    pub sheet: String,
+    pub idx: CellIdx,
+    /// As written in the file, without the leading `=`
+    pub formula: String,
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}!{}{}: ={}", reference::sheet_to_str(&self.sheet), reference::col_to_str(self.idx.col), self.idx.row + 1, self.formula)
+    }
+}
+
+/// What could not be imported as is
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ImportInfo {
+    pub unsupported: Vec<Unsupported>,
+    /// Sheets holding no cells, like charts, which are left out
+    pub skipped: Vec<String>,
+}
+
+/// Kinds of files a workbook is read from and written to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Native,
+    Xlsx,
+    Ods,
+    /// CSV or TSV, holding a single sheet
+    Csv,
+}
+
+impl Format {
+    /// Format of a file by its extension, `None` when it is not a known one
+    pub fn from_path(path: &Path) -> Option<Format> {
+        match path.extension()?.to_string_lossy().to_ascii_lowercase().as_str() {
+            rsheet::EXTENSION => Some(Format::Native),
+            xlsx::EXTENSION => Some(Format::Xlsx),
+            ods::EXTENSION => Some(Format::Ods),
+            "csv" | "tsv" | "tab" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// Read a workbook in any format, native when the extension is not a known one.
+/// Only native workbooks become the file of the state. Its Python code does not run until it is trusted.
+pub fn open(path: &Path) -> Result<(SheetState, ImportInfo), FileError> {
+    let (mut state, info) = match Format::from_path(path) {
+        Some(Format::Xlsx) => xlsx::import_file(path),
+        Some(Format::Ods) => ods::import_file(path),
+        Some(Format::Csv) => {
+            let mut state = SheetState::new();
+            csv::import_file(state.sheet_mut(), path, &CsvOptions::for_path(path))?;
+            state.workbook.take_journal();
+            Ok((state, ImportInfo::default()))
+        },
+        Some(Format::Native) | None => Ok((rsheet::open_file(path)?, ImportInfo::default())),
+    }?;
+    let policy = PythonPolicy{trusted: false, ..state.workbook.python_policy()};
+    state.workbook.set_python_policy(policy);
+    Ok((state, info))
+}
+
+/// Write a workbook in the format of `path`, `None` when it is not a known one.
+/// CSV holds the values of the active sheet.
+pub fn write(state: &mut SheetState, path: &Path) -> Option<Result<(), FileError>> {
+    Some(match Format::from_path(path)? {
+        Format::Native => rsheet::save_file(state, path),
+        Format::Xlsx => xlsx::export_file(state, path),
+        Format::Ods => ods::export_file(state, path),
+        Format::Csv => csv::export_file(state, state.active(), path, &CsvOptions::for_path(path)).map_err(FileError::from),
+    })
+}
+
+/// Text as entered so that it reads back as that same text
+pub(crate) fn literal(text: String) -> String {
+    let plain = !text.starts_with('=') && matches!(Value::from_input(&text), Value::Text(ref read) if *read == text);
+    if plain { text } else { format!("'{}", text) }
+}
+
+/// Note left on exported cells of engines other applications cannot run, which keep what they computed
+pub(crate) fn engine_note(cell: &Cell) -> Option<String> {
+    (cell.engine != EngineId::SIMPLE)
+        .then(|| format!("Computed by the {} engine, exported as its value:\n{}", cell.engine, cell.value))
+}
+
+/// Zip container of XML parts, as used by XLSX and ODS
+pub(crate) struct Package<'a> {
+    archive: ZipArchive<Cursor<&'a [u8]>>,
+}
+
+impl<'a> Package<'a> {
+    pub fn open(bytes: &'a [u8]) -> Result<Self, FileError> {
+        Ok(Package{archive: ZipArchive::new(Cursor::new(bytes)).map_err(format_error)?})
+    }
+
+    /// A part, `None` when the package does not have it
+    pub fn read(&mut self, path: &str) -> Result<Option<Element>, FileError> {
+        let mut file = match self.archive.by_name(path) {
+            Ok(file) => file,
+            Err(ZipError::FileNotFound) => return Ok(None),
+            Err(err) => return Err(format_error(err)),
+        };
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+        xml::parse(&bytes).map(Some).map_err(|err| FileError::Format(format!("{}: {}", path, err)))
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{engine, engine_simple::{self, Expr}, sheet_state::SheetState, value::Value, error::ErrorKind};
+
+/// Names of the built-in functions
+pub const BUILTINS: [&str; 13] = [
+    "SUM", "AVERAGE", "MIN", "MAX", "COUNT", "COUNTA", "ROUND", "ABS", "IF", "AND", "OR", "NOT", "IFERROR",
+];
+
+/// Evaluate a built-in function of the simple engine, or one offered by another engine.
+/// Arguments are passed unevaluated so IF / IFERROR only evaluate the branch they need.
+pub fn call(sheet_state: &mut SheetState, name: &str, args: &[Expr]) -> Value {
+    match name {
+        "SUM" => aggregate(sheet_state, args, |nums| Value::Number(nums.iter().sum())),
+        "AVERAGE" => aggregate(sheet_state, args, |nums| {
+            if nums.is_empty() {
+                return Value::error(ErrorKind::DivZero, "No numbers to average");
+            }
+            Value::Number(nums.iter().sum::<f64>() / nums.len() as f64)
+        }),
+        "MIN" => aggregate(sheet_state, args, |nums| {
+            Value::Number(nums.iter().cloned().reduce(f64::min).unwrap_or(0.0))
+        }),
+        "MAX" => aggregate(sheet_state, args, |nums| {
+            Value::Number(nums.iter().cloned().reduce(f64::max).unwrap_or(0.0))
+        }),
+        "COUNT" => {
+            let values = flatten(sheet_state, args);
+            Value::Number(values.iter().filter(|(val, direct)| {
+                val.is_number() || (*direct && val.to_number().is_ok() && !val.is_empty())
+            }).count() as f64)
+        },
+        "COUNTA" => {
+            let values = flatten(sheet_state, args);
+            Value::Number(values.iter().filter(|(val, _)| !val.is_empty()).count() as f64)
+        },
+        "ROUND" => {
+            if args.len() != 2 {
+                return arity_error(name, "2 arguments");
+            }
+            let (num, digits) = match (number_arg(sheet_state, &args[0]), number_arg(sheet_state, &args[1])) {
+                (Ok(num), Ok(digits)) => (num, digits.trunc() as i32),
+                (Err(err), _) | (_, Err(err)) => return err,
+            };
+            let factor = 10f64.powi(digits.abs());
+            // Halves go by the decimal digits shown, 1.005 being 1.00499999999999989... as a float
+            let round = |num: f64| format!("{:.14e}", num).parse::<f64>().unwrap_or(num).round();
+            let res = if digits >= 0 {
+                round(num * factor) / factor
+            } else {
+                round(num / factor) * factor
+            };
+            Value::Number(res)
+        },
+        "ABS" => {
+            if args.len() != 1 {
+                return arity_error(name, "1 argument");
+            }
+            match number_arg(sheet_state, &args[0]) {
+                Ok(num) => Value::Number(num.abs()),
+                Err(err) => err,
+            }
+        },
+        "IF" => {
+            if args.len() < 2 || args.len() > 3 {
+                return arity_error(name, "2 or 3 arguments");
+            }
+            let cond = match engine_simple::eval(sheet_state, &args[0]).to_bool() {
+                Ok(cond) => cond,
+                Err(err) => return err,
+            };
+            if cond {
+                engine_simple::eval(sheet_state, &args[1])
+            } else if let Some(otherwise) = args.get(2) {
+                engine_simple::eval(sheet_state, otherwise)
+            } else {
+                Value::Boolean(false)
+            }
+        },
+        "AND" => logical(sheet_state, args, |bools| bools.iter().all(|b| *b)),
+        "OR" => logical(sheet_state, args, |bools| bools.iter().any(|b| *b)),
+        "NOT" => {
+            if args.len() != 1 {
+                return arity_error(name, "1 argument");
+            }
+            match engine_simple::eval(sheet_state, &args[0]).to_bool() {
+                Ok(b) => Value::Boolean(!b),
+                Err(err) => err,
+            }
+        },
+        "IFERROR" => {
+            if args.len() != 2 {
+                return arity_error(name, "2 arguments");
+            }
+            let value = engine_simple::eval(sheet_state, &args[0]);
+            if value.is_error() {
+                engine_simple::eval(sheet_state, &args[1])
+            } else {
+                value
+            }
+        },
+        _ => {
+            // Functions other engines offer, like Python functions of the workbook
+            let values = args.iter().map(|arg| engine_simple::eval(sheet_state, arg)).collect::<Vec<_>>();
+            engine::call_function(sheet_state, name, &values)
+                .unwrap_or_else(|| Value::error(ErrorKind::Name, format!("Unknown function {}", name)))
+        },
+    }
+}
+
+fn arity_error(name: &str, expected: &str) -> Value {
+    Value::error(ErrorKind::Value, format!("{} expects {}", name, expected))
+}
+
+fn number_arg(sheet_state: &mut SheetState, arg: &Expr) -> Result<f64, Value> {
+    engine_simple::eval(sheet_state, arg).to_number()
+}
+
+/// Evaluate all arguments, expanding arrays.
+/// Each value is paired with whether it was passed directly (rather than from a cell or range).
+fn flatten(sheet_state: &mut SheetState, args: &[Expr]) -> Vec<(Value, bool)> {
+    let mut values = vec![];
+    for arg in args {
+        let direct = !matches!(arg, Expr::Reference(..) | Expr::Range(..));
+        match engine_simple::eval(sheet_state, arg) {
+            Value::Array(rows) => {
+                values.extend(rows.into_iter().flatten().map(|val| (val, false)));
+            },
+            val => values.push((val, direct)),
+        }
+    }
+    values
+}
+
+/// Numbers in cells and ranges are used as is and other types skipped,
+/// while direct arguments are coerced to numbers.
+fn aggregate<F>(sheet_state: &mut SheetState, args: &[Expr], func: F) -> Value where F: Fn(&[f64]) -> Value {
+    let mut nums = vec![];
+    for (val, direct) in flatten(sheet_state, args) {
+        match val {
+            Value::Number(n) => nums.push(n),
+            Value::Error(_) => return val,
+            _ if direct => match val.to_number() {
+                Ok(n) => nums.push(n),
+                Err(err) => return err,
+            },
+            _ => (),
+        }
+    }
+    func(&nums)
+}
+
+/// Like `aggregate`, for booleans. Having no logical values at all is an error.
+fn logical<F>(sheet_state: &mut SheetState, args: &[Expr], func: F) -> Value where F: Fn(&[bool]) -> bool {
+    let mut bools = vec![];
+    for (val, direct) in flatten(sheet_state, args) {
+        match val {
+            Value::Boolean(b) => bools.push(b),
+            Value::Number(n) => bools.push(n != 0.0),
+            Value::Error(_) => return val,
+            _ if direct => match val.to_bool() {
+                Ok(b) => bools.push(b),
+                Err(err) => return err,
+            },
+            _ => (),
+        }
+    }
+    if bools.is_empty() {
+        return Value::error(ErrorKind::Value, "No logical values");
+    }
+    Value::Boolean(func(&bools))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::CellIdx;
+
+    fn eval(state: &mut SheetState, text: &str) -> Value {
+        let idx = CellIdx{col: 10, row: 10};
+        state.sheet_mut().set_text(idx.clone(), text.to_string());
+        state.get_value(&idx)
+    }
+
+    fn sample_state() -> SheetState {
+        let mut state = SheetState::new();
+        for (row, text) in ["1", "2", "text", "", "4.5", "TRUE"].iter().enumerate() {
+            state.sheet_mut().set_text(CellIdx{col: 0, row: row as u32}, text.to_string());
+        }
+        state
+    }
+
+    #[test]
+    fn aggregates() {
+        let mut state = sample_state();
+
+        assert_eq!(eval(&mut state, "=SUM(A1:A6)"), Value::Number(7.5));
+        assert_eq!(eval(&mut state, "=SUM(A1:A6, 10, \"2\")"), Value::Number(19.5));
+        assert_eq!(eval(&mut state, "=sum(A1, A2)"), Value::Number(3.0));
+        assert_eq!(eval(&mut state, "=SUM(A1:A6, \"x\")").error_kind(), Some(ErrorKind::Value));
+        assert_eq!(eval(&mut state, "=AVERAGE(A1:A6)"), Value::Number(2.5));
+        assert_eq!(eval(&mut state, "=AVERAGE(A3:A4)").error_kind(), Some(ErrorKind::DivZero));
+        assert_eq!(eval(&mut state, "=MIN(A:A)"), Value::Number(1.0));
+        assert_eq!(eval(&mut state, "=MAX(A1:A6, -3)"), Value::Number(4.5));
+        // Text in referenced cells is skipped, as in ranges
+        assert_eq!(eval(&mut state, "=MAX(A3)"), Value::Number(0.0));
+        assert_eq!(eval(&mut state, "=SUM(A1, A3)"), Value::Number(1.0));
+        assert_eq!(eval(&mut state, "=COUNT(A1:A6)"), Value::Number(3.0));
+        assert_eq!(eval(&mut state, "=COUNTA(A1:A6)"), Value::Number(5.0));
+        assert_eq!(eval(&mut state, "=SUM()"), Value::Number(0.0));
+    }
+
+    #[test]
+    fn math() {
+        let mut state = sample_state();
+
+        assert_eq!(eval(&mut state, "=ROUND(2.345, 2)"), Value::Number(2.35));
+        assert_eq!(eval(&mut state, "=ROUND(-2.5, 0)"), Value::Number(-3.0));
+        assert_eq!(eval(&mut state, "=ROUND(1.005, 2)"), Value::Number(1.01));
+        assert_eq!(eval(&mut state, "=ROUND(-1.005, 2)"), Value::Number(-1.01));
+        assert_eq!(eval(&mut state, "=ROUND(1234, -2)"), Value::Number(1200.0));
+        assert_eq!(eval(&mut state, "=ROUND(1)").error_kind(), Some(ErrorKind::Value));
+        assert_eq!(eval(&mut state, "=ABS(-A5)"), Value::Number(4.5));
+        assert_eq!(eval(&mut state, "=ABS(A3)").error_kind(), Some(ErrorKind::Value));
+    }
+
+    #[test]
+    fn logic() {
+        let mut state = sample_state();
+
+        assert_eq!(eval(&mut state, "=IF(A1<A2, \"less\", \"more\")"), Value::from("less"));
+        assert_eq!(eval(&mut state, "=IF(A1>A2, \"less\")"), Value::Boolean(false));
+        assert_eq!(eval(&mut state, "=IF(A6, 1/0, 2)").error_kind(), Some(ErrorKind::DivZero));
+        assert_eq!(eval(&mut state, "=IF(FALSE, 1/0, 2)"), Value::Number(2.0));
+        assert_eq!(eval(&mut state, "=IF(A3, 1, 2)").error_kind(), Some(ErrorKind::Value));
+        assert_eq!(eval(&mut state, "=AND(A1:A6)"), Value::Boolean(true));
+        assert_eq!(eval(&mut state, "=AND(TRUE, A1=2)"), Value::Boolean(false));
+        assert_eq!(eval(&mut state, "=AND(A3, TRUE)"), Value::Boolean(true));
+        assert_eq!(eval(&mut state, "=OR(A1=2, A3=\"TEXT\")"), Value::Boolean(true));
+        assert_eq!(eval(&mut state, "=OR(A3:A4)").error_kind(), Some(ErrorKind::Value));
+        assert_eq!(eval(&mut state, "=NOT(A4)"), Value::Boolean(true));
+        assert_eq!(eval(&mut state, "=IFERROR(1/0, \"oops\")"), Value::from("oops"));
+        assert_eq!(eval(&mut state, "=IFERROR(A1, \"oops\")"), Value::Number(1.0));
+        assert_eq!(eval(&mut state, "=IFERROR(#SPILL!, 0)"), Value::Number(0.0));
+        assert_eq!(eval(&mut state, "=#SPILL!").error_kind(), Some(ErrorKind::Spill));
+        assert_eq!(eval(&mut state, "=A3&\" \"&A1&\"\"\"\""), Value::from("text 1\""));
+    }
+
+    #[test]
+    fn unknown_function() {
+        let mut state = sample_state();
+
+        assert_eq!(eval(&mut state, "=NOSUCHFUNCTION(1)").error_kind(), Some(ErrorKind::Name));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+
+use crate::{sheet::{Cell, CellEdit, CellIdx}, workbook::{SheetId, Workbook}};
+
+pub const DEFAULT_DEPTH: usize = 100;
+
+/// A single modification of a workbook, holding what is needed to apply it in both directions
+#[derive(Clone, PartialEq, Debug)]
+pub enum Edit {
+    Cell(SheetId, CellEdit),
+    AddSheet{id: SheetId, name: String, position: usize},
+    /// Cells are those of the sheet when it was deleted
+    DeleteSheet{id: SheetId, name: String, position: usize, cells: Vec<(CellIdx, Cell)>},
+    RenameSheet{id: SheetId, old: String, new: String},
+    MoveSheet{id: SheetId, from: usize, to: usize},
+    PythonModule{old: String, new: String},
+}
+
+/// Undo / redo stacks of transactions, a transaction being the edits of one user action
+pub struct History {
+    undo: VecDeque<Vec<Edit>>,
+    redo: Vec<Vec<Edit>>,
+    /// Maximum number of transactions that can be undone
+    depth: usize,
+}
+
+impl History {
+    pub fn new(depth: usize) -> Self {
+        History{undo: VecDeque::new(), redo: vec![], depth}
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Limit the number of transactions kept, dropping the oldest ones
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.undo.len() > depth {
+            self.undo.pop_front();
+        }
+        self.redo.truncate(depth);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Add a transaction, anything undone before can no longer be redone
+    pub fn record(&mut self, edits: Vec<Edit>) {
+        if edits.is_empty() || self.depth == 0 {
+            return;
+        }
+        self.redo.clear();
+        self.undo.push_back(edits);
+        if self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+
+    /// Revert the last transaction, `false` when there is nothing to undo
+    pub fn undo(&mut self, workbook: &mut Workbook) -> bool {
+        let edits = match self.undo.pop_back() {
+            Some(edits) => edits,
+            None => return false,
+        };
+        for edit in edits.iter().rev() {
+            workbook.apply(edit, true);
+        }
+        self.redo.push(edits);
+        true
+    }
+
+    /// Apply the last undone transaction again, `false` when there is nothing to redo
+    pub fn redo(&mut self, workbook: &mut Workbook) -> bool {
+        let edits = match self.redo.pop() {
+            Some(edits) => edits,
+            None => return false,
+        };
+        for edit in edits.iter() {
+            workbook.apply(edit, false);
+        }
+        self.undo.push_back(edits);
+        true
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_DEPTH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(workbook: &Workbook, sheet: SheetId, col: u32) -> String {
+        workbook.sheet(sheet).unwrap().get_text(&CellIdx{col, row: 0})
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut workbook = Workbook::new();
+        let mut history = History::new(2);
+        let id = workbook.sheet_ids()[0];
+        let set = |workbook: &mut Workbook, col, value: &str| {
+            workbook.sheet_mut(id).unwrap().set_text(CellIdx{col, row: 0}, value.to_string());
+        };
+
+        for value in ["1", "2", "3"] {
+            set(&mut workbook, 0, value);
+            history.record(workbook.take_journal());
+        }
+
+        assert!(history.undo(&mut workbook));
+        assert_eq!(text(&workbook, id, 0), "2");
+        assert!(history.undo(&mut workbook));
+        assert_eq!(text(&workbook, id, 0), "1");
+        // Only two transactions are kept
+        assert!(!history.undo(&mut workbook));
+
+        assert!(history.redo(&mut workbook));
+        assert_eq!(text(&workbook, id, 0), "2");
+
+        set(&mut workbook, 1, "new");
+        history.record(workbook.take_journal());
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn python_module() {
+        let mut workbook = Workbook::new();
+        let mut history = History::default();
+        workbook.set_python_module("def double(x):\n    return 2 * x\n");
+        workbook.take_structure_change();
+        history.record(workbook.take_journal());
+
+        assert!(history.undo(&mut workbook));
+        assert_eq!(workbook.python_module(), "");
+        assert!(workbook.take_structure_change());
+        assert!(history.redo(&mut workbook));
+        assert!(workbook.python_module().starts_with("def double"));
+    }
+
+    #[test]
+    fn multi_cell_transactions() {
+        let mut workbook = Workbook::new();
+        let mut history = History::default();
+        let id = workbook.sheet_ids()[0];
+        for col in 0..3 {
+            workbook.sheet_mut(id).unwrap().set_text(CellIdx{col, row: 0}, format!("={}", col));
+        }
+        workbook.sheet_mut(id).unwrap().set_text(CellIdx{col: 0, row: 1}, "=SUM(A1:C1)".to_string());
+        history.record(workbook.take_journal());
+
+        // Removing a column moves cells and rewrites formulas, all undone at once
+        workbook.remove_cols(id, 1, 1).unwrap();
+        let data = workbook.add_sheet("Data").unwrap();
+        workbook.rename_sheet(data, "Q1 Data").unwrap();
+        history.record(workbook.take_journal());
+        assert_eq!(text(&workbook, id, 1), "=2");
+        assert_eq!(workbook.sheet(id).unwrap().get_text(&CellIdx{col: 0, row: 1}), "=SUM(A1:B1)");
+
+        assert!(history.undo(&mut workbook));
+        assert_eq!((text(&workbook, id, 1), text(&workbook, id, 2)), ("=1".to_string(), "=2".to_string()));
+        assert_eq!(workbook.sheet(id).unwrap().get_text(&CellIdx{col: 0, row: 1}), "=SUM(A1:C1)");
+        assert_eq!(workbook.sheet_ids(), vec![id]);
+
+        assert!(history.redo(&mut workbook));
+        assert_eq!(text(&workbook, id, 2), "");
+        assert_eq!(workbook.name(data), Some("Q1 Data"));
+
+        // Deleted sheets come back with their cells
+        workbook.sheet_mut(data).unwrap().set_text(CellIdx{col: 0, row: 0}, "kept".to_string());
+        history.record(workbook.take_journal());
+        workbook.delete_sheet(data).unwrap();
+        history.record(workbook.take_journal());
+        assert!(history.undo(&mut workbook));
+        assert_eq!(text(&workbook, data, 0), "kept");
+        assert!(history.undo(&mut workbook));
+        assert_eq!(text(&workbook, data, 0), "");
+        assert!(history.redo(&mut workbook));
+        assert!(history.redo(&mut workbook));
+        assert!(workbook.sheet(data).is_none());
+    }
+}
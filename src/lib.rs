@@ -0,0 +1,37 @@
+//! Spreadsheet engine of Rusty Sheet: workbooks of cells evaluated by formula engines,
+//! read from and written to native, Excel, OpenDocument and CSV files.
+//!
+//! [`SheetState`] holds a [`Workbook`] along with the values of its cells, which are
+//! evaluated on demand and kept until their precedents change.
+//!
+//! The GUIs are only part of the binary, depend on the crate with `default-features = false`
+//! to leave them out.
+
+pub mod sheet;
+pub mod sheet_state;
+pub mod engine;
+pub mod engine_simple;
+pub mod value;
+pub mod error;
+mod functions;
+mod dependencies;
+pub mod reference;
+pub mod workbook;
+pub mod history;
+pub mod csv;
+pub mod file;
+pub mod rsheet;
+mod xml;
+pub mod xlsx;
+pub mod ods;
+pub mod cli;
+#[cfg(feature = "python")]
+pub mod engine_python;
+
+pub use sheet::{Cell, CellIdx, Sheet};
+pub use engine::{Engine, EngineId, Formula};
+pub use sheet_state::SheetState;
+pub use workbook::{CellPos, PythonPolicy, SheetId, Workbook, WorkbookError, WorkbookId};
+pub use value::Value;
+pub use error::{CellError, ErrorKind, Exception};
+pub use file::{FileError, Format};
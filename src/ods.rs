@@ -0,0 +1,549 @@
+use std::{fmt, fs, io::{Cursor, Write}, path::Path};
+
+use zip::{write::FileOptions, CompressionMethod};
+
+use crate::{
+    sheet::{Sheet, CellIdx}, sheet_state::SheetState, workbook::{Workbook, SheetId, CellPos},
+    engine_simple::{self, Expr, UnaryOp}, value::{Value, format_number}, reference::sheet_to_str,
+    file::{FileError, ImportInfo, Unsupported, Package, format_error, literal, engine_note},
+    xml::{self, Element, Node},
+};
+
+pub const EXTENSION: &str = "ods";
+
+const MIME_TYPE: &str = "application/vnd.oasis.opendocument.spreadsheet";
+const XML_DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+
+/// Parts separated by `separator`, ignoring those within quoted sheet names
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == '\'' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&text[start..i]);
+            start = i + 1;
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Sheet name and address of one end of a reference, `Sheet2.A1`, `'Q1 Data'.$B$4` or `.A1`
+fn reference_part(part: &str) -> Option<(Option<String>, &str)> {
+    // A leading `$` anchors the sheet, which sheet names in the simple engine always are
+    let part = part.strip_prefix('$').unwrap_or(part);
+    let Some(quoted) = part.strip_prefix('\'') else {
+        let (name, address) = part.rsplit_once('.')?;
+        return Some(((!name.is_empty()).then(|| name.to_string()), address));
+    };
+    let mut name = String::new();
+    let mut chars = quoted.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\'' if chars.peek().map(|(_, c)| *c) == Some('\'') => { chars.next(); name.push('\''); },
+            '\'' => return Some((Some(name), quoted[i + 1..].strip_prefix('.')?)),
+            _ => name.push(c),
+        }
+    }
+    None
+}
+
+/// Bracketed OpenFormula reference, without its brackets, in simple engine syntax.
+/// Ranges spanning several sheets have no equivalent.
+fn from_reference(reference: &str) -> Option<String> {
+    let mut sheet = None;
+    let mut addresses = vec![];
+    for part in split_unquoted(reference, ':') {
+        let (name, address) = reference_part(part)?;
+        match (&sheet, name) {
+            (_, None) => (),
+            (None, Some(name)) if addresses.is_empty() => sheet = Some(name),
+            (Some(first), Some(name)) if *first == name => (),
+            _ => return None,
+        }
+        addresses.push(address);
+    }
+    let prefix = sheet.map(|name| format!("{}!", sheet_to_str(&name))).unwrap_or_default();
+    Some(format!("{}{}", prefix, addresses.join(":")))
+}
+
+/// OpenFormula text like `of:=SUM([.A1:.B2];1)` in simple engine syntax,
+/// `None` when the simple engine cannot evaluate it
+fn from_openformula(formula: &str) -> Option<String> {
+    let (namespace, body) = formula.split_once('=')?;
+    if !namespace.chars().all(|c| c.is_ascii_alphanumeric() || c == ':') {
+        return None;
+    }
+
+    let mut text = String::from("=");
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                // Doubled quotes just close and reopen the string
+                text.push(c);
+                for c in chars.by_ref() {
+                    text.push(c);
+                    if c == '"' {
+                        break;
+                    }
+                }
+            },
+            '[' => {
+                let mut reference = String::new();
+                let mut quoted = false;
+                loop {
+                    match chars.next()? {
+                        ']' if !quoted => break,
+                        c => {
+                            quoted ^= c == '\'';
+                            reference.push(c);
+                        },
+                    }
+                }
+                text.push_str(&from_reference(&reference)?);
+            },
+            ';' => text.push(','),
+            _ => text.push(c),
+        }
+    }
+    engine_simple::canonical(&text)
+}
+
+/// A formula written the OpenFormula way, with bracketed references and `;` between arguments
+struct OpenFormula<'a>(&'a Expr);
+
+impl OpenFormula<'_> {
+    fn sheet(f: &mut fmt::Formatter<'_>, sheet: &Option<String>) -> fmt::Result {
+        match sheet {
+            Some(sheet) => write!(f, "{}.", sheet_to_str(sheet)),
+            None => write!(f, "."),
+        }
+    }
+}
+
+impl fmt::Display for OpenFormula<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn nested(expr: &Expr) -> OpenFormula<'_> {
+            OpenFormula(expr)
+        }
+        match self.0 {
+            Expr::Boolean(b) => write!(f, "{}()", if *b { "TRUE" } else { "FALSE" }),
+            Expr::Reference(sheet, reference) => {
+                write!(f, "[")?;
+                OpenFormula::sheet(f, sheet)?;
+                write!(f, "{}]", reference)
+            },
+            Expr::Range(sheet, range) => {
+                let text = range.to_string();
+                let (start, end) = text.split_once(':').unwrap_or((&text, &text));
+                write!(f, "[")?;
+                OpenFormula::sheet(f, sheet)?;
+                write!(f, "{}:.{}]", start, end)
+            },
+            Expr::Unary(op, expr) => {
+                write!(f, "{}", if *op == UnaryOp::Negate { "-" } else { "+" })?;
+                if expr.precedence() < 6 { write!(f, "({})", nested(expr)) } else { write!(f, "{}", nested(expr)) }
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                if lhs.precedence() < op.precedence() { write!(f, "({})", nested(lhs))?; } else { write!(f, "{}", nested(lhs))?; }
+                write!(f, "{}", op.symbol())?;
+                if rhs.precedence() <= op.precedence() { write!(f, "({})", nested(rhs)) } else { write!(f, "{}", nested(rhs)) }
+            },
+            Expr::Function(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 { write!(f, ";")?; }
+                    write!(f, "{}", nested(arg))?;
+                }
+                write!(f, ")")
+            },
+            // Numbers, texts and errors are written alike
+            expr => write!(f, "{}", expr),
+        }
+    }
+}
+
+/// Simple engine formula as an OpenFormula attribute value
+fn to_openformula(text: &str) -> Option<String> {
+    engine_simple::parse_portable(text).map(|expr| format!("of:={}", OpenFormula(&expr)))
+}
+
+/// Text of a paragraph, spaces, tabs and line breaks being elements of their own
+fn paragraph_text(paragraph: &Element, text: &mut String) {
+    for node in paragraph.children.iter() {
+        match node {
+            Node::Text(part) => text.push_str(part),
+            Node::Element(element) => match element.name.as_str() {
+                "s" => {
+                    let count = element.attr("text:c").and_then(|c| c.parse::<usize>().ok()).unwrap_or(1);
+                    text.push_str(&" ".repeat(count));
+                },
+                "tab" => text.push('\t'),
+                "line-break" => text.push('\n'),
+                "annotation" | "note" => (),
+                _ => paragraph_text(element, text),
+            },
+        }
+    }
+}
+
+fn cell_text(cell: &Element) -> String {
+    cell.children("p").map(|paragraph| {
+        let mut text = String::new();
+        paragraph_text(paragraph, &mut text);
+        text
+    }).collect::<Vec<_>>().join("\n")
+}
+
+/// Value of a cell, as it would be entered, `None` for empty cells.
+/// Dates and times keep their ISO 8601 text.
+fn cell_value(cell: &Element) -> Option<String> {
+    let text = match cell.attr("office:value-type")? {
+        "float" | "percentage" | "currency" => return cell.attr("office:value").map(str::to_string),
+        "boolean" => return Some(if cell.attr("office:boolean-value") == Some("true") { "TRUE" } else { "FALSE" }.to_string()),
+        "date" => cell.attr("office:date-value")?.to_string(),
+        "time" => cell.attr("office:time-value")?.to_string(),
+        // LibreOffice marks error results of formulas this way
+        _ if cell.attr("calcext:value-type") == Some("error") => return Some(cell_text(cell)),
+        _ => cell.attr("office:string-value").map(str::to_string).unwrap_or_else(|| cell_text(cell)),
+    };
+    (!text.is_empty()).then(|| literal(text))
+}
+
+fn repeated(element: &Element, attr: &str) -> u32 {
+    element.attr(attr).and_then(|count| count.parse::<u32>().ok()).unwrap_or(1).max(1)
+}
+
+/// Rows of a table, including those within header and group elements
+fn table_rows<'a>(element: &'a Element, rows: &mut Vec<&'a Element>) {
+    for child in element.elements() {
+        match child.name.as_str() {
+            "table-row" => rows.push(child),
+            "table-header-rows" | "table-row-group" | "table-rows" => table_rows(child, rows),
+            _ => (),
+        }
+    }
+}
+
+fn read_table(table: &Element, sheet: &mut Sheet, name: &str, info: &mut ImportInfo) {
+    let mut rows = vec![];
+    table_rows(table, &mut rows);
+
+    let mut row = 0;
+    for row_xml in rows {
+        let row_count = repeated(row_xml, "table:number-rows-repeated");
+        // Cells of the row with how many times they repeat, identical cells being written once
+        let mut cells = vec![];
+        let mut col = 0;
+        for cell in row_xml.elements().filter(|cell| cell.name == "table-cell" || cell.name == "covered-table-cell") {
+            let col_count = repeated(cell, "table:number-columns-repeated");
+            let value = cell_value(cell);
+            let text = match cell.attr("table:formula") {
+                Some(formula) => from_openformula(formula).or_else(|| {
+                    let formula = formula.split_once('=').map_or(formula, |(_, formula)| formula).to_string();
+                    info.unsupported.push(Unsupported{sheet: name.to_string(), idx: CellIdx{col, row}, formula});
+                    value
+                }),
+                None => value,
+            };
+            if let Some(text) = text {
+                cells.push((col, col_count, text));
+            }
+            col = col.saturating_add(col_count);
+        }
+
+        for drow in 0..row_count {
+            for (col, col_count, text) in cells.iter() {
+                for dcol in 0..*col_count {
+                    let text = if dcol == 0 && drow == 0 { text.clone() } else { engine_simple::translate(text, dcol as i64, drow as i64) };
+                    sheet.set_text(CellIdx{col: col + dcol, row: row + drow}, text);
+                }
+            }
+        }
+        row = row.saturating_add(row_count);
+    }
+}
+
+/// Read an OpenDocument spreadsheet into a fresh state.
+/// Formulas the simple engine cannot evaluate are replaced by their cached values and listed in
+/// the returned info.
+pub fn import(bytes: &[u8]) -> Result<(SheetState, ImportInfo), FileError> {
+    let mut package = Package::open(bytes)?;
+    let content = package.read("content.xml")?.ok_or_else(|| format_error("no content part"))?;
+    let spreadsheet = content.child("body").and_then(|body| body.child("spreadsheet"))
+        .ok_or_else(|| format_error("not a spreadsheet"))?;
+    let tables = spreadsheet.children("table").collect::<Vec<_>>();
+    if tables.is_empty() {
+        return Err(format_error("no sheets"));
+    }
+
+    let names = tables.iter().map(|table| table.attr("table:name").unwrap_or_default()).collect::<Vec<_>>();
+    let mut workbook = Workbook::with_sheets(&names).map_err(format_error)?;
+    let mut info = ImportInfo::default();
+    for ((id, table), name) in workbook.sheet_ids().into_iter().zip(tables).zip(names.iter()) {
+        read_table(table, workbook.sheet_mut(id).unwrap(), name, &mut info);
+    }
+    // Importing is not something to undo
+    workbook.take_journal();
+
+    let mut state = SheetState::with_workbook(workbook);
+    state.text = state.sheet().get_text(&state.selected);
+    Ok((state, info))
+}
+
+pub fn import_file(path: &Path) -> Result<(SheetState, ImportInfo), FileError> {
+    import(&fs::read(path)?)
+}
+
+/// One `text:p` per line, runs of spaces and tabs written as elements so they are kept
+fn paragraphs(text: &str) -> String {
+    let mut out = String::new();
+    for line in text.split('\n') {
+        out.push_str("<text:p>");
+        let mut spaces = 0;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == ' ' {
+                spaces += 1;
+                if chars.peek() == Some(&' ') {
+                    continue;
+                }
+                // A single space between words stays as it is
+                let leading = out.ends_with("<text:p>");
+                if spaces == 1 && !leading && chars.peek().is_some() {
+                    out.push(' ');
+                } else {
+                    out.push_str(&format!("<text:s text:c=\"{}\"/>", spaces));
+                }
+                spaces = 0;
+            } else if c == '\t' {
+                out.push_str("<text:tab/>");
+            } else {
+                out.push_str(&xml::escape(&c.to_string()));
+            }
+        }
+        out.push_str("</text:p>");
+    }
+    out
+}
+
+/// `table:table-cell` element with an optional formula, `value` being the computed one
+fn cell_xml(out: &mut String, formula: Option<&str>, value: &Value, note: Option<&str>) {
+    out.push_str("<table:table-cell");
+    if let Some(formula) = formula {
+        out.push_str(&format!(" table:formula=\"{}\"", xml::escape(formula)));
+    }
+    let (attrs, text) = match value.to_scalar() {
+        Value::Number(n) => (format!(" office:value-type=\"float\" office:value=\"{}\"", n), format_number(n)),
+        Value::Text(text) => (" office:value-type=\"string\"".to_string(), text),
+        Value::Boolean(b) => (format!(" office:value-type=\"boolean\" office:boolean-value=\"{}\"", b), value.to_string()),
+        Value::Error(err) => (
+            " office:value-type=\"string\" office:string-value=\"\" calcext:value-type=\"error\"".to_string(),
+            err.kind.standard_code().to_string(),
+        ),
+        _ => (String::new(), String::new()),
+    };
+    out.push_str(&attrs);
+    out.push('>');
+    if let Some(note) = note {
+        out.push_str(&format!("<office:annotation>{}</office:annotation>", paragraphs(note)));
+    }
+    if !attrs.is_empty() {
+        out.push_str(&paragraphs(&text));
+    }
+    out.push_str("</table:table-cell>");
+}
+
+fn empty_cells(out: &mut String, count: u32) {
+    match count {
+        0 => (),
+        1 => out.push_str("<table:table-cell/>"),
+        _ => out.push_str(&format!("<table:table-cell table:number-columns-repeated=\"{}\"/>", count)),
+    }
+}
+
+fn table_xml(out: &mut String, state: &mut SheetState, sheet: SheetId) {
+    let name = state.workbook.name(sheet).unwrap().to_string();
+    let mut cells = state.workbook.sheet(sheet).unwrap().cells()
+        .filter(|(_, cell)| !cell.value.trim().is_empty())
+        .map(|(idx, cell)| (idx.clone(), Some(cell.clone())))
+        .collect::<Vec<_>>();
+    // Spilled elements are written as values, like the cells of other engines computing them
+    cells.extend(state.spilled_cells(sheet).into_iter().map(|idx| (idx, None)));
+    cells.sort_by_key(|(idx, _)| (idx.row, idx.col));
+    let cols = cells.iter().map(|(idx, _)| idx.col + 1).max().unwrap_or(1);
+
+    out.push_str(&format!("<table:table table:name=\"{}\"><table:table-column table:number-columns-repeated=\"{}\"/>", xml::escape(&name), cols));
+    // Next row and column to write
+    let (mut row, mut col) = (0, 0);
+    for (idx, cell) in cells {
+        if idx.row != row || col == 0 {
+            if col > 0 {
+                out.push_str("</table:table-row>");
+                row += 1;
+            }
+            let skipped = idx.row - row;
+            if skipped > 0 {
+                out.push_str(&format!("<table:table-row table:number-rows-repeated=\"{}\"><table:table-cell/></table:table-row>", skipped));
+            }
+            out.push_str("<table:table-row>");
+            row = idx.row;
+            col = 0;
+        }
+        empty_cells(out, idx.col - col);
+        col = idx.col + 1;
+
+        let mut value = state.get_value_at(&CellPos{sheet, idx: idx.clone()});
+        let note = cell.as_ref().and_then(engine_note);
+        let entered = cell.map(|cell| cell.value).filter(|text| text.starts_with('='));
+        let formula = match (&note, &entered, &value) {
+            (Some(_), _, _) => None,
+            (None, Some(text), _) => to_openformula(text),
+            // Error values can only be had from formulas
+            (None, None, Value::Error(err)) => Some(format!("of:={}", err.kind.standard_code())),
+            _ => None,
+        };
+        if let (None, None, Some(text)) = (&note, &formula, entered) {
+            // The text as entered, rather than an error that has lost its formula
+            value = Value::Text(text);
+        }
+        cell_xml(out, formula.as_deref(), &value, note.as_deref());
+    }
+    if col > 0 {
+        out.push_str("</table:table-row>");
+    } else {
+        out.push_str("<table:table-row><table:table-cell/></table:table-row>");
+    }
+    out.push_str("</table:table>");
+}
+
+/// Every sheet of the workbook as an OpenDocument spreadsheet, formulas as OpenFormula along
+/// with their computed values
+pub fn export(state: &mut SheetState) -> Vec<u8> {
+    let mut content = format!("{}<office:document-content \
+        xmlns:office=\"urn:oasis:names:tc:opendocument:xmlns:office:1.0\" \
+        xmlns:table=\"urn:oasis:names:tc:opendocument:xmlns:table:1.0\" \
+        xmlns:text=\"urn:oasis:names:tc:opendocument:xmlns:text:1.0\" \
+        xmlns:of=\"urn:oasis:names:tc:opendocument:xmlns:of:1.2\" \
+        xmlns:calcext=\"urn:org:documentfoundation:names:experimental:calc:xmlns:calcext:1.0\" \
+        office:version=\"1.2\"><office:body><office:spreadsheet>", XML_DECLARATION);
+    for id in state.workbook.sheet_ids() {
+        table_xml(&mut content, state, id);
+    }
+    content.push_str("</office:spreadsheet></office:body></office:document-content>");
+
+    let manifest = format!("{}<manifest:manifest xmlns:manifest=\"urn:oasis:names:tc:opendocument:xmlns:manifest:1.0\" manifest:version=\"1.2\">\
+        <manifest:file-entry manifest:full-path=\"/\" manifest:version=\"1.2\" manifest:media-type=\"{}\"/>\
+        <manifest:file-entry manifest:full-path=\"content.xml\" manifest:media-type=\"text/xml\"/>\
+        </manifest:manifest>", XML_DECLARATION, MIME_TYPE);
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+    // The media type comes first and uncompressed, so the format can be told from the first bytes
+    zip.start_file("mimetype", FileOptions::default().compression_method(CompressionMethod::Stored)).unwrap();
+    zip.write_all(MIME_TYPE.as_bytes()).unwrap();
+    for (path, part) in [("META-INF/manifest.xml", manifest), ("content.xml", content)] {
+        zip.start_file(path, FileOptions::default()).unwrap();
+        zip.write_all(part.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+pub fn export_file(state: &mut SheetState, path: &Path) -> Result<(), FileError> {
+    fs::write(path, export(state))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formulas() {
+        assert_eq!(from_openformula("of:=SUM([.A1:.B2];[$'Q1 Data'.$C$3])").as_deref(), Some("=SUM(A1:B2, 'Q1 Data'!$C$3)"));
+        assert_eq!(from_openformula("of:=IF([Sheet2.A1:Sheet2.A4]>1;\"a;[b]\";TRUE())").as_deref(), Some("=IF(Sheet2!A1:A4>1, \"a;[b]\", TRUE)"));
+        assert_eq!(from_openformula("of:=['It''s'.A:.B]").as_deref(), Some("='It''s'!A:B"));
+        // Ranges across sheets and functions the simple engine lacks
+        assert_eq!(from_openformula("of:=SUM([Sheet1.A1:Sheet2.A1])"), None);
+        assert_eq!(from_openformula("of:=VLOOKUP([.A1];[.B1:.C9];2)"), None);
+
+        assert_eq!(to_openformula("=SUM(A1:B2, 'Q1 Data'!$C$3)*-(1+A1)").as_deref(), Some("of:=SUM([.A1:.B2];['Q1 Data'.$C$3])*-(1+[.A1])"));
+        assert_eq!(to_openformula("=IF(TRUE, 1:3, \"x\")").as_deref(), Some("of:=IF(TRUE();[.1:.3];\"x\")"));
+        assert_eq!(to_openformula("=#CIRCULAR!"), None);
+    }
+
+    const CONTENT: &str = r#"<office:document-content xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0"
+        xmlns:table="urn:oasis:names:tc:opendocument:xmlns:table:1.0" xmlns:text="urn:oasis:names:tc:opendocument:xmlns:text:1.0"
+        xmlns:calcext="urn:org:documentfoundation:names:experimental:calc:xmlns:calcext:1.0"><office:body><office:spreadsheet>
+        <table:table table:name="Sheet1"><table:table-column table:number-columns-repeated="3"/>
+        <table:table-header-rows><table:table-row>
+            <table:table-cell office:value-type="string"><text:p>Q1<text:s/><text:s text:c="2"/>sales</text:p><text:p>net</text:p></table:table-cell>
+            <table:table-cell table:number-columns-repeated="2" office:value-type="float" office:value="1.5"><text:p>1.5</text:p></table:table-cell>
+        </table:table-row></table:table-header-rows>
+        <table:table-row table:number-rows-repeated="2">
+            <table:table-cell table:formula="of:=[.B1]*2" office:value-type="float" office:value="3"/>
+            <table:table-cell table:formula="of:=1/0" office:value-type="string" office:string-value="" calcext:value-type="error"><text:p>#DIV/0!</text:p></table:table-cell>
+            <table:table-cell table:formula="of:=VLOOKUP(1;[.A1:.B2];2)" office:value-type="boolean" office:boolean-value="true"/>
+        </table:table-row>
+        <table:table-row table:number-rows-repeated="1048570"><table:table-cell table:number-columns-repeated="1024"/></table:table-row>
+        </table:table><table:table table:name="Q1 Data"/>
+        </office:spreadsheet></office:body></office:document-content>"#;
+
+    #[test]
+    fn import_document() {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("content.xml", FileOptions::default()).unwrap();
+        zip.write_all(CONTENT.as_bytes()).unwrap();
+        let (mut state, info) = import(&zip.finish().unwrap().into_inner()).unwrap();
+
+        assert_eq!(state.workbook.sheet_ids().len(), 2);
+        assert_eq!(state.workbook.name(state.workbook.sheet_ids()[1]), Some("Q1 Data"));
+        let text = |state: &SheetState, col, row| state.sheet().get_text(&CellIdx{col, row});
+        assert_eq!(text(&state, 0, 0), "Q1   sales\nnet");
+        assert_eq!((text(&state, 1, 0), text(&state, 2, 0)), ("1.5".to_string(), "1.5".to_string()));
+        assert_eq!((text(&state, 0, 1), text(&state, 0, 2)), ("=B1*2".to_string(), "=B2*2".to_string()));
+        assert_eq!(text(&state, 1, 2), "=1/0");
+        assert_eq!(text(&state, 2, 1), "TRUE");
+        assert_eq!(info.unsupported.len(), 1);
+        assert_eq!(info.unsupported[0].formula, "VLOOKUP(1;[.A1:.B2];2)");
+        assert_eq!(state.get_value(&CellIdx{col: 0, row: 1}).to_string(), "3");
+        assert_eq!(state.get_value(&CellIdx{col: 0, row: 2}).to_string(), "#DIV/0!");
+        assert_eq!(state.sheet().extent(), CellIdx{col: 3, row: 3});
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut state = SheetState::new();
+        let data = state.workbook.add_sheet("Q1 Data").unwrap();
+        let set = |state: &mut SheetState, col, row, text: &str| state.sheet_mut().set_text(CellIdx{col, row}, text.to_string());
+        set(&mut state, 0, 0, "two  spaces\tand\na line");
+        set(&mut state, 2, 0, "=SUM('Q1 Data'!A1:A2)/2");
+        set(&mut state, 1, 3, "TRUE");
+        set(&mut state, 2, 3, "#N/A");
+        set(&mut state, 3, 3, "=1+");
+        set(&mut state, 4, 3, "'=text");
+        state.set_active(data);
+        set(&mut state, 0, 0, "3");
+        set(&mut state, 0, 1, "4.5");
+        state.set_active(state.workbook.sheet_ids()[0]);
+
+        assert_eq!(paragraphs(" a b  "), "<text:p><text:s text:c=\"1\"/>a b<text:s text:c=\"2\"/></text:p>");
+        let (mut imported, info) = import(&export(&mut state)).unwrap();
+        assert!(info.unsupported.is_empty());
+        let text = |state: &SheetState, col, row| state.sheet().get_text(&CellIdx{col, row});
+        assert_eq!(text(&imported, 0, 0), "two  spaces\tand\na line");
+        assert_eq!(text(&imported, 2, 0), "=SUM('Q1 Data'!A1:A2)/2");
+        assert_eq!(imported.get_value(&CellIdx{col: 2, row: 0}), Value::Number(3.75));
+        assert_eq!(text(&imported, 1, 3), "TRUE");
+        assert_eq!(text(&imported, 2, 3), "=#N/A");
+        assert_eq!(text(&imported, 3, 3), "'=1+");
+        assert_eq!(text(&imported, 4, 3), "'=text");
+        assert_eq!(imported.sheet().extent(), CellIdx{col: 5, row: 4});
+
+        imported.set_active(imported.workbook.sheet_ids()[1]);
+        assert_eq!(text(&imported, 0, 1), "4.5");
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt;
+
+use crate::sheet::CellIdx;
+/// Rectangle of cells, where references to ranges point
+pub use crate::dependencies::Area;
+
+/// Columns of a sheet, `XFD` is the last one
+pub const MAX_COLS: u32 = 16384;
+/// Rows of a sheet
+pub const MAX_ROWS: u32 = 1048576;
+
+/// Column letters to a zero based column index, "A" is 0 and "AA" is 26. `None` past the last column.
+pub fn str_to_col(s: &str) -> Option<u32> {
+    let col = s.chars().try_fold(0u32, |col, c| {
+        col.checked_mul(26)?.checked_add(c as u32 - 'A' as u32 + 1).filter(|col| *col <= MAX_COLS)
+    })?;
+    col.checked_sub(1)
+}
+
+/// Zero based column index to column letters
+pub fn col_to_str(col: u32) -> String {
+    let mut scratch = col;
+    let mut text = String::new();
+    loop {
+        let current: u8 = (scratch % 26) as u8;
+        text.insert(0, (current+b'A') as char);
+        scratch /=  26;
+        if scratch == 0 {
+            break;
+        }
+        scratch -= 1;
+    }
+
+    text
+}
+
+/// Sheet name as written in a formula, quoted unless it is a plain identifier
+pub fn sheet_to_str(name: &str) -> String {
+    let mut chars = name.chars();
+    let plain = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    if plain {
+        name.to_string()
+    } else {
+        format!("'{}'", name.replace('\'', "''"))
+    }
+}
+
+/// Zero based column or row of a reference, `absolute` when anchored with `$`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Coord {
+    pub index: u32,
+    pub absolute: bool,
+}
+
+impl Coord {
+    pub fn relative(index: u32) -> Self {
+        Coord{index, absolute: false}
+    }
+
+    pub fn absolute(index: u32) -> Self {
+        Coord{index, absolute: true}
+    }
+
+    /// Move a relative coordinate, `None` when it falls off the sheet
+    pub fn offset(&self, delta: i64) -> Option<Coord> {
+        if self.absolute {
+            return Some(*self);
+        }
+        let index = u32::try_from(self.index as i64 + delta).ok()?;
+        Some(Coord{index, ..*self})
+    }
+
+    /// Follow `count` rows / columns inserted (or removed, when negative) at `at`.
+    /// Applies to anchored coordinates as well, they point at the same data after the move.
+    pub fn shift(&self, at: u32, count: i64) -> Option<Coord> {
+        if self.index < at {
+            return Some(*self);
+        }
+        if count < 0 && (self.index as i64) < at as i64 - count {
+            // The referenced row / column itself was removed
+            return None;
+        }
+        let index = u32::try_from(self.index as i64 + count).ok()?;
+        Some(Coord{index, ..*self})
+    }
+
+    fn fmt_col(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", if self.absolute { "$" } else { "" }, col_to_str(self.index))
+    }
+
+    fn fmt_row(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", if self.absolute { "$" } else { "" }, self.index + 1)
+    }
+}
+
+/// Direction of an insertion or removal
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Axis {
+    Col,
+    Row,
+}
+
+/// Single cell reference, like `A1`, `$A$1`, `A$1` or `$A1`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CellRef {
+    pub col: Coord,
+    pub row: Coord,
+}
+
+impl CellRef {
+    pub fn new(col: Coord, row: Coord) -> Self {
+        CellRef{col, row}
+    }
+
+    pub fn idx(&self) -> CellIdx {
+        CellIdx{col: self.col.index, row: self.row.index}
+    }
+
+    /// Reference as it reads after copying the formula `dcol` columns and `drow` rows away
+    pub fn offset(&self, dcol: i64, drow: i64) -> Option<CellRef> {
+        Some(CellRef{col: self.col.offset(dcol)?, row: self.row.offset(drow)?})
+    }
+
+    pub fn shift(&self, axis: Axis, at: u32, count: i64) -> Option<CellRef> {
+        match axis {
+            Axis::Col => Some(CellRef{col: self.col.shift(at, count)?, ..*self}),
+            Axis::Row => Some(CellRef{row: self.row.shift(at, count)?, ..*self}),
+        }
+    }
+}
+
+impl fmt::Display for CellRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.col.fmt_col(f)?;
+        self.row.fmt_row(f)
+    }
+}
+
+/// Rectangular reference, `None` spans the whole column (`A:B`) or row (`1:2`).
+/// Bounds are kept ordered, start first.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct RangeRef {
+    pub cols: Option<(Coord, Coord)>,
+    pub rows: Option<(Coord, Coord)>,
+}
+
+fn ordered(a: Coord, b: Coord) -> (Coord, Coord) {
+    if a.index <= b.index { (a, b) } else { (b, a) }
+}
+
+fn offset_bounds(bounds: Option<(Coord, Coord)>, delta: i64) -> Option<Option<(Coord, Coord)>> {
+    match bounds {
+        Some((start, end)) => Some(Some(ordered(start.offset(delta)?, end.offset(delta)?))),
+        None => Some(None),
+    }
+}
+
+fn shift_bounds(bounds: Option<(Coord, Coord)>, at: u32, count: i64) -> Option<Option<(Coord, Coord)>> {
+    let (start, end) = match bounds {
+        Some(bounds) => bounds,
+        None => return Some(None),
+    };
+    if count >= 0 {
+        return Some(Some((start.shift(at, count)?, end.shift(at, count)?)));
+    }
+
+    // A partially removed range shrinks, it only breaks once all of it is gone
+    let removed_end = at as i64 - count;
+    let start = match start.shift(at, count) {
+        Some(start) => start,
+        None if (end.index as i64) < removed_end => return None,
+        None => Coord{index: at, ..start},
+    };
+    let end = match end.shift(at, count) {
+        Some(end) => end,
+        None if at == 0 => return None,
+        None => Coord{index: at - 1, ..end},
+    };
+    Some(Some((start, end)))
+}
+
+impl RangeRef {
+    pub fn cells(start: CellRef, end: CellRef) -> Self {
+        RangeRef{cols: Some(ordered(start.col, end.col)), rows: Some(ordered(start.row, end.row))}
+    }
+
+    pub fn cols(start: Coord, end: Coord) -> Self {
+        RangeRef{cols: Some(ordered(start, end)), rows: None}
+    }
+
+    pub fn rows(start: Coord, end: Coord) -> Self {
+        RangeRef{cols: None, rows: Some(ordered(start, end))}
+    }
+
+    pub fn area(&self) -> Area {
+        Area{
+            cols: self.cols.map(|(start, end)| (start.index, end.index)),
+            rows: self.rows.map(|(start, end)| (start.index, end.index)),
+        }
+    }
+
+    pub fn offset(&self, dcol: i64, drow: i64) -> Option<RangeRef> {
+        Some(RangeRef{cols: offset_bounds(self.cols, dcol)?, rows: offset_bounds(self.rows, drow)?})
+    }
+
+    pub fn shift(&self, axis: Axis, at: u32, count: i64) -> Option<RangeRef> {
+        match axis {
+            Axis::Col => Some(RangeRef{cols: shift_bounds(self.cols, at, count)?, ..*self}),
+            Axis::Row => Some(RangeRef{rows: shift_bounds(self.rows, at, count)?, ..*self}),
+        }
+    }
+}
+
+impl fmt::Display for RangeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.cols, self.rows) {
+            (Some((start_col, end_col)), Some((start_row, end_row))) => {
+                write!(f, "{}:{}", CellRef::new(start_col, start_row), CellRef::new(end_col, end_row))
+            },
+            (Some((start, end)), None) => {
+                start.fmt_col(f)?;
+                write!(f, ":")?;
+                end.fmt_col(f)
+            },
+            (None, Some((start, end))) => {
+                start.fmt_row(f)?;
+                write!(f, ":")?;
+                end.fmt_row(f)
+            },
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns() {
+        for (text, col) in [("A", 0), ("Z", 25), ("AA", 26), ("BB", 53), ("ZZ", 701), ("AAA", 702)] {
+            assert_eq!(str_to_col(text), Some(col));
+            assert_eq!(col_to_str(col), text);
+        }
+        assert_eq!(str_to_col("XFD"), Some(MAX_COLS - 1));
+        assert_eq!(str_to_col("XFE"), None);
+        assert_eq!(str_to_col("AAAAAAAAA"), None);
+    }
+
+    #[test]
+    fn sheet_names() {
+        assert_eq!(sheet_to_str("Sheet2"), "Sheet2");
+        assert_eq!(sheet_to_str("Q1 Data"), "'Q1 Data'");
+        assert_eq!(sheet_to_str("Bob's"), "'Bob''s'");
+        assert_eq!(sheet_to_str("2021"), "'2021'");
+    }
+
+    #[test]
+    fn offset() {
+        let mixed = CellRef::new(Coord::absolute(0), Coord::relative(1));
+        assert_eq!(mixed.to_string(), "$A2");
+        assert_eq!(mixed.offset(3, 2).unwrap().to_string(), "$A4");
+        assert_eq!(mixed.offset(3, -2), None);
+
+        let range = RangeRef::cells(CellRef::new(Coord::relative(1), Coord::absolute(0)), CellRef::new(Coord::relative(2), Coord::relative(4)));
+        assert_eq!(range.to_string(), "B$1:C5");
+        assert_eq!(range.offset(1, 1).unwrap().to_string(), "C$1:D6");
+
+        let cols = RangeRef::cols(Coord::relative(0), Coord::absolute(1));
+        assert_eq!(cols.to_string(), "A:$B");
+        assert_eq!(cols.offset(1, 100).unwrap().to_string(), "B:$B");
+    }
+
+    #[test]
+    fn shift() {
+        let anchored = CellRef::new(Coord::absolute(1), Coord::absolute(4));
+        assert_eq!(anchored.shift(Axis::Row, 2, 3).unwrap().to_string(), "$B$8");
+        assert_eq!(anchored.shift(Axis::Row, 5, 3).unwrap().to_string(), "$B$5");
+        assert_eq!(anchored.shift(Axis::Col, 0, -1).unwrap().to_string(), "$A$5");
+        assert_eq!(anchored.shift(Axis::Col, 1, -1), None);
+
+        let range = RangeRef::cells(CellRef::new(Coord::relative(0), Coord::relative(1)), CellRef::new(Coord::relative(0), Coord::relative(5)));
+        assert_eq!(range.shift(Axis::Row, 3, 2).unwrap().to_string(), "A2:A8");
+        assert_eq!(range.shift(Axis::Row, 0, -3).unwrap().to_string(), "A1:A3");
+        assert_eq!(range.shift(Axis::Row, 4, -10).unwrap().to_string(), "A2:A4");
+        assert_eq!(range.shift(Axis::Row, 1, -5), None);
+    }
+}
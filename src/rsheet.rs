@@ -0,0 +1,222 @@
+use std::{fs, path::Path, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{sheet::{Cell, CellIdx}, engine::{self, EngineId}, sheet_state::SheetState, workbook::Workbook, file::{FileError, format_error}};
+
+/// Extension of native workbook files
+pub const EXTENSION: &str = "rsheet";
+/// Format marker at the top of every file
+const FORMAT: &str = "rusty-sheet";
+/// Version written by this build, files of a later version are refused
+pub const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct WorkbookFile {
+    format: String,
+    version: u32,
+    sheets: Vec<SheetFile>,
+    #[serde(default)]
+    view: ViewFile,
+    /// Code shared by the Python cells
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    python_module: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SheetFile {
+    name: String,
+    cells: Vec<CellFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CellFile {
+    col: u32,
+    row: u32,
+    #[serde(default = "simple_engine", skip_serializing_if = "is_simple_engine")]
+    engine: String,
+    value: String,
+}
+
+fn simple_engine() -> String {
+    "simple".to_string()
+}
+
+fn is_simple_engine(engine: &str) -> bool {
+    engine == "simple"
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ViewFile {
+    /// Position of the active sheet
+    sheet: usize,
+    selected: CellIdx,
+    view_offset: CellIdx,
+}
+
+fn engine_from_name(name: &str) -> Result<EngineId, FileError> {
+    engine::find(name).map(|engine| engine.id()).ok_or_else(|| FileError::Engine(name.to_string()))
+}
+
+/// Workbook and view state as a native file
+pub fn save(state: &SheetState) -> Vec<u8> {
+    let workbook = &state.workbook;
+    let sheets = workbook.sheet_ids().into_iter().map(|id| {
+        let mut cells = workbook.sheet(id).unwrap().cells()
+            .filter(|(_, cell)| !cell.value.is_empty())
+            .map(|(idx, cell)| CellFile{col: idx.col, row: idx.row, engine: cell.engine.name().to_string(), value: cell.value.clone()})
+            .collect::<Vec<_>>();
+        // Stable order, so saving the same workbook twice gives the same file
+        cells.sort_by_key(|cell| (cell.row, cell.col));
+        SheetFile{name: workbook.name(id).unwrap().to_string(), cells}
+    }).collect();
+    let view = ViewFile{
+        sheet: workbook.position(state.active()).unwrap_or(0),
+        selected: state.selected.clone(),
+        view_offset: state.view_offset.clone(),
+    };
+
+    let python_module = workbook.python_module().to_string();
+    let file = WorkbookFile{format: FORMAT.to_string(), version: VERSION, sheets, view, python_module};
+    serde_json::to_vec_pretty(&file).unwrap()
+}
+
+/// Read a native file into a fresh state, with an empty history
+pub fn load(bytes: &[u8]) -> Result<SheetState, FileError> {
+    let file: WorkbookFile = serde_json::from_slice(bytes).map_err(format_error)?;
+    if file.format != FORMAT {
+        return Err(FileError::Format(format!("unknown format \"{}\"", file.format)));
+    }
+    if file.version > VERSION {
+        return Err(FileError::Version(file.version));
+    }
+    if file.sheets.is_empty() {
+        return Err(FileError::Format("no sheets".to_string()));
+    }
+
+    let names = file.sheets.iter().map(|sheet| sheet.name.as_str()).collect::<Vec<_>>();
+    let mut workbook = Workbook::with_sheets(&names).map_err(format_error)?;
+    let ids = workbook.sheet_ids();
+    for (id, sheet_file) in ids.iter().zip(file.sheets) {
+        let sheet = workbook.sheet_mut(*id).unwrap();
+        for cell in sheet_file.cells {
+            let engine = engine_from_name(&cell.engine)?;
+            sheet.insert(CellIdx{col: cell.col, row: cell.row}, Cell{engine, value: cell.value});
+        }
+    }
+    workbook.set_python_module(&file.python_module);
+    // Loading is not something to undo
+    workbook.take_journal();
+
+    let mut state = SheetState::with_workbook(workbook);
+    if let Some(id) = ids.get(file.view.sheet) {
+        state.set_active(*id);
+    }
+    state.selected = file.view.selected;
+    state.view_offset = file.view.view_offset;
+    state.text = state.sheet().get_text(&state.selected);
+    Ok(state)
+}
+
+/// Save to `path` and remember it as the file of the state
+pub fn save_file(state: &mut SheetState, path: &Path) -> Result<(), FileError> {
+    // Write next to the target first, so a failed save never leaves half a workbook behind
+    let scratch = path.with_extension(format!("{}.tmp", EXTENSION));
+    fs::write(&scratch, save(state))?;
+    fs::rename(&scratch, path)?;
+    state.path = Some(path.to_path_buf());
+    state.mark_saved();
+    Ok(())
+}
+
+pub fn open_file(path: &Path) -> Result<SheetState, FileError> {
+    let mut state = load(&fs::read(path)?)?;
+    state.path = Some(path.to_path_buf());
+    Ok(state)
+}
+
+/// Saves modified workbooks that have a file, at most once every `interval`
+pub struct Autosave {
+    pub interval: Duration,
+    last: Instant,
+}
+
+impl Autosave {
+    pub fn new(interval: Duration) -> Self {
+        Autosave{interval, last: Instant::now()}
+    }
+
+    /// When the next save may happen
+    pub fn deadline(&self) -> Instant {
+        self.last + self.interval
+    }
+
+    /// Save if due, `None` when nothing had to be saved
+    pub fn tick(&mut self, state: &mut SheetState) -> Option<Result<(), FileError>> {
+        if Instant::now() < self.deadline() || !state.is_dirty() {
+            return None;
+        }
+        let path = state.path.clone()?;
+        self.last = Instant::now();
+        Some(save_file(state, &path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut state = SheetState::new();
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 0}, "2".to_string());
+        let data = state.workbook.add_sheet("Q1 Data").unwrap();
+        state.set_active(data);
+        state.sheet_mut().set_text(CellIdx{col: 1, row: 3}, "=Sheet1!A1*3".to_string());
+        state.selected = CellIdx{col: 1, row: 3};
+        state.view_offset = CellIdx{col: 0, row: 2};
+        state.workbook.set_python_module("import math\n");
+
+        let mut loaded = load(&save(&state)).unwrap();
+        assert_eq!(loaded.workbook.sheet_ids().len(), 2);
+        assert_eq!(loaded.workbook.name(loaded.active()), Some("Q1 Data"));
+        assert_eq!(loaded.selected, state.selected);
+        assert_eq!(loaded.view_offset, state.view_offset);
+        assert_eq!(loaded.text, "=Sheet1!A1*3");
+        assert_eq!(loaded.workbook.python_module(), "import math\n");
+        assert_eq!(loaded.get_value(&CellIdx{col: 1, row: 3}).to_string(), "6");
+        assert!(!loaded.undo());
+        assert_eq!(save(&loaded), save(&state));
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(matches!(load(b"not json"), Err(FileError::Format(_))));
+        assert!(matches!(load(br#"{"format": "other", "version": 1, "sheets": []}"#), Err(FileError::Format(_))));
+        assert!(matches!(load(br#"{"format": "rusty-sheet", "version": 99, "sheets": []}"#), Err(FileError::Version(99))));
+        let unknown = br#"{"format": "rusty-sheet", "version": 1, "sheets": [
+            {"name": "S", "cells": [{"col": 0, "row": 0, "engine": "lua", "value": "1"}]}]}"#;
+        assert!(matches!(load(unknown), Err(FileError::Engine(_))));
+    }
+
+    #[test]
+    fn dirty_state() {
+        let path = std::env::temp_dir().join(format!("rusty-sheet-test-{}.{}", std::process::id(), EXTENSION));
+        let mut state = SheetState::new();
+        assert!(!state.is_dirty());
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 0}, "1".to_string());
+        state.commit();
+        assert!(state.is_dirty());
+
+        save_file(&mut state, &path).unwrap();
+        assert!(!state.is_dirty());
+        let mut autosave = Autosave::new(Duration::from_secs(0));
+        assert!(autosave.tick(&mut state).is_none());
+
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 0}, "2".to_string());
+        state.commit();
+        assert!(autosave.tick(&mut state).unwrap().is_ok());
+        assert_eq!(open_file(&path).unwrap().sheet().get_text(&CellIdx{col: 0, row: 0}), "2");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,10 @@
 use std::{collections::HashMap, ops::Add};
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+use serde::{Serialize, Deserialize};
+
+use crate::{engine_simple, engine::EngineId, reference::{Axis, Coord}};
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
 pub struct CellIdx {
     pub col: u32,
     pub row: u32,
@@ -17,30 +21,48 @@ impl Add for CellIdx {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug, Copy)]
-pub enum EngineType {
-    Simple,
-    #[cfg(feature = "python")]
-    Python
-}
-
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Cell {
-    pub engine: EngineType,
+    pub engine: EngineId,
     pub value: String,
 }
 
+/// A cell replaced, `None` standing for no cell at all
+#[derive(Clone, PartialEq, Debug)]
+pub struct CellEdit {
+    pub idx: CellIdx,
+    pub old: Option<Cell>,
+    pub new: Option<Cell>,
+}
+
 pub struct Sheet {
     cells: HashMap<CellIdx, Cell>,
+    /// Cells modified since the last `take_changes`
+    changes: Vec<CellIdx>,
+    /// Every modification since the last `take_journal`, in order
+    journal: Vec<CellEdit>,
 }
 
 impl Sheet {
     pub fn new() -> Self {
-        Sheet{cells: HashMap::new()}
+        Sheet{cells: HashMap::new(), changes: vec![], journal: vec![]}
     }
 
     pub fn insert(&mut self, idx: CellIdx, value: Cell) {
-        self.cells.insert(idx, value);
+        self.put(idx, Some(value));
+    }
+
+    /// Replace or remove a cell, all modifications go through here
+    pub(crate) fn put(&mut self, idx: CellIdx, cell: Option<Cell>) {
+        if self.cells.get(&idx) == cell.as_ref() {
+            return;
+        }
+        let old = match &cell {
+            Some(cell) => self.cells.insert(idx.clone(), cell.clone()),
+            None => self.cells.remove(&idx),
+        };
+        self.changes.push(idx.clone());
+        self.journal.push(CellEdit{idx, old, new: cell});
     }
 
     pub fn get(&self, idx: &CellIdx) -> Option<&Cell> {
@@ -49,11 +71,11 @@ impl Sheet {
 
     pub fn set_text(&mut self, idx: CellIdx, value: String) {
         let engine = if let Some(current) = self.cells.get(&idx) {
-            current.engine.clone()
+            current.engine
         } else {
-            EngineType::Simple
+            EngineId::SIMPLE
         };
-        self.cells.insert(idx, Cell{engine, value});
+        self.insert(idx, Cell{engine, value});
     }
 
     pub fn get_text(&self, idx: &CellIdx) -> String {
@@ -62,6 +84,96 @@ impl Sheet {
             None => "".to_string(),
         }
     }
+
+    /// All cells, in no particular order
+    pub fn cells(&self) -> impl Iterator<Item = (&CellIdx, &Cell)> {
+        self.cells.iter()
+    }
+
+    /// Cells modified since the last call
+    pub fn take_changes(&mut self) -> Vec<CellIdx> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Modifications since the last call, oldest first
+    pub(crate) fn take_journal(&mut self) -> Vec<CellEdit> {
+        std::mem::take(&mut self.journal)
+    }
+
+    /// Copy a cell, moving the relative references of its formula along
+    pub fn copy_cell(&mut self, from: &CellIdx, to: CellIdx) {
+        let mut cell = match self.cells.get(from) {
+            Some(cell) => cell.clone(),
+            None => Cell{engine: EngineId::SIMPLE, value: "".to_string()},
+        };
+        if cell.engine == EngineId::SIMPLE {
+            let dcol = to.col as i64 - from.col as i64;
+            let drow = to.row as i64 - from.row as i64;
+            cell.value = engine_simple::translate(&cell.value, dcol, drow);
+        }
+        self.insert(to, cell);
+    }
+
+    /// Move the cells from `at` on by `count` rows / columns, dropping removed ones.
+    /// Formulas are left to `Workbook`, which knows about references from other sheets.
+    pub(crate) fn move_cells(&mut self, axis: Axis, at: u32, count: i64) {
+        let coord = |idx: &CellIdx| match axis {
+            Axis::Col => idx.col,
+            Axis::Row => idx.row,
+        };
+        let mut moving = self.cells.iter()
+            .filter(|(idx, _)| coord(idx) >= at)
+            .map(|(idx, cell)| (idx.clone(), cell.clone()))
+            .collect::<Vec<_>>();
+        // Deterministic order keeps the journal reproducible
+        moving.sort_by_key(|(idx, _)| (idx.row, idx.col));
+
+        for (idx, _) in moving.iter() {
+            self.put(idx.clone(), None);
+        }
+        for (idx, cell) in moving {
+            if let Some(moved) = Coord::relative(coord(&idx)).shift(at, count) {
+                let moved = match axis {
+                    Axis::Col => CellIdx{col: moved.index, ..idx},
+                    Axis::Row => CellIdx{row: moved.index, ..idx},
+                };
+                self.put(moved, Some(cell));
+            }
+        }
+    }
+
+    /// Replace the text of every cell of `engine` with `func(text)`
+    pub(crate) fn rewrite<F>(&mut self, engine: EngineId, func: F) where F: Fn(&str) -> String {
+        let rewritten = self.cells.iter()
+            .filter(|(_, cell)| cell.engine == engine)
+            .filter_map(|(idx, cell)| {
+                let value = func(&cell.value);
+                (value != cell.value).then(|| (idx.clone(), Cell{engine, value}))
+            })
+            .collect::<Vec<_>>();
+        for (idx, cell) in rewritten {
+            self.put(idx, Some(cell));
+        }
+    }
+
+    /// Number of columns and rows in use, counted from A1
+    pub fn extent(&self) -> CellIdx {
+        let mut extent = CellIdx{col: 0, row: 0};
+        for (idx, cell) in self.cells.iter() {
+            if cell.value.trim().is_empty() {
+                continue;
+            }
+            extent.col = extent.col.max(idx.col + 1);
+            extent.row = extent.row.max(idx.row + 1);
+        }
+        extent
+    }
+}
+
+impl Default for Sheet {
+    fn default() -> Self {
+        Sheet::new()
+    }
 }
 
 #[cfg(test)]
@@ -78,13 +190,48 @@ mod tests {
         assert_eq!(sheet.get_text(&idx), "test".to_string());
     }
 
+    #[test]
+    fn extent() {
+        let mut sheet = Sheet::new();
+        assert_eq!(sheet.extent(), CellIdx{col: 0, row: 0});
+
+        sheet.set_text(CellIdx{col: 5, row: 3}, "test".to_string());
+        sheet.set_text(CellIdx{col: 1, row: 8}, "test".to_string());
+        sheet.set_text(CellIdx{col: 9, row: 9}, "".to_string());
+        assert_eq!(sheet.extent(), CellIdx{col: 6, row: 9});
+    }
+
+    #[test]
+    fn changes() {
+        let mut sheet = Sheet::new();
+        let idx = CellIdx{col: 5, row: 3};
+
+        sheet.set_text(idx.clone(), "test".to_string());
+        sheet.set_text(idx.clone(), "test".to_string());
+        assert_eq!(sheet.take_changes(), vec![idx.clone()]);
+        assert_eq!(sheet.take_changes(), vec![]);
+    }
+
+    #[test]
+    fn copy_cell() {
+        let mut sheet = Sheet::new();
+        sheet.set_text(CellIdx{col: 2, row: 2}, "=A1+$A1+A$1+$A$1+SUM(A1:B$2)".to_string());
+
+        sheet.copy_cell(&CellIdx{col: 2, row: 2}, CellIdx{col: 3, row: 4});
+        assert_eq!(sheet.get_text(&CellIdx{col: 3, row: 4}), "=B3+$A3+B$1+$A$1+SUM(B$2:C3)");
+
+        sheet.copy_cell(&CellIdx{col: 2, row: 2}, CellIdx{col: 1, row: 2});
+        assert_eq!(sheet.get_text(&CellIdx{col: 1, row: 2}), "=#REF!+$A1+#REF!+$A$1+SUM(#REF!)");
+    }
+
+    #[cfg(feature = "python")]
     #[test]
     fn engine() {
         let mut sheet = Sheet::new();
         let idx = CellIdx{col: 5, row: 3};
         assert_eq!(sheet.get(&idx), None);
 
-        let cell = Cell{engine: EngineType::Python, value: "test".to_string()};
+        let cell = Cell{engine: EngineId::PYTHON, value: "test".to_string()};
         sheet.insert(idx.clone(), cell.clone());
         assert_eq!(sheet.get(&idx), Some(&cell));
 
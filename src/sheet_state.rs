@@ -1,52 +1,469 @@
+use std::{collections::{HashMap, HashSet}, path::PathBuf, rc::Rc};
 
-use crate::{sheet::*, engine_simple};
-#[cfg(feature = "python")]
-use create::engine_python;
+use crate::{
+    sheet::*, engine::{self, EngineId, Formula}, value::Value, dependencies::{Area, DependencyGraph},
+    workbook::{Workbook, SheetId, CellPos}, error::{CellError, ErrorKind, Exception}, history::History, reference::col_to_str,
+};
 
 pub struct SheetState {
     pub selected: CellIdx,
     pub view_offset: CellIdx,
     pub text: String,
-    pub sheet: Sheet,
+    pub workbook: Workbook,
+    pub history: History,
+    /// File the workbook was opened from or last saved to
+    pub path: Option<PathBuf>,
+    /// Modified since opened or last saved
+    dirty: bool,
+    /// Sheet shown and edited, and the one formulas outside of any cell refer to
+    active: SheetId,
+    /// Cells currently being evaluated, innermost last
+    eval_stack: Vec<CellPos>,
+    /// Cells found to be part of a cycle, that are still being evaluated
+    in_cycle: HashSet<CellPos>,
+    /// Last evaluated value of each cell, until it or its precedents change
+    values: HashMap<CellPos, Value>,
+    /// Parsed text of each cell evaluated so far, until the text changes
+    formulas: HashMap<CellPos, Rc<dyn Formula>>,
+    /// Cells of each sheet with formulas that spill, recorded as the formulas are parsed
+    anchors: HashMap<SheetId, HashSet<CellIdx>>,
+    /// Sheets with all formulas parsed once, so all their anchors are known, and kept as they change
+    scanned: HashSet<SheetId>,
+    /// Array values spilled by cells, which themselves show the top-left element
+    spilled: HashMap<CellPos, Vec<Vec<Value>>>,
+    dependencies: DependencyGraph,
 }
 
 
 impl SheetState {
     pub fn new() -> Self {
-        SheetState{selected: CellIdx{col: 0, row: 0}, view_offset: CellIdx{col: 0, row: 0}, text: "".to_string(), sheet: Sheet::new()}
+        SheetState::with_workbook(Workbook::new())
     }
 
-    pub fn get_value(&mut self, idx: &CellIdx) -> String
+    /// State showing the first sheet of `workbook`
+    pub fn with_workbook(workbook: Workbook) -> Self {
+        SheetState{
+            selected: CellIdx{col: 0, row: 0},
+            view_offset: CellIdx{col: 0, row: 0},
+            text: "".to_string(),
+            active: workbook.sheet_ids()[0],
+            workbook,
+            history: History::default(),
+            path: None,
+            dirty: false,
+            eval_stack: vec![],
+            in_cycle: HashSet::new(),
+            values: HashMap::new(),
+            formulas: HashMap::new(),
+            anchors: HashMap::new(),
+            scanned: HashSet::new(),
+            spilled: HashMap::new(),
+            dependencies: DependencyGraph::new(),
+        }
+    }
+
+    pub fn active(&self) -> SheetId {
+        self.active
+    }
+
+    /// Switch to another sheet, ignored when there is no such sheet
+    pub fn set_active(&mut self, id: SheetId) {
+        if self.workbook.sheet(id).is_some() {
+            self.active = id;
+        }
+    }
+
+    /// The active sheet
+    pub fn sheet(&self) -> &Sheet {
+        self.workbook.sheet(self.active).unwrap()
+    }
+
+    pub fn sheet_mut(&mut self) -> &mut Sheet {
+        self.workbook.sheet_mut(self.active).unwrap()
+    }
+
+    /// Sheet a formula refers to by `name`, the sheet of the cell being evaluated when unnamed
+    pub fn resolve_sheet(&self, name: Option<&str>) -> Result<SheetId, CellError> {
+        match name {
+            Some(name) => self.workbook.find(name)
+                .ok_or_else(|| CellError::new(ErrorKind::Ref, format!("No sheet named \"{}\"", name))),
+            None => Ok(self.eval_stack.last().map(|pos| pos.sheet).unwrap_or(self.active)),
+        }
+    }
+
+    /// Close the current transaction: edits since the previous commit are undone together
+    pub fn commit(&mut self) {
+        let edits = self.workbook.take_journal();
+        self.dirty |= !edits.is_empty();
+        self.history.record(edits);
+    }
+
+    /// Revert the last transaction, `false` when there is nothing to undo
+    pub fn undo(&mut self) -> bool {
+        self.commit();
+        let undone = self.history.undo(&mut self.workbook);
+        self.dirty |= undone;
+        self.check_active();
+        undone
+    }
+
+    /// Apply the last undone transaction again, `false` when there is nothing to redo
+    pub fn redo(&mut self) -> bool {
+        self.commit();
+        let redone = self.history.redo(&mut self.workbook);
+        self.dirty |= redone;
+        self.check_active();
+        redone
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.dirty = false;
+    }
+
+    /// Fall back to the first sheet when the active one is gone
+    fn check_active(&mut self) {
+        if self.workbook.sheet(self.active).is_none() {
+            self.active = self.workbook.sheet_ids()[0];
+        }
+    }
+
+    /// Drop the cached values of changed cells and everything depending on them
+    fn apply_changes(&mut self) {
+        self.check_active();
+        let changes = self.workbook.take_changes();
+        let restart = self.workbook.take_structure_change();
+        if restart {
+            // Sheet names resolve differently now, start over. Which cells spill stays the same.
+            self.values.clear();
+            self.formulas.clear();
+            self.spilled.clear();
+            self.dependencies = DependencyGraph::new();
+            let workbook = &self.workbook;
+            self.anchors.retain(|sheet, _| workbook.sheet(*sheet).is_some());
+            self.scanned.retain(|sheet| workbook.sheet(*sheet).is_some());
+        }
+        for pos in changes {
+            self.formulas.remove(&pos);
+            let was_anchor = self.anchors.get_mut(&pos.sheet).is_some_and(|anchors| anchors.remove(&pos.idx));
+            // Records the new formula as an anchor, sheets not scanned yet record it when they are
+            let is_anchor = self.scanned.contains(&pos.sheet) && self.spills(&pos);
+            if restart {
+                continue;
+            }
+            if was_anchor || is_anchor {
+                // Empty cells below and right of it may be covered now, or no longer
+                let covered = self.values.keys()
+                    .filter(|cell| cell.sheet == pos.sheet && cell.idx.col >= pos.idx.col && cell.idx.row >= pos.idx.row)
+                    .filter(|cell| self.is_blank(cell))
+                    .cloned().collect::<Vec<_>>();
+                for cell in covered {
+                    for affected in self.dependencies.affected(&cell) {
+                        self.values.remove(&affected);
+                    }
+                }
+            }
+            for affected in self.dependencies.affected(&pos) {
+                self.values.remove(&affected);
+            }
+        }
+    }
+
+    /// Record that the cell being evaluated reads `pos`
+    fn record_cell(&mut self, pos: &CellPos) {
+        if let Some(reader) = self.eval_stack.last() {
+            self.dependencies.add_cell(reader, pos);
+        }
+    }
+
+    /// Value of a cell on the active sheet
+    pub fn get_value(&mut self, idx: &CellIdx) -> Value
+    {
+        let pos = CellPos{sheet: self.active, idx: idx.clone()};
+        self.get_value_at(&pos)
+    }
+
+    pub fn get_value_at(&mut self, pos: &CellPos) -> Value
+    {
+        if self.eval_stack.is_empty() {
+            self.apply_changes();
+        }
+        self.record_cell(pos);
+        self.get_cached(pos)
+    }
+
+    /// Exception that made a cell on the active sheet, or a cell it reads, fail, like a Python traceback
+    pub fn exception(&mut self, idx: &CellIdx) -> Option<Exception> {
+        match self.get_value(idx) {
+            Value::Error(CellError{exception: Some(exception), ..}) => Some(*exception),
+            _ => None,
+        }
+    }
+
+    fn evaluate(&mut self, pos: &CellPos) -> Value
     {
-        let (text, engine) = match self.sheet.get(idx) {
+        let (text, engine) = match self.workbook.sheet(pos.sheet).and_then(|sheet| sheet.get(&pos.idx)) {
                 Some(cell) => {
                     let text = cell.value.trim();
-                    if text.is_empty() { return "".to_string(); }
+                    if text.is_empty() { return self.spilled(pos); }
 
                     ( text.to_string(), cell.engine )
 
                 },
-                None => { return "".to_string(); }
+                None => { return self.spilled(pos); }
+        };
+
+        if let Some(start) = self.eval_stack.iter().position(|p| p == pos) {
+            // Everything from the first evaluation of this cell and up is in the cycle
+            self.in_cycle.extend(self.eval_stack[start..].iter().cloned());
+            return Value::circular();
+        }
+
+        self.dependencies.clear(pos);
+        self.eval_stack.push(pos.clone());
+        let (semi_final, spills) = match self.formula(pos, engine, &text) {
+            Ok(formula) => {
+                for dependency in formula.dependencies() {
+                    if let Ok(sheet) = self.resolve_sheet(dependency.sheet.as_deref()) {
+                        self.dependencies.add_area(pos, sheet, &dependency.area);
+                    }
+                }
+                (formula.evaluate(self), formula.spills())
+            },
+            Err(err) => (Value::Error(err), false),
+        };
+        self.eval_stack.pop();
+
+        if self.in_cycle.remove(pos) {
+            return Value::circular();
+        }
+        if spills {
+            return self.spill(pos, semi_final);
+        }
+
+        match semi_final {
+            Value::Text(text) => {
+                let splt = text.split('\r').collect::<Vec<&str>>();
+                if splt.len() > 1 {
+                    Value::Text(splt[0].to_string())
+                } else {
+                    Value::Text(text)
+                }
+            },
+            value => value,
+        }
+    }
+
+    /// Formula of the text of a cell, parsed by its engine on first use
+    fn formula(&mut self, pos: &CellPos, engine: EngineId, text: &str) -> Result<Rc<dyn Formula>, CellError> {
+        if let Some(formula) = self.formulas.get(pos) {
+            return Ok(formula.clone());
+        }
+        let engine = engine::get(engine)
+            .ok_or_else(|| CellError::new(ErrorKind::Name, format!("No engine named \"{}\"", engine)))?;
+        let formula: Rc<dyn Formula> = engine.parse(text)?.into();
+        self.formulas.insert(pos.clone(), formula.clone());
+        if formula.spills() {
+            self.anchors.entry(pos.sheet).or_default().insert(pos.idx.clone());
+        }
+        Ok(formula)
+    }
+
+    fn is_blank(&self, pos: &CellPos) -> bool {
+        match self.workbook.sheet(pos.sheet).and_then(|sheet| sheet.get(&pos.idx)) {
+            Some(cell) => cell.value.trim().is_empty(),
+            None => true,
+        }
+    }
+
+    /// Whether the formula of a cell spills its array values
+    fn spills(&mut self, pos: &CellPos) -> bool {
+        if self.is_blank(pos) {
+            return false;
+        }
+        let cell = self.workbook.sheet(pos.sheet).and_then(|sheet| sheet.get(&pos.idx)).unwrap();
+        let (text, engine) = (cell.value.trim().to_string(), cell.engine);
+        self.formula(pos, engine, &text).is_ok_and(|formula| formula.spills())
+    }
+
+    /// Cells of `sheet` with formulas that spill, parsing the formulas not parsed yet the first time
+    fn anchors(&mut self, sheet: SheetId) -> Vec<CellIdx> {
+        if self.scanned.insert(sheet) {
+            let unparsed = match self.workbook.sheet(sheet) {
+                Some(cells) => cells.cells()
+                    .map(|(idx, _)| CellPos{sheet, idx: idx.clone()})
+                    .filter(|pos| !self.formulas.contains_key(pos))
+                    .collect::<Vec<_>>(),
+                None => vec![],
+            };
+            for pos in unparsed {
+                self.spills(&pos);
+            }
+        }
+        let mut anchors = self.anchors.get(&sheet).map(|anchors| anchors.iter().cloned().collect::<Vec<_>>()).unwrap_or_default();
+        anchors.sort_by_key(|idx| (idx.row, idx.col));
+        anchors
+    }
+
+    /// Value of the empty cell at `pos`: its element of an array spilled over it, if any
+    fn spilled(&mut self, pos: &CellPos) -> Value {
+        self.dependencies.clear(pos);
+        for idx in self.anchors(pos.sheet) {
+            if idx.col > pos.idx.col || idx.row > pos.idx.row {
+                continue;
+            }
+            let anchor = CellPos{sheet: pos.sheet, idx};
+            // Being evaluated and reading this cell, which then sees it unspilled
+            if self.eval_stack.contains(&anchor) {
+                continue;
+            }
+            self.dependencies.add_cell(pos, &anchor);
+            self.get_cached(&anchor);
+            let row = self.spilled.get(&anchor).and_then(|rows| rows.get((pos.idx.row - anchor.idx.row) as usize));
+            if let Some(value) = row.and_then(|row| row.get((pos.idx.col - anchor.idx.col) as usize)) {
+                return value.clone();
+            }
+        }
+        Value::Empty
+    }
+
+    /// Empty cells of `sheet` showing an element of an array spilled over them, by row and column
+    pub fn spilled_cells(&mut self, sheet: SheetId) -> Vec<CellIdx> {
+        if self.eval_stack.is_empty() {
+            self.apply_changes();
+        }
+        let mut cells = HashSet::new();
+        for idx in self.anchors(sheet) {
+            let anchor = CellPos{sheet, idx: idx.clone()};
+            self.get_cached(&anchor);
+            let rows = match self.spilled.get(&anchor) {
+                Some(rows) => rows,
+                None => continue,
+            };
+            for (row, values) in rows.iter().enumerate() {
+                for col in 0..values.len() {
+                    let covered = CellIdx{col: idx.col + col as u32, row: idx.row + row as u32};
+                    if covered != idx {
+                        cells.insert(covered);
+                    }
+                }
+            }
+        }
+        let mut cells = cells.into_iter()
+            .filter(|idx| self.get_cached(&CellPos{sheet, idx: idx.clone()}) != Value::Empty)
+            .collect::<Vec<_>>();
+        cells.sort_by_key(|idx| (idx.row, idx.col));
+        cells
+    }
+
+    /// Spread the array value of a spilling formula at `pos` over the cells it covers, leaving the
+    /// top-left element for the cell itself. A #SPILL! error when the covered cells are not empty.
+    fn spill(&mut self, pos: &CellPos, value: Value) -> Value {
+        self.spilled.remove(pos);
+        let (rows, cols) = match &value {
+            Value::Array(rows) => (rows.len() as u32, rows.iter().map(Vec::len).max().unwrap_or(0) as u32),
+            _ => return value,
+        };
+        if rows == 0 || cols == 0 {
+            return Value::Empty;
+        }
+        let CellIdx{col, row} = pos.idx;
+        let area = Area{
+            cols: Some((col, col.saturating_add(cols - 1))),
+            rows: Some((row, row.saturating_add(rows - 1))),
         };
+        // Typing into the covered cells blocks the spill, clearing them unblocks it
+        self.dependencies.add_area(pos, pos.sheet, &area);
+
+        let sheet = self.workbook.sheet(pos.sheet).unwrap();
+        let blocking = sheet.cells()
+            .filter(|(idx, cell)| **idx != pos.idx && area.contains(idx) && !cell.value.trim().is_empty())
+            .map(|(idx, _)| (idx.row, idx.col))
+            .min();
+        match blocking {
+            Some((row, col)) => Value::error(ErrorKind::Spill, format!("{}{} is in the way of the result", col_to_str(col), row + 1)),
+            None => match value {
+                Value::Array(rows) => {
+                    // Covered cells read by the formula itself were not spilled over yet
+                    let read = self.values.keys()
+                        .filter(|cell| cell.sheet == pos.sheet && cell != &pos && area.contains(&cell.idx))
+                        .cloned().collect::<Vec<_>>();
+                    for cell in read {
+                        for affected in self.dependencies.affected(&cell) {
+                            self.values.remove(&affected);
+                        }
+                    }
+                    let first = rows[0].first().cloned().unwrap_or(Value::Empty);
+                    self.spilled.insert(pos.clone(), rows);
+                    first
+                },
+                value => value,
+            },
+        }
+    }
+
+    /// Values of `area` on `sheet` as rows, bounded by the used part of the sheet: the cells beyond
+    /// it are all empty
+    pub fn get_area(&mut self, sheet: SheetId, area: &Area) -> Vec<Vec<Value>>
+    {
+        if let Some(reader) = self.eval_stack.last() {
+            self.dependencies.add_area(reader, sheet, area);
+        }
 
-        let semi_final = match engine {
-            EngineType::Simple => { engine_simple::calc(self, text.as_str()) },
-            #[cfg(feature = "python")]
-            EngineType::Python => { engine_python::calc(self, text.as_str()) }
+        let mut extent = match self.workbook.sheet(sheet) {
+            Some(sheet) => sheet.extent(),
+            None => return vec![],
         };
+        // Arrays the area's own cells spill are used too, those cells are read below anyway
+        for idx in self.anchors(sheet).into_iter().filter(|idx| area.contains(idx)) {
+            let anchor = CellPos{sheet, idx};
+            if self.eval_stack.contains(&anchor) {
+                continue;
+            }
+            self.get_cached(&anchor);
+            if let Some(rows) = self.spilled.get(&anchor) {
+                extent.row = extent.row.max(anchor.idx.row + rows.len() as u32);
+                extent.col = extent.col.max(anchor.idx.col + rows.iter().map(Vec::len).max().unwrap_or(0) as u32);
+            }
+        }
+        let (start_col, end_col) = area.cols.unwrap_or((0, u32::MAX));
+        let (start_row, end_row) = area.rows.unwrap_or((0, u32::MAX));
+        if start_col >= extent.col || start_row >= extent.row {
+            return vec![];
+        }
+        let (end_col, end_row) = (end_col.min(extent.col - 1), end_row.min(extent.row - 1));
 
-        let splt = semi_final.split('\r').collect::<Vec<&str>>();
-        if splt.len() > 1 {
-            splt[0].to_string()
-        } else {
-            semi_final
+        (start_row..=end_row).map(|row| {
+            (start_col..=end_col).map(|col| self.get_cached(&CellPos{sheet, idx: CellIdx{col, row}})).collect()
+        }).collect()
+    }
+
+    /// Like `get_value_at`, without recording a dependency - for cells read as part of an area
+    fn get_cached(&mut self, pos: &CellPos) -> Value
+    {
+        if let Some(value) = self.values.get(pos) {
+            return value.clone();
         }
+
+        let value = self.evaluate(pos);
+        self.values.insert(pos.clone(), value.clone());
+        value
+    }
+}
+
+impl Default for SheetState {
+    fn default() -> Self {
+        SheetState::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::ErrorKind;
 
     #[test]
     fn simple_engine_plain() {
@@ -54,43 +471,71 @@ mod tests {
 
         let idx = state.selected.clone();
 
-        assert_eq!(state.get_value(&idx), "".to_string());
+        assert_eq!(state.get_value(&idx), Value::Empty);
 
-        state.sheet.set_text(idx.clone(), "test".to_string());
-        assert_eq!(state.get_value(&idx), "test".to_string());
+        state.sheet_mut().set_text(idx.clone(), "test".to_string());
+        assert_eq!(state.get_value(&idx), Value::from("test"));
     }
 
-    // TODO: Fix this test...
-    // #[test]
-    // fn simple_engine_self_reference() {
-    //     let mut state = SheetState::new();
-    //     let mut idx = state.selected.clone();
+    #[test]
+    fn simple_engine_self_reference() {
+        let mut state = SheetState::new();
+        let idx = state.selected.clone();
+
+        assert_eq!(state.get_value(&idx), Value::Empty);
 
-    //     assert_eq!(state.get_value(&idx), "".to_string());
+        state.sheet_mut().set_text(idx.clone(), "=A1".to_string());
+        assert_eq!(state.get_value(&idx), Value::circular());
+
+        state.sheet_mut().set_text(idx.clone(), "=SUM(A1:B2)".to_string());
+        assert_eq!(state.get_value(&idx), Value::circular());
+    }
 
-    //     state.sheet.set_text(idx.clone(), "=A1".to_string());
-    //     assert_eq!(state.get_value(&idx), "test".to_string());
-    // }
+    #[test]
+    fn simple_engine_cycle() {
+        let mut state = SheetState::new();
+        let a1 = CellIdx{col: 0, row: 0};
+        let b1 = CellIdx{col: 1, row: 0};
+        let c1 = CellIdx{col: 2, row: 0};
+
+        state.sheet_mut().set_text(a1.clone(), "=B1+1".to_string());
+        state.sheet_mut().set_text(b1.clone(), "=IF(TRUE, A1)".to_string());
+        state.sheet_mut().set_text(c1.clone(), "=A1".to_string());
+
+        // Every cell in the cycle is marked, no matter where evaluation started
+        assert_eq!(state.get_value(&c1), Value::circular());
+        assert_eq!(state.get_value(&a1), Value::circular());
+        assert_eq!(state.get_value(&b1), Value::circular());
+
+        // Breaking the cycle recovers all cells
+        state.sheet_mut().set_text(b1.clone(), "1".to_string());
+        assert_eq!(state.get_value(&a1), Value::Number(2.0));
+        assert_eq!(state.get_value(&b1), Value::Number(1.0));
+        assert_eq!(state.get_value(&c1), Value::Number(2.0));
+    }
 
     #[test]
     fn simple_engine_single_reference() {
         let mut state = SheetState::new();
         let mut idx = state.selected.clone();
 
-        assert_eq!(state.get_value(&idx), "".to_string());
+        assert_eq!(state.get_value(&idx), Value::Empty);
 
-        state.sheet.set_text(state.selected.clone(), "test".to_string());
-        assert_eq!(state.get_value(&idx), "test".to_string());
+        state.sheet_mut().set_text(idx.clone(), "test".to_string());
+        assert_eq!(state.get_value(&idx), Value::from("test"));
 
         idx.col = 1;
-        state.sheet.set_text(idx.clone(), "=A1".to_string());
-        assert_eq!(state.get_value(&idx), "test".to_string());
+        state.sheet_mut().set_text(idx.clone(), "=A1".to_string());
+        assert_eq!(state.get_value(&idx), Value::from("test"));
 
 
         let very_large_idx = CellIdx{col: 53, row: 999};
-        state.sheet.set_text(very_large_idx, "another test".to_string());
-        state.sheet.set_text(idx.clone(), "=BB1000".to_string());
-        assert_eq!(state.get_value(&idx), "another test".to_string());
+        state.sheet_mut().set_text(very_large_idx, "another test".to_string());
+        state.sheet_mut().set_text(idx.clone(), "=BB1000".to_string());
+        assert_eq!(state.get_value(&idx), Value::from("another test"));
+
+        state.sheet_mut().set_text(idx.clone(), "=$BB$1000&BB$1000&$BB1000".to_string());
+        assert_eq!(state.get_value(&idx), Value::from("another testanother testanother test"));
     }
 
     #[test]
@@ -99,63 +544,326 @@ mod tests {
         let mut idx = state.selected.clone();
 
 
-        assert_eq!(state.get_value(&idx), "".to_string());
+        assert_eq!(state.get_value(&idx), Value::Empty);
 
-        state.sheet.set_text(idx.clone(), "test".to_string());
-        assert_eq!(state.get_value(&idx), "test".to_string());
+        state.sheet_mut().set_text(idx.clone(), "test".to_string());
+        assert_eq!(state.get_value(&idx), Value::from("test"));
 
         idx.col = 1;
-        state.sheet.set_text(idx.clone(), "=A1".to_string());
-        assert_eq!(state.get_value(&idx), "test".to_string());
+        state.sheet_mut().set_text(idx.clone(), "=A1".to_string());
+        assert_eq!(state.get_value(&idx), Value::from("test"));
 
 
         idx.col = 2;
-        state.sheet.set_text(idx.clone(), "=B1".to_string());
-        assert_eq!(state.get_value(&idx), "test".to_string());
+        state.sheet_mut().set_text(idx.clone(), "=B1".to_string());
+        assert_eq!(state.get_value(&idx), Value::from("test"));
+    }
+
+    #[test]
+    fn simple_engine_arithmetic() {
+        let mut state = SheetState::new();
+        let idx = state.selected.clone();
+
+        state.sheet_mut().set_text(idx.clone(), "=1+2*3".to_string());
+        assert_eq!(state.get_value(&idx), Value::Number(7.0));
+
+        state.sheet_mut().set_text(idx.clone(), "=(1+2)*3".to_string());
+        assert_eq!(state.get_value(&idx), Value::Number(9.0));
+
+        state.sheet_mut().set_text(idx.clone(), "=10-4-3".to_string());
+        assert_eq!(state.get_value(&idx), Value::Number(3.0));
+
+        state.sheet_mut().set_text(idx.clone(), "=2^3^2".to_string());
+        assert_eq!(state.get_value(&idx), Value::Number(64.0));
+
+        state.sheet_mut().set_text(idx.clone(), "=-2^2".to_string());
+        assert_eq!(state.get_value(&idx), Value::Number(4.0));
+
+        state.sheet_mut().set_text(idx.clone(), "= 7 / 2 - -.5".to_string());
+        assert_eq!(state.get_value(&idx), Value::Number(4.0));
+
+        state.sheet_mut().set_text(idx.clone(), "=1/0".to_string());
+        assert_eq!(state.get_value(&idx).error_kind(), Some(ErrorKind::DivZero));
+
+        state.sheet_mut().set_text(idx.clone(), "=1+".to_string());
+        assert_eq!(state.get_value(&idx).error_kind(), Some(ErrorKind::Parse));
+    }
+
+    #[test]
+    fn simple_engine_arithmetic_references() {
+        let mut state = SheetState::new();
+
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 0}, "4".to_string());
+        state.sheet_mut().set_text(CellIdx{col: 1, row: 0}, "2.5".to_string());
+        state.sheet_mut().set_text(CellIdx{col: 2, row: 0}, "text".to_string());
+
+        let idx = CellIdx{col: 0, row: 1};
+        state.sheet_mut().set_text(idx.clone(), "=A1+B1*2".to_string());
+        assert_eq!(state.get_value(&idx), Value::Number(9.0));
+
+        // Empty cells count as zero
+        state.sheet_mut().set_text(idx.clone(), "=A1*Z99+1".to_string());
+        assert_eq!(state.get_value(&idx), Value::Number(1.0));
+
+        state.sheet_mut().set_text(idx.clone(), "=A1+C1".to_string());
+        assert_eq!(state.get_value(&idx).error_kind(), Some(ErrorKind::Value));
+    }
+
+    #[test]
+    fn simple_engine_errors() {
+        let mut state = SheetState::new();
+        let a1 = CellIdx{col: 0, row: 0};
+        let a2 = CellIdx{col: 0, row: 1};
+
+        state.sheet_mut().set_text(a1.clone(), "=1/0".to_string());
+        state.sheet_mut().set_text(a2.clone(), "=SUM(A1, 1) * 2".to_string());
+        assert_eq!(state.get_value(&a2), Value::error(ErrorKind::DivZero, "Division by zero"));
+
+        state.sheet_mut().set_text(a1.clone(), "=B0".to_string());
+        assert_eq!(state.get_value(&a2), Value::error(ErrorKind::Ref, "Row 0 does not exist"));
+
+        state.sheet_mut().set_text(a1.clone(), "=FOO(1)".to_string());
+        assert_eq!(state.get_value(&a2), Value::error(ErrorKind::Name, "Unknown function FOO"));
+
+        state.sheet_mut().set_text(a1.clone(), "=1+*2".to_string());
+        assert_eq!(state.get_value(&a2), Value::error(ErrorKind::Parse, "Syntax error at position 4"));
+
+        state.sheet_mut().set_text(a1.clone(), "#N/A".to_string());
+        assert_eq!(state.get_value(&a2).error_kind(), Some(ErrorKind::NA));
+        assert_eq!(state.get_value(&a2).to_string(), "#N/A");
+    }
+
+    #[test]
+    fn incremental_recalculation() {
+        let mut state = SheetState::new();
+        let a1 = CellIdx{col: 0, row: 0};
+        let b1 = CellIdx{col: 1, row: 0};
+        let c1 = CellIdx{col: 2, row: 0};
+        let d1 = CellIdx{col: 3, row: 0};
+        let e1 = CellIdx{col: 4, row: 0};
+        let cached = |state: &SheetState, idx: &CellIdx| {
+            state.values.contains_key(&CellPos{sheet: state.active(), idx: idx.clone()})
+        };
+
+        state.sheet_mut().set_text(a1.clone(), "1".to_string());
+        state.sheet_mut().set_text(b1.clone(), "=A1*2".to_string());
+        state.sheet_mut().set_text(c1.clone(), "=SUM(A:A)".to_string());
+        state.sheet_mut().set_text(d1.clone(), "=B1+C1".to_string());
+        state.sheet_mut().set_text(e1.clone(), "=5".to_string());
+
+        assert_eq!(state.get_value(&d1), Value::Number(3.0));
+        assert_eq!(state.get_value(&e1), Value::Number(5.0));
+        assert!(cached(&state, &b1));
+
+        // Only the changed cell and its dependents are recalculated
+        state.sheet_mut().set_text(a1.clone(), "2".to_string());
+        assert_eq!(state.get_value(&e1), Value::Number(5.0));
+        for idx in [&a1, &b1, &c1, &d1] {
+            assert!(!cached(&state, idx));
+        }
+        assert_eq!(state.get_value(&d1), Value::Number(6.0));
+
+        // New cells inside a referenced range are picked up
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 5}, "10".to_string());
+        assert_eq!(state.get_value(&d1), Value::Number(16.0));
+
+        // Formulas that stop referencing a cell are no longer affected by it
+        state.sheet_mut().set_text(b1.clone(), "=7".to_string());
+        assert_eq!(state.get_value(&d1), Value::Number(19.0));
+        state.sheet_mut().set_text(a1.clone(), "3".to_string());
+        state.get_value(&d1);
+        assert!(cached(&state, &b1));
+    }
+
+    #[test]
+    fn simple_engine_sheets() {
+        let mut state = SheetState::new();
+        let first = state.active();
+        let data = state.workbook.add_sheet("Q1 Data").unwrap();
+        let a1 = CellIdx{col: 0, row: 0};
+        let b1 = CellIdx{col: 1, row: 0};
+
+        state.sheet_mut().set_text(a1.clone(), "=SUM('Q1 Data'!A1:A10)+'q1 data'!B4".to_string());
+        state.sheet_mut().set_text(b1.clone(), "=Nope!A1".to_string());
+        state.set_active(data);
+        for row in 0..4 {
+            state.sheet_mut().set_text(CellIdx{col: 0, row}, (row + 1).to_string());
+        }
+        state.sheet_mut().set_text(CellIdx{col: 1, row: 3}, "=A1*100".to_string());
+        state.set_active(first);
+        assert_eq!(state.get_value(&a1), Value::Number(110.0));
+        assert_eq!(state.get_value(&b1).error_kind(), Some(ErrorKind::Ref));
+
+        // Changes on the other sheet reach this one
+        state.workbook.sheet_mut(data).unwrap().set_text(a1.clone(), "2".to_string());
+        assert_eq!(state.get_value(&a1), Value::Number(211.0));
+
+        // Unqualified references stay on the sheet of the formula
+        state.workbook.sheet_mut(data).unwrap().set_text(b1.clone(), "=A1".to_string());
+        assert_eq!(state.get_value_at(&CellPos{sheet: data, idx: b1.clone()}), Value::Number(2.0));
+
+        state.workbook.rename_sheet(data, "Nope").unwrap();
+        assert_eq!(state.get_value(&a1), Value::Number(211.0));
+        assert_eq!(state.get_value(&b1), Value::Number(2.0));
+
+        state.workbook.delete_sheet(data).unwrap();
+        assert_eq!(state.get_value(&a1).error_kind(), Some(ErrorKind::Ref));
     }
 
+    #[test]
+    fn undo_redo() {
+        let mut state = SheetState::new();
+        let a1 = CellIdx{col: 0, row: 0};
+        let b1 = CellIdx{col: 1, row: 0};
+
+        state.sheet_mut().set_text(a1.clone(), "1".to_string());
+        state.sheet_mut().set_text(b1.clone(), "=A1*2".to_string());
+        state.commit();
+        state.sheet_mut().set_text(a1.clone(), "5".to_string());
+        assert_eq!(state.get_value(&b1), Value::Number(10.0));
+
+        // Uncommitted edits are undone first, and cached values follow
+        assert!(state.undo());
+        assert_eq!(state.get_value(&b1), Value::Number(2.0));
+        assert!(state.redo());
+        assert_eq!(state.get_value(&b1), Value::Number(10.0));
+
+        let first = state.active();
+        let other = state.workbook.add_sheet("Other").unwrap();
+        state.set_active(other);
+        state.commit();
+        assert!(state.undo());
+        assert_eq!(state.active(), first);
+        assert_eq!(state.get_value(&b1), Value::Number(10.0));
+        assert!(state.undo());
+        assert!(state.undo());
+        assert!(!state.undo());
+        assert_eq!(state.get_value(&b1), Value::Empty);
+    }
+
+    #[test]
+    fn simple_engine_ranges() {
+        let mut state = SheetState::new();
+
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 0}, "1".to_string());
+        state.sheet_mut().set_text(CellIdx{col: 1, row: 0}, "a".to_string());
+        state.sheet_mut().set_text(CellIdx{col: 0, row: 1}, "=A1*2".to_string());
+
+        let idx = CellIdx{col: 3, row: 3};
+        state.sheet_mut().set_text(idx.clone(), "=A1:B2".to_string());
+        assert_eq!(state.get_value(&idx), Value::Array(vec![
+            vec![Value::Number(1.0), Value::from("a")],
+            vec![Value::Number(2.0), Value::Empty],
+        ]));
+
+        // Reversed corners describe the same rectangle
+        state.sheet_mut().set_text(idx.clone(), "=B2:A1".to_string());
+        assert_eq!(state.get_value(&idx), Value::Array(vec![
+            vec![Value::Number(1.0), Value::from("a")],
+            vec![Value::Number(2.0), Value::Empty],
+        ]));
+
+        state.sheet_mut().set_text(idx, "".to_string());
+
+        // Whole columns and rows are bounded by the used part of the sheet
+        let idx = CellIdx{col: 2, row: 0};
+        state.sheet_mut().set_text(idx.clone(), "=A:A".to_string());
+        assert_eq!(state.get_value(&idx), Value::Array(vec![
+            vec![Value::Number(1.0)],
+            vec![Value::Number(2.0)],
+        ]));
+        state.sheet_mut().set_text(idx, "".to_string());
+
+        let idx = CellIdx{col: 0, row: 2};
+        state.sheet_mut().set_text(idx.clone(), "=1:1".to_string());
+        assert_eq!(state.get_value(&idx), Value::Array(vec![
+            vec![Value::Number(1.0), Value::from("a")],
+        ]));
+
+        // Past the last row and column of a sheet
+        for text in ["=A0:B2", "=SUM(1:4000000000)", "=A1048577", "=XFE1", "=SUM(A:XFE)", "=AAAAAAAAA1"] {
+            state.sheet_mut().set_text(idx.clone(), text.to_string());
+            assert_eq!(state.get_value(&idx).error_kind(), Some(ErrorKind::Ref), "{}", text);
+        }
+        state.sheet_mut().set_text(idx, "".to_string());
+
+        // So are ranges past it, however far they reach
+        state.sheet_mut().set_text(CellIdx{col: 2, row: 0}, "=SUM(A1:A1048576)".to_string());
+        assert_eq!(state.get_value(&CellIdx{col: 2, row: 0}), Value::Number(3.0));
+        state.sheet_mut().set_text(CellIdx{col: 2, row: 0}, "".to_string());
+        let other = state.workbook.add_sheet("Other").unwrap();
+        state.workbook.sheet_mut(other).unwrap().set_text(CellIdx{col: 0, row: 0}, "=SUM(Sheet1!A1:XFD1048576)".to_string());
+        assert_eq!(state.get_value_at(&CellPos{sheet: other, idx: CellIdx{col: 0, row: 0}}), Value::Number(3.0));
+    }
+
+    #[cfg(feature = "python")]
     #[test]
     fn python_plain() {
         let mut state = SheetState::new();
         let mut idx = state.selected.clone();
 
-        assert_eq!(state.get_value(&idx), "".to_string());
+        assert_eq!(state.get_value(&idx), Value::Empty);
+
+        let cell = Cell{engine: EngineId::PYTHON, value: "'test'".to_string()};
+
+        state.sheet_mut().insert(idx.clone(), cell);
+        assert_eq!(state.get_value(&idx), Value::from("test"));
+
+        let cell = Cell{engine: EngineId::PYTHON, value: "6".to_string()};
 
-        let cell = Cell{engine: EngineType::Python, value: "'test'".to_string()};
+        state.sheet_mut().insert(idx.clone(), cell);
+        assert_eq!(state.get_value(&idx), Value::Number(6.0));
 
-        state.sheet.insert(idx.clone(), cell);
-        assert_eq!(state.get_value(&idx), "test".to_string());
+        let cell = Cell{engine: EngineId::PYTHON, value: "5.2".to_string()};
 
-        let cell = Cell{engine: EngineType::Python, value: "6".to_string()};
+        state.sheet_mut().insert(idx.clone(), cell);
+        assert_eq!(state.get_value(&idx), Value::Number(5.2));
 
-        state.sheet.insert(idx.clone(), cell);
-        assert_eq!(state.get_value(&idx), "6".to_string());
+    }
 
-        let cell = Cell{engine: EngineType::Python, value: "5.2".to_string()};
+    #[cfg(feature = "python")]
+    #[test]
+    fn python_error() {
+        let mut state = SheetState::new();
+        let idx = state.selected.clone();
 
-        state.sheet.insert(idx.clone(), cell);
-        assert_eq!(state.get_value(&idx), "5.2".to_string());
+        let cell = Cell{engine: EngineId::PYTHON, value: "1/0".to_string()};
+        state.sheet_mut().insert(idx.clone(), cell);
 
+        match state.get_value(&idx) {
+            Value::Error(err) => {
+                assert_eq!(err.kind, ErrorKind::Python);
+                assert!(err.message.contains("ZeroDivisionError"));
+            },
+            value => panic!("Expected an error, got {:?}", value),
+        }
     }
 
+    #[cfg(feature = "python")]
     #[test]
     fn python_reference()
     {
         let mut state = SheetState::new();
         let mut idx = state.selected.clone();
 
-        assert_eq!(state.get_value(&idx), "".to_string());
+        assert_eq!(state.get_value(&idx), Value::Empty);
 
-        let cell = Cell{engine: EngineType::Python, value: "5.2".to_string()};
+        let cell = Cell{engine: EngineId::PYTHON, value: "5.2".to_string()};
 
-        state.sheet.insert(idx.clone(), cell);
-        assert_eq!(state.get_value(&idx), "5.2".to_string());
+        state.sheet_mut().insert(idx.clone(), cell);
+        assert_eq!(state.get_value(&idx), Value::Number(5.2));
 
-        let cell = Cell{engine: EngineType::Python, value: "cell(sheet, 'A1')".to_string()};
+        let cell = Cell{engine: EngineId::PYTHON, value: "cell(sheet, 'A1')".to_string()};
 
         idx.col = 1;
-        state.sheet.insert(idx.clone(), cell);
-        assert_eq!(state.get_value(&idx), "5.2".to_string());
+        state.sheet_mut().insert(idx.clone(), cell);
+        assert_eq!(state.get_value(&idx), Value::from("5.2"));
+
+        let data = state.workbook.add_sheet("Q1 Data").unwrap();
+        state.workbook.sheet_mut(data).unwrap().set_text(CellIdx{col: 1, row: 3}, "7".to_string());
+        let cell = Cell{engine: EngineId::PYTHON, value: "cell(sheet, \"'Q1 Data'!B4\")".to_string()};
+        state.sheet_mut().insert(idx.clone(), cell);
+        assert_eq!(state.get_value(&idx), Value::from("7"));
     }
 
 }
@@ -7,7 +7,7 @@ use skia_safe::{
     FontMgr, Font,
 };
 
-use crate::{sheet_state::*, sheet::{CellIdx}};
+use rusty_sheet::{sheet_state::*, sheet::{CellIdx}, value::Value};
 
 const FONT_NAME: &'static str = "DejaVu Sans Mono";
 const CELL_SIZE: (usize, usize) = (80, 20);
@@ -184,13 +184,19 @@ fn render_grid(canvas: &mut skia_safe::canvas::Canvas, size: &ISize, state: &mut
                     // Idx for value
                     let idx = CellIdx{col: i, row: j} + state.view_offset.clone();
 
-                    let text = state.get_value(&idx);
-                    if !text.is_empty() {
+                    let value = state.get_value(&idx);
+                    if !value.is_empty() {
+                        let text = value.to_string();
                         let str = text.as_str();
                         let (_, bounds) = font.measure_str(str, None);
 
                         if rect.width() > bounds.width() {
-                            canvas.draw_str(str, (rect.left() + (rect.width() - bounds.width())/2.0, rect.top() + (rect.height() + bounds.height())/2.0), &font, &text_paint);
+                            let x = match value {
+                                Value::Number(_) => rect.right() - bounds.width() - 2.0,
+                                Value::Text(_) => rect.left() + 2.0,
+                                _ => rect.left() + (rect.width() - bounds.width())/2.0,
+                            };
+                            canvas.draw_str(str, (x, rect.top() + (rect.height() + bounds.height())/2.0), &font, &text_paint);
                         } else {
                             canvas.save();
                             canvas.clip_rect(rect, None, None);
@@ -207,7 +213,7 @@ fn render_grid(canvas: &mut skia_safe::canvas::Canvas, size: &ISize, state: &mut
     }
 }
 
-fn render_input(canvas: &mut skia_safe::canvas::Canvas, size: &ISize, state: &SheetState) {
+fn render_input(canvas: &mut skia_safe::canvas::Canvas, size: &ISize, state: &SheetState, selected_value: &Value) {
     {
         let mut paint = Paint::default();
         paint.set_stroke_width(2.0);
@@ -238,6 +244,30 @@ fn render_input(canvas: &mut skia_safe::canvas::Canvas, size: &ISize, state: &Sh
             canvas.draw_str(txt, (8.0, bounds.height() + offset) , &font, &text_paint);
             offset += bounds.height() + 4.0;
         }
+
+        // Why the selected cell failed, above it the traceback of an exception
+        if let Value::Error(err) = selected_value {
+            let mut error_paint = Paint::default();
+            error_paint.set_color(0xff_d93025);
+
+            let txt = format!("{}: {}", err.kind.code(), err.message);
+            let (_, bounds) = font.measure_str(txt.as_str(), None);
+            let mut bottom = size.height as f32 - bounds.height();
+            canvas.draw_str(txt.as_str(), (8.0, bottom), &font, &error_paint);
+
+            if let Some(exception) = &err.exception {
+                let mut small = font.clone();
+                small.set_size(13.0);
+                canvas.save();
+                canvas.clip_rect(Rect::new(0.0, offset, size.width as f32, bottom - bounds.height()), None, None);
+                for line in exception.traceback.lines().rev() {
+                    let (_, bounds) = small.measure_str(line, None);
+                    bottom -= bounds.height().max(small.size()) + 4.0;
+                    canvas.draw_str(line, (8.0, bottom), &small, &error_paint);
+                }
+                canvas.restore();
+            }
+        }
     }
 }
 
@@ -249,7 +279,9 @@ pub fn render(canvas: &mut skia_safe::canvas::Canvas, state: &mut SheetState) {
 
     canvas.reset_matrix();
     //canvas.clip_rect(Rect::from_isize(input_size), None, None);
-    render_input(canvas, &input_size, state);
+    let selected = state.selected.clone();
+    let selected_value = state.get_value(&selected);
+    render_input(canvas, &input_size, state, &selected_value);
 
     //canvas.reset_matrix();
     //canvas.clip_rect(Rect::new(0.0, input_size.height as f32, full_size.width as f32, full_size.height as f32), None, None);
@@ -0,0 +1,376 @@
+use std::time::Instant;
+
+use glutin::event::ModifiersState;
+use rusty_sheet::{sheet_state::SheetState, PythonPolicy, file, rsheet, xlsx, ods};
+
+const DEBOUNCE_MILLIS: u128 = 120;
+
+fn debounce<F>(mut func: F)  -> impl FnMut(&mut SheetState) where F: FnMut(&mut SheetState) {
+    let mut last = Box::new(Instant::now());
+    move |state| {
+        if last.elapsed().as_millis() > DEBOUNCE_MILLIS
+        {
+            func(state);
+            *last = Instant::now();
+        }
+    }
+}
+
+const AUTOSAVE_SECS: u64 = 30;
+
+/// Store the text being edited into the selected cell
+fn store_input(state: &mut SheetState) {
+    let (idx, text) = (state.selected.clone(), state.text.trim_end().to_string());
+    state.sheet_mut().set_text(idx, text);
+    state.commit();
+}
+
+fn window_title(state: &SheetState) -> String {
+    let name = match &state.path {
+        Some(path) => path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+        None => "Untitled".to_string(),
+    };
+    let untrusted = if state.workbook.python_policy().trusted { "" } else { " (untrusted)" };
+    format!("{}{}{} - Rusty Sheet", name, if state.is_dirty() { "*" } else { "" }, untrusted)
+}
+
+fn file_dialog() -> rfd::FileDialog {
+    rfd::FileDialog::new()
+        .add_filter("Rusty Sheet workbook", &[rsheet::EXTENSION])
+        .add_filter("Excel workbook", &[xlsx::EXTENSION])
+        .add_filter("OpenDocument spreadsheet", &[ods::EXTENSION])
+}
+
+/// Open a workbook of any format, reporting formulas that were imported as values
+fn open_path(path: &std::path::Path) -> Result<SheetState, file::FileError> {
+    let (state, info) = file::open(path)?;
+    for unsupported in info.unsupported {
+        eprintln!("{} is not supported, showing its last value", unsupported);
+    }
+    Ok(state)
+}
+
+/// Save to the current file, asking for one on `save_as` or when there is none yet.
+/// Picking an Excel or OpenDocument file exports to it, the workbook keeps its own file.
+fn save(state: &mut SheetState, save_as: bool) {
+    store_input(state);
+    let path = match (&state.path, save_as) {
+        (Some(path), false) => path.clone(),
+        _ => match file_dialog().save_file() {
+            Some(path) => match file::Format::from_path(&path) {
+                Some(file::Format::Native) | None => path.with_extension(rsheet::EXTENSION),
+                Some(_) => {
+                    if let Some(Err(err)) = file::write(state, &path) {
+                        eprintln!("Exporting {} failed: {}", path.display(), err);
+                    }
+                    return;
+                },
+            },
+            None => return,
+        },
+    };
+    if let Err(err) = rsheet::save_file(state, &path) {
+        eprintln!("Saving {} failed: {}", path.display(), err);
+    }
+}
+
+/// Ask for a workbook to open, confirming first when there are unsaved changes
+fn open(state: &mut SheetState) {
+    store_input(state);
+    if state.is_dirty() {
+        let discard = rfd::MessageDialog::new()
+            .set_title("Unsaved changes")
+            .set_description("Discard the changes to the current workbook?")
+            .set_buttons(rfd::MessageButtons::YesNo)
+            .show();
+        if !discard {
+            return;
+        }
+    }
+    if let Some(path) = file_dialog().pick_file() {
+        match open_path(&path) {
+            Ok(opened) => *state = opened,
+            Err(err) => eprintln!("Opening {} failed: {}", path.display(), err),
+        }
+    }
+}
+
+/// Let the Python code of an opened workbook run, once confirmed
+fn trust(state: &mut SheetState) {
+    let policy = state.workbook.python_policy();
+    if policy.trusted {
+        return;
+    }
+    let trusted = rfd::MessageDialog::new()
+        .set_title("Trust workbook")
+        .set_description("Run the Python code of this workbook? Only do so for files from people you trust.")
+        .set_buttons(rfd::MessageButtons::YesNo)
+        .show();
+    if trusted {
+        state.workbook.set_python_policy(PythonPolicy{trusted, ..policy});
+    }
+}
+
+/// Replace the Python module of the workbook with the content of a `.py` file
+fn load_python_module(state: &mut SheetState) {
+    store_input(state);
+    if let Some(path) = rfd::FileDialog::new().add_filter("Python module", &["py"]).pick_file() {
+        match std::fs::read_to_string(&path) {
+            Ok(source) => {
+                state.workbook.set_python_module(&source);
+                state.commit();
+            },
+            Err(err) => eprintln!("Reading {} failed: {}", path.display(), err),
+        }
+    }
+}
+
+/// Command line: an optional workbook to open (or create on first save) and `--autosave`
+fn initial_state() -> (SheetState, Option<rsheet::Autosave>) {
+    use std::{path::PathBuf, time::Duration};
+
+    let mut path = None;
+    let mut autosave = None;
+    for arg in std::env::args().skip(1) {
+        if arg == "--autosave" {
+            autosave = Some(rsheet::Autosave::new(Duration::from_secs(AUTOSAVE_SECS)));
+        } else {
+            path = Some(PathBuf::from(arg));
+        }
+    }
+
+    let state = match path {
+        Some(path) if path.exists() => open_path(&path).unwrap_or_else(|err| {
+            eprintln!("Opening {} failed: {}", path.display(), err);
+            SheetState::new()
+        }),
+        path => {
+            let mut state = SheetState::new();
+            state.path = path;
+            state
+        },
+    };
+    (state, autosave)
+}
+
+pub fn main() {
+    use gl::types::*;
+    use glutin::{
+        event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+        event_loop::{ControlFlow, EventLoop},
+        window::WindowBuilder,
+        GlProfile,
+    };
+    use skia_safe::{
+        gpu::{gl::FramebufferInfo, BackendRenderTarget, SurfaceOrigin},
+        Color, ColorType, Surface,
+    };
+
+    type WindowedContext = glutin::ContextWrapper<glutin::PossiblyCurrent, glutin::window::Window>;
+
+    let el = EventLoop::new();
+    let wb = WindowBuilder::new().with_title("Rusty Sheet");
+
+    let cb = glutin::ContextBuilder::new()
+         .with_depth_buffer(0)
+         .with_stencil_buffer(8)
+         .with_pixel_format(24, 8)
+         .with_gl_profile(GlProfile::Core);
+
+    let windowed_context = cb.build_windowed(wb, &el).unwrap();
+
+    let windowed_context = unsafe { windowed_context.make_current().unwrap() };
+
+    gl::load_with(|s| windowed_context.get_proc_address(s));
+
+    let mut gr_context = skia_safe::gpu::DirectContext::new_gl(None, None).unwrap();
+
+    let fb_info = {
+        let mut fboid: GLint = 0;
+        unsafe { gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut fboid) };
+
+        FramebufferInfo {
+            fboid: fboid.try_into().unwrap(),
+            format: skia_safe::gpu::gl::Format::RGBA8.into(),
+        }
+    };
+
+    windowed_context
+        .window()
+        .set_inner_size(glutin::dpi::Size::new(glutin::dpi::LogicalSize::new(
+            1024.0, 1024.0,
+        )));
+
+    fn create_surface(
+        windowed_context: &WindowedContext,
+        fb_info: &FramebufferInfo,
+        gr_context: &mut skia_safe::gpu::DirectContext,
+    ) -> skia_safe::Surface {
+        let pixel_format = windowed_context.get_pixel_format();
+        let size = windowed_context.window().inner_size();
+        let backend_render_target = BackendRenderTarget::new_gl(
+            (
+                size.width.try_into().unwrap(),
+                size.height.try_into().unwrap(),
+            ),
+            pixel_format.multisampling.map(|s| s.try_into().unwrap()),
+            pixel_format.stencil_bits.try_into().unwrap(),
+            *fb_info,
+        );
+        Surface::from_backend_render_target(
+            gr_context,
+            &backend_render_target,
+            SurfaceOrigin::BottomLeft,
+            ColorType::RGBA8888,
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    let surface = create_surface(&windowed_context, &fb_info, &mut gr_context);
+
+    // Guarantee the drop order inside the FnMut closure. `WindowedContext` _must_ be dropped after
+    // `DirectContext`.
+    //
+    // https://github.com/rust-skia/rust-skia/issues/476
+    struct Env {
+        surface: Surface,
+        gr_context: skia_safe::gpu::DirectContext,
+        windowed_context: WindowedContext,
+    }
+
+    let mut env = Env {
+        surface,
+        gr_context,
+        windowed_context,
+    };
+
+    let (mut state, mut autosave) = initial_state();
+    env.windowed_context.window().set_title(&window_title(&state));
+
+    let pre_move = move |state: &mut SheetState| {
+        store_input(state);
+    };
+    let post_move = move |state: &mut SheetState| {
+        state.text = state.sheet().get_text(&state.selected);
+    };
+
+    //let compose_move = move |func: &mut dyn FnMut(&mut SheetState)| {
+    let compose_move = move |func: fn(&mut SheetState)| {
+        debounce(move |state| {
+            pre_move(state);
+            func(state);
+            post_move(state);
+        })
+    };
+
+
+    let mut handle_left = compose_move(move |state| { state.selected.col = state.selected.col.saturating_sub(1); });
+    let mut handle_right = compose_move(move |state| { state.selected.col += 1; });
+    let mut handle_up = compose_move(move |state| { state.selected.row = state.selected.row.saturating_sub(1); });
+    let mut handle_down = compose_move(move |state| { state.selected.row += 1; });
+
+    let mut ctrl_pressed = false;
+
+    el.run(move |event, _, control_flow| {
+        *control_flow = ControlFlow::Wait;
+
+        if let Some(autosave) = autosave.as_mut() {
+            if let Some(Err(err)) = autosave.tick(&mut state) {
+                eprintln!("Autosave failed: {}", err);
+            }
+            *control_flow = ControlFlow::WaitUntil(autosave.deadline());
+        }
+
+        #[allow(deprecated)]
+        match event {
+            Event::LoopDestroyed => {},
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::Resized(physical_size) => {
+                    env.surface =
+                        create_surface(&env.windowed_context, &fb_info, &mut env.gr_context);
+                    env.windowed_context.resize(physical_size)
+                }
+                WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                WindowEvent::ModifiersChanged(state) => {
+                    ctrl_pressed = state.contains(ModifiersState::CTRL);
+                },
+                WindowEvent::ReceivedCharacter(char) => {
+                    match char {
+                        '\u{8}' => { state.text.pop(); },
+                        _ => {
+                            if !ctrl_pressed {
+                                state.text.push(char);
+                            } else {
+                                store_input(&mut state);
+                            }
+                        },
+                    }
+                    env.windowed_context.window().request_redraw();
+                },
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            virtual_keycode,
+                            modifiers,
+                            state: key_state,
+                            ..
+                        },
+                    ..
+                } => {
+                    if modifiers.logo() {
+                        if let Some(VirtualKeyCode::Q) = virtual_keycode {
+                            *control_flow = ControlFlow::Exit;
+                        }
+                    }
+
+                    match virtual_keycode {
+                        Some(VirtualKeyCode::Left) => { handle_left(&mut state); },
+                        Some(VirtualKeyCode::Right) => { handle_right(&mut state); },
+                        Some(VirtualKeyCode::Up) => { handle_up(&mut state); },
+                        Some(VirtualKeyCode::Down) => { handle_down(&mut state); },
+                        // Ctrl+Z undoes, Ctrl+Shift+Z redoes
+                        Some(VirtualKeyCode::Z) if modifiers.ctrl() && key_state == ElementState::Pressed => {
+                            let done = if modifiers.shift() { state.redo() } else { state.undo() };
+                            if done {
+                                state.text = state.sheet().get_text(&state.selected);
+                            }
+                        },
+                        // Ctrl+S saves, Ctrl+Shift+S saves as, Ctrl+O opens
+                        Some(VirtualKeyCode::S) if modifiers.ctrl() && key_state == ElementState::Pressed => {
+                            save(&mut state, modifiers.shift());
+                        },
+                        Some(VirtualKeyCode::O) if modifiers.ctrl() && key_state == ElementState::Pressed => {
+                            open(&mut state);
+                        },
+                        // Ctrl+P loads the workbook's Python module from a file
+                        Some(VirtualKeyCode::P) if modifiers.ctrl() && key_state == ElementState::Pressed => {
+                            load_python_module(&mut state);
+                        },
+                        // Ctrl+T trusts an opened workbook to run its Python code
+                        Some(VirtualKeyCode::T) if modifiers.ctrl() && key_state == ElementState::Pressed => {
+                            trust(&mut state);
+                        },
+                        _ => (),
+                    }
+                    env.windowed_context.window().set_title(&window_title(&state));
+                    env.windowed_context.window().request_redraw();
+                },
+                WindowEvent::CursorMoved {..} => {
+                    env.windowed_context.window().request_redraw();
+                }
+                _ => (),
+            },
+            Event::RedrawRequested(_) => {
+                {
+                    let canvas = env.surface.canvas();
+                    canvas.clear(Color::WHITE);
+                    crate::skia_renderer::render(canvas, &mut state);
+                }
+                env.surface.canvas().flush();
+                env.windowed_context.swap_buffers().unwrap();
+            },
+            _ => (),
+        }
+    });}
@@ -0,0 +1,246 @@
+use std::{cmp::Ordering, fmt};
+
+use crate::error::{CellError, ErrorKind};
+
+/// Result of evaluating a cell
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Empty,
+    Number(f64),
+    Text(String),
+    Boolean(bool),
+    Error(CellError),
+    /// Rows of values, as produced by ranges
+    Array(Vec<Vec<Value>>),
+}
+
+impl Value {
+    pub fn error<S: Into<String>>(kind: ErrorKind, message: S) -> Self {
+        Value::Error(CellError::new(kind, message))
+    }
+
+    /// Value of every cell taking part in a reference cycle
+    pub fn circular() -> Self {
+        Value::error(ErrorKind::Circular, "Circular reference")
+    }
+
+    /// Type a literal (non formula) cell input.
+    /// A leading `'` forces the rest of the input to be text.
+    pub fn from_input(text: &str) -> Self {
+        if text.is_empty() {
+            return Value::Empty;
+        }
+        if let Some(text) = text.strip_prefix('\'') {
+            return Value::Text(text.to_string());
+        }
+
+        let trimmed = text.trim();
+        if let Some(kind) = ErrorKind::from_code(trimmed) {
+            return Value::error(kind, "Entered as a value");
+        }
+        if trimmed.eq_ignore_ascii_case("TRUE") {
+            return Value::Boolean(true);
+        }
+        if trimmed.eq_ignore_ascii_case("FALSE") {
+            return Value::Boolean(false);
+        }
+        // Only plain decimal notation, so "inf" or "NaN" stay text
+        if trimmed.starts_with(|c: char| c.is_ascii_digit() || c == '.' || c == '-' || c == '+') {
+            if let Ok(n) = trimmed.parse::<f64>() {
+                if n.is_finite() {
+                    return Value::Number(n);
+                }
+            }
+        }
+
+        Value::Text(text.to_string())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Value::Empty)
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::Number(_))
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Value::Error(_))
+    }
+
+    pub fn error_kind(&self) -> Option<ErrorKind> {
+        match self {
+            Value::Error(err) => Some(err.kind),
+            _ => None,
+        }
+    }
+
+    /// Coerce for arithmetic, errors are passed back as `Err` so they propagate
+    pub fn to_number(&self) -> Result<f64, Value> {
+        match self {
+            Value::Empty => Ok(0.0),
+            Value::Number(n) => Ok(*n),
+            Value::Boolean(b) => Ok(if *b { 1.0 } else { 0.0 }),
+            Value::Text(text) => match Value::from_input(text.trim()) {
+                Value::Number(n) => Ok(n),
+                _ => Err(Value::error(ErrorKind::Value, format!("Expected a number, got \"{}\"", text))),
+            },
+            Value::Error(_) => Err(self.clone()),
+            Value::Array(rows) => match rows.first().and_then(|row| row.first()) {
+                Some(first) => first.to_number(),
+                None => Ok(0.0),
+            },
+        }
+    }
+
+    /// Coerce for logical functions, errors are passed back as `Err` so they propagate
+    pub fn to_bool(&self) -> Result<bool, Value> {
+        match self {
+            Value::Empty => Ok(false),
+            Value::Number(n) => Ok(*n != 0.0),
+            Value::Boolean(b) => Ok(*b),
+            Value::Text(text) => match Value::from_input(text.trim()) {
+                Value::Boolean(b) => Ok(b),
+                _ => Err(Value::error(ErrorKind::Value, format!("Expected TRUE or FALSE, got \"{}\"", text))),
+            },
+            Value::Error(_) => Err(self.clone()),
+            Value::Array(rows) => match rows.first().and_then(|row| row.first()) {
+                Some(first) => first.to_bool(),
+                None => Ok(false),
+            },
+        }
+    }
+
+    /// The value arrays stand for when a single value is needed
+    pub fn to_scalar(&self) -> Value {
+        match self {
+            Value::Array(rows) => match rows.first().and_then(|row| row.first()) {
+                Some(first) => first.clone(),
+                None => Value::Empty,
+            },
+            _ => self.clone(),
+        }
+    }
+
+    /// Spreadsheet ordering: numbers sort before text, text before booleans.
+    /// Text is compared ignoring case, empty values compare as the zero of the other side.
+    pub fn compare(&self, other: &Value) -> Ordering {
+        fn rank(value: &Value) -> u8 {
+            match value {
+                Value::Number(_) => 0,
+                Value::Text(_) => 1,
+                Value::Boolean(_) => 2,
+                _ => 3,
+            }
+        }
+
+        match (self.to_scalar(), other.to_scalar()) {
+            (Value::Empty, Value::Empty) => Ordering::Equal,
+            (Value::Empty, other) => other.zero().compare(&other),
+            (this, Value::Empty) => this.compare(&this.zero()),
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            (Value::Text(a), Value::Text(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+            (Value::Boolean(a), Value::Boolean(b)) => a.cmp(&b),
+            (a, b) => rank(&a).cmp(&rank(&b)),
+        }
+    }
+
+    fn zero(&self) -> Value {
+        match self {
+            Value::Text(_) => Value::Text("".to_string()),
+            Value::Boolean(_) => Value::Boolean(false),
+            _ => Value::Number(0.0),
+        }
+    }
+}
+
+/// Format a number the way a spreadsheet shows it - at most 15 significant digits
+/// so floating point noise (0.1+0.2) is not displayed.
+pub fn format_number(n: f64) -> String {
+    if n == 0.0 {
+        return "0".to_string();
+    }
+    let rounded = format!("{:.14e}", n).parse::<f64>().unwrap_or(n);
+    rounded.to_string()
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Empty => Ok(()),
+            Value::Number(n) => write!(f, "{}", format_number(*n)),
+            Value::Text(text) => write!(f, "{}", text),
+            Value::Boolean(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Value::Error(err) => write!(f, "{}", err),
+            Value::Array(rows) => match rows.first().and_then(|row| row.first()) {
+                Some(first) => first.fmt(f),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_typing() {
+        assert_eq!(Value::from_input(""), Value::Empty);
+        assert_eq!(Value::from_input("6"), Value::Number(6.0));
+        assert_eq!(Value::from_input(" -5.25 "), Value::Number(-5.25));
+        assert_eq!(Value::from_input("1e3"), Value::Number(1000.0));
+        assert_eq!(Value::from_input("true"), Value::Boolean(true));
+        assert_eq!(Value::from_input("FALSE"), Value::Boolean(false));
+        assert_eq!(Value::from_input("test"), Value::from("test"));
+        assert_eq!(Value::from_input("inf"), Value::from("inf"));
+        assert_eq!(Value::from_input("'42"), Value::from("42"));
+        assert_eq!(Value::from_input("#N/A").error_kind(), Some(ErrorKind::NA));
+    }
+
+    #[test]
+    fn compare() {
+        assert_eq!(Value::Number(1.0).compare(&Value::Number(2.0)), Ordering::Less);
+        assert_eq!(Value::from("abc").compare(&Value::from("ABC")), Ordering::Equal);
+        assert_eq!(Value::Number(100.0).compare(&Value::from("1")), Ordering::Less);
+        assert_eq!(Value::from("z").compare(&Value::Boolean(false)), Ordering::Less);
+        assert_eq!(Value::Empty.compare(&Value::Number(0.0)), Ordering::Equal);
+        assert_eq!(Value::Empty.compare(&Value::from("")), Ordering::Equal);
+        assert_eq!(Value::Boolean(true).compare(&Value::Empty), Ordering::Greater);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Value::Number(7.0).to_string(), "7");
+        assert_eq!(Value::Number(0.1 + 0.2).to_string(), "0.3");
+        assert_eq!(Value::Number(-2.5).to_string(), "-2.5");
+        assert_eq!(Value::Boolean(true).to_string(), "TRUE");
+        assert_eq!(Value::Empty.to_string(), "");
+        assert_eq!(Value::error(ErrorKind::DivZero, "Division by zero").to_string(), "#DIV/0!");
+        assert_eq!(Value::Array(vec![vec![Value::from("a"), Value::from("b")]]).to_string(), "a");
+    }
+}
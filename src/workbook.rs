@@ -0,0 +1,413 @@
+use std::{fmt, time::Duration, sync::atomic::{AtomicU64, Ordering}};
+
+use crate::{sheet::{Sheet, Cell, CellIdx}, engine::{self, EngineId}, engine_simple, reference::Axis, history::Edit};
+
+/// Stable identity of a sheet, kept through renames and reordering
+pub type SheetId = u32;
+
+/// Identity of a workbook among those open, for what engines keep about it outside of it
+pub type WorkbookId = u64;
+
+/// A cell on a specific sheet
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct CellPos {
+    pub sheet: SheetId,
+    pub idx: CellIdx,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum WorkbookError {
+    InvalidName(String),
+    DuplicateName(String),
+    NoSuchSheet(SheetId),
+    /// A workbook always keeps at least one sheet
+    LastSheet,
+}
+
+impl fmt::Display for WorkbookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkbookError::InvalidName(name) => write!(f, "\"{}\" is not a valid sheet name", name),
+            WorkbookError::DuplicateName(name) => write!(f, "A sheet named \"{}\" already exists", name),
+            WorkbookError::NoSuchSheet(id) => write!(f, "No sheet with id {}", id),
+            WorkbookError::LastSheet => write!(f, "Cannot delete the only sheet"),
+        }
+    }
+}
+
+/// How the Python code of a workbook may run, chosen by the user rather than saved with the file
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PythonPolicy {
+    /// Python code runs at all. Workbooks read from files are not trusted until the user says so.
+    pub trusted: bool,
+    /// Longest a cell or a call of a workbook function may run, checked between lines of Python code
+    pub time_limit: Option<Duration>,
+    /// Only pure builtins and a few pure modules, with names and attributes starting with `_` out of
+    /// reach, keeping code off files and processes. Not trusting the workbook keeps it from running at all.
+    pub restricted: bool,
+}
+
+impl Default for PythonPolicy {
+    fn default() -> Self {
+        PythonPolicy{trusted: true, time_limit: Some(Duration::from_secs(5)), restricted: false}
+    }
+}
+
+struct Entry {
+    id: SheetId,
+    name: String,
+    sheet: Sheet,
+}
+
+/// Named sheets, in display order
+pub struct Workbook {
+    id: WorkbookId,
+    sheets: Vec<Entry>,
+    next_id: SheetId,
+    /// Python code shared by the Python cells, kept even by builds without Python
+    python_module: String,
+    python_policy: PythonPolicy,
+    /// Sheets were added, renamed or deleted, or the Python module or policy changed, since the last `take_structure_change`
+    structure_changed: bool,
+    /// Sheet level edits, cell edits of each sheet are gathered in here before every sheet edit
+    journal: Vec<Edit>,
+}
+
+impl Workbook {
+    pub fn new() -> Self {
+        Workbook::with_sheets(&["Sheet1"]).unwrap()
+    }
+
+    /// Workbook of empty sheets named `names` in order, with nothing to undo
+    pub fn with_sheets<S: AsRef<str>>(names: &[S]) -> Result<Self, WorkbookError> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let mut workbook = Workbook{id, sheets: vec![], next_id: 0, python_module: String::new(),
+            python_policy: PythonPolicy::default(), structure_changed: false, journal: vec![]};
+        for name in names {
+            workbook.add_sheet(name.as_ref())?;
+        }
+        if workbook.sheets.is_empty() {
+            workbook.add_sheet("Sheet1")?;
+        }
+        workbook.journal.clear();
+        Ok(workbook)
+    }
+
+    /// Sheet ids in display order
+    pub fn sheet_ids(&self) -> Vec<SheetId> {
+        self.sheets.iter().map(|entry| entry.id).collect()
+    }
+
+    pub fn sheet(&self, id: SheetId) -> Option<&Sheet> {
+        self.entry(id).map(|entry| &entry.sheet)
+    }
+
+    pub fn sheet_mut(&mut self, id: SheetId) -> Option<&mut Sheet> {
+        self.sheets.iter_mut().find(|entry| entry.id == id).map(|entry| &mut entry.sheet)
+    }
+
+    pub fn name(&self, id: SheetId) -> Option<&str> {
+        self.entry(id).map(|entry| entry.name.as_str())
+    }
+
+    /// Sheet by name, ignoring case like formulas do
+    pub fn find(&self, name: &str) -> Option<SheetId> {
+        self.sheets.iter().find(|entry| entry.name.eq_ignore_ascii_case(name)).map(|entry| entry.id)
+    }
+
+    pub fn position(&self, id: SheetId) -> Option<usize> {
+        self.sheets.iter().position(|entry| entry.id == id)
+    }
+
+    /// First free name of the form `SheetN`
+    pub fn new_sheet_name(&self) -> String {
+        (1..).map(|n| format!("Sheet{}", n)).find(|name| self.find(name).is_none()).unwrap()
+    }
+
+    pub fn add_sheet(&mut self, name: &str) -> Result<SheetId, WorkbookError> {
+        self.check_name(name, None)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.flush_journal();
+        self.journal.push(Edit::AddSheet{id, name: name.to_string(), position: self.sheets.len()});
+        self.sheets.push(Entry{id, name: name.to_string(), sheet: Sheet::new()});
+        self.structure_changed = true;
+        Ok(id)
+    }
+
+    /// Rename a sheet, formulas referring to it follow the new name
+    pub fn rename_sheet(&mut self, id: SheetId, name: &str) -> Result<(), WorkbookError> {
+        self.check_name(name, Some(id))?;
+        let position = self.position(id).ok_or(WorkbookError::NoSuchSheet(id))?;
+        let old = std::mem::replace(&mut self.sheets[position].name, name.to_string());
+        self.flush_journal();
+        self.journal.push(Edit::RenameSheet{id, old: old.clone(), new: name.to_string()});
+        self.rewrite_formulas(|_, text| engine_simple::rename_sheet(text, &old, Some(name)));
+        self.structure_changed = true;
+        Ok(())
+    }
+
+    /// Delete a sheet, formulas referring to it get `#REF!` instead
+    pub fn delete_sheet(&mut self, id: SheetId) -> Result<Sheet, WorkbookError> {
+        let position = self.position(id).ok_or(WorkbookError::NoSuchSheet(id))?;
+        if self.sheets.len() == 1 {
+            return Err(WorkbookError::LastSheet);
+        }
+        self.flush_journal();
+        let entry = self.sheets.remove(position);
+        let cells = entry.sheet.cells().map(|(idx, cell)| (idx.clone(), cell.clone())).collect();
+        self.journal.push(Edit::DeleteSheet{id, name: entry.name.clone(), position, cells});
+        self.rewrite_formulas(|_, text| engine_simple::rename_sheet(text, &entry.name, None));
+        self.structure_changed = true;
+        Ok(entry.sheet)
+    }
+
+    /// Move a sheet to `position` in the display order
+    pub fn move_sheet(&mut self, id: SheetId, position: usize) -> Result<(), WorkbookError> {
+        let current = self.position(id).ok_or(WorkbookError::NoSuchSheet(id))?;
+        let entry = self.sheets.remove(current);
+        let position = position.min(self.sheets.len());
+        self.sheets.insert(position, entry);
+        self.flush_journal();
+        self.journal.push(Edit::MoveSheet{id, from: current, to: position});
+        Ok(())
+    }
+
+    pub fn id(&self) -> WorkbookId {
+        self.id
+    }
+
+    pub fn python_module(&self) -> &str {
+        &self.python_module
+    }
+
+    /// Replace the Python code shared by the Python cells, which are all evaluated again
+    pub fn set_python_module(&mut self, source: &str) {
+        if source == self.python_module {
+            return;
+        }
+        let old = std::mem::replace(&mut self.python_module, source.to_string());
+        self.flush_journal();
+        self.journal.push(Edit::PythonModule{old, new: source.to_string()});
+        self.structure_changed = true;
+    }
+
+    pub fn python_policy(&self) -> PythonPolicy {
+        self.python_policy
+    }
+
+    /// Change how Python code may run, evaluating the Python cells again. Not an edit, nothing to undo.
+    pub fn set_python_policy(&mut self, policy: PythonPolicy) {
+        if policy != self.python_policy {
+            self.python_policy = policy;
+            self.structure_changed = true;
+        }
+    }
+
+    pub fn insert_rows(&mut self, id: SheetId, at: u32, count: u32) -> Result<(), WorkbookError> {
+        self.shift(id, Axis::Row, at, count as i64)
+    }
+
+    pub fn remove_rows(&mut self, id: SheetId, at: u32, count: u32) -> Result<(), WorkbookError> {
+        self.shift(id, Axis::Row, at, -(count as i64))
+    }
+
+    pub fn insert_cols(&mut self, id: SheetId, at: u32, count: u32) -> Result<(), WorkbookError> {
+        self.shift(id, Axis::Col, at, count as i64)
+    }
+
+    pub fn remove_cols(&mut self, id: SheetId, at: u32, count: u32) -> Result<(), WorkbookError> {
+        self.shift(id, Axis::Col, at, -(count as i64))
+    }
+
+    /// Move the cells of a sheet and have formulas on every sheet follow the move
+    fn shift(&mut self, id: SheetId, axis: Axis, at: u32, count: i64) -> Result<(), WorkbookError> {
+        let position = self.position(id).ok_or(WorkbookError::NoSuchSheet(id))?;
+        let target = self.sheets[position].name.clone();
+        self.rewrite_formulas(|own, text| engine_simple::shift(text, own, &target, axis, at, count));
+        self.sheets[position].sheet.move_cells(axis, at, count);
+        Ok(())
+    }
+
+    /// Replace the text of every simple formula with `func(sheet name, text)`
+    fn rewrite_formulas<F>(&mut self, func: F) where F: Fn(&str, &str) -> String {
+        for entry in self.sheets.iter_mut() {
+            let name = &entry.name;
+            entry.sheet.rewrite(EngineId::SIMPLE, |text| func(name, text));
+        }
+    }
+
+    /// Cells modified on any sheet since the last call
+    pub fn take_changes(&mut self) -> Vec<CellPos> {
+        let mut changes = vec![];
+        for entry in self.sheets.iter_mut() {
+            let sheet = entry.id;
+            changes.extend(entry.sheet.take_changes().into_iter().map(|idx| CellPos{sheet, idx}));
+        }
+        changes
+    }
+
+    /// Edits since the last call, oldest first
+    pub fn take_journal(&mut self) -> Vec<Edit> {
+        self.flush_journal();
+        std::mem::take(&mut self.journal)
+    }
+
+    fn flush_journal(&mut self) {
+        for entry in self.sheets.iter_mut() {
+            let id = entry.id;
+            self.journal.extend(entry.sheet.take_journal().into_iter().map(|edit| Edit::Cell(id, edit)));
+        }
+    }
+
+    /// Apply an edit kept by the history, or revert it when `undo`.
+    /// The edit itself is not journaled again.
+    pub(crate) fn apply(&mut self, edit: &Edit, undo: bool) {
+        self.flush_journal();
+        match edit {
+            Edit::Cell(id, edit) => {
+                if let Some(sheet) = self.sheet_mut(*id) {
+                    sheet.put(edit.idx.clone(), if undo { edit.old.clone() } else { edit.new.clone() });
+                    sheet.take_journal();
+                }
+            },
+            Edit::AddSheet{id, name, position} => {
+                if undo { self.remove_entry(*id); } else { self.restore_entry(*id, name, *position, &[]); }
+            },
+            Edit::DeleteSheet{id, name, position, cells} => {
+                if undo { self.restore_entry(*id, name, *position, cells); } else { self.remove_entry(*id); }
+            },
+            Edit::RenameSheet{id, old, new} => {
+                if let Some(position) = self.position(*id) {
+                    self.sheets[position].name = if undo { old.clone() } else { new.clone() };
+                    self.structure_changed = true;
+                }
+            },
+            Edit::MoveSheet{id, from, to} => {
+                if let Some(position) = self.position(*id) {
+                    let entry = self.sheets.remove(position);
+                    let position = if undo { *from } else { *to };
+                    self.sheets.insert(position.min(self.sheets.len()), entry);
+                }
+            },
+            Edit::PythonModule{old, new} => {
+                self.python_module = if undo { old.clone() } else { new.clone() };
+                self.structure_changed = true;
+            },
+        }
+    }
+
+    fn remove_entry(&mut self, id: SheetId) {
+        self.sheets.retain(|entry| entry.id != id);
+        self.structure_changed = true;
+    }
+
+    fn restore_entry(&mut self, id: SheetId, name: &str, position: usize, cells: &[(CellIdx, Cell)]) {
+        let mut sheet = Sheet::new();
+        for (idx, cell) in cells {
+            sheet.insert(idx.clone(), cell.clone());
+        }
+        sheet.take_journal();
+        self.sheets.insert(position.min(self.sheets.len()), Entry{id, name: name.to_string(), sheet});
+        self.structure_changed = true;
+    }
+
+    /// Whether sheets were added, renamed or deleted, or the Python module changed, since the last call
+    pub fn take_structure_change(&mut self) -> bool {
+        std::mem::take(&mut self.structure_changed)
+    }
+
+    fn entry(&self, id: SheetId) -> Option<&Entry> {
+        self.sheets.iter().find(|entry| entry.id == id)
+    }
+
+    /// Same rules as common spreadsheets: up to 31 characters, none of `[]:*?/\`,
+    /// not starting or ending with `'` and unique ignoring case
+    fn check_name(&self, name: &str, renamed: Option<SheetId>) -> Result<(), WorkbookError> {
+        let invalid = name.trim().is_empty() || name.chars().count() > 31
+            || name.contains(|c| "[]:*?/\\".contains(c))
+            || name.starts_with('\'') || name.ends_with('\'');
+        if invalid {
+            return Err(WorkbookError::InvalidName(name.to_string()));
+        }
+        match self.find(name) {
+            Some(existing) if Some(existing) != renamed => Err(WorkbookError::DuplicateName(name.to_string())),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Workbook {
+    fn drop(&mut self) {
+        engine::close(self.id);
+    }
+}
+
+impl Default for Workbook {
+    fn default() -> Self {
+        Workbook::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheets() {
+        let mut workbook = Workbook::new();
+        let first = workbook.sheet_ids()[0];
+        assert_eq!(workbook.name(first), Some("Sheet1"));
+
+        let second = workbook.add_sheet(&workbook.new_sheet_name()).unwrap();
+        assert_eq!(workbook.name(second), Some("Sheet2"));
+        assert_eq!(workbook.add_sheet("sheet2"), Err(WorkbookError::DuplicateName("sheet2".to_string())));
+        assert_eq!(workbook.add_sheet("a/b"), Err(WorkbookError::InvalidName("a/b".to_string())));
+
+        workbook.move_sheet(second, 0).unwrap();
+        assert_eq!(workbook.sheet_ids(), vec![second, first]);
+
+        workbook.delete_sheet(first).unwrap();
+        assert_eq!(workbook.delete_sheet(second).err(), Some(WorkbookError::LastSheet));
+        assert_eq!(workbook.find("SHEET2"), Some(second));
+    }
+
+    #[test]
+    fn formulas_follow_sheets() {
+        let mut workbook = Workbook::new();
+        let first = workbook.sheet_ids()[0];
+        let data = workbook.add_sheet("Data").unwrap();
+        let a1 = CellIdx{col: 0, row: 0};
+        workbook.sheet_mut(first).unwrap().set_text(a1.clone(), "=Data!B4+SUM(data!A1:A3)+A2".to_string());
+
+        workbook.rename_sheet(data, "Q1 Data").unwrap();
+        assert_eq!(workbook.sheet(first).unwrap().get_text(&a1), "='Q1 Data'!B4+SUM('Q1 Data'!A1:A3)+A2");
+
+        workbook.insert_rows(data, 0, 1).unwrap();
+        assert_eq!(workbook.sheet(first).unwrap().get_text(&a1), "='Q1 Data'!B5+SUM('Q1 Data'!A2:A4)+A2");
+
+        workbook.delete_sheet(data).unwrap();
+        assert_eq!(workbook.sheet(first).unwrap().get_text(&a1), "=#REF!+SUM(#REF!)+A2");
+    }
+
+    #[test]
+    fn insert_remove() {
+        let mut workbook = Workbook::new();
+        let id = workbook.sheet_ids()[0];
+        let sheet = workbook.sheet_mut(id).unwrap();
+        sheet.set_text(CellIdx{col: 0, row: 0}, "1".to_string());
+        sheet.set_text(CellIdx{col: 0, row: 3}, "=$A$1*(A3-1)+SUM(A1:A3)".to_string());
+
+        workbook.insert_rows(id, 1, 2).unwrap();
+        let sheet = workbook.sheet(id).unwrap();
+        assert_eq!(sheet.get_text(&CellIdx{col: 0, row: 3}), "");
+        assert_eq!(sheet.get_text(&CellIdx{col: 0, row: 5}), "=$A$1*(A5-1)+SUM(A1:A5)");
+
+        workbook.remove_rows(id, 4, 1).unwrap();
+        assert_eq!(workbook.sheet(id).unwrap().get_text(&CellIdx{col: 0, row: 4}), "=$A$1*(#REF!-1)+SUM(A1:A4)");
+
+        workbook.remove_cols(id, 0, 1).unwrap();
+        assert_eq!(workbook.sheet(id).unwrap().get_text(&CellIdx{col: 0, row: 0}), "");
+    }
+}
@@ -0,0 +1,539 @@
+use std::{collections::HashMap, fs, io::{Cursor, Write}, path::Path};
+
+use zip::write::FileOptions;
+
+use crate::{
+    sheet::{Sheet, CellIdx}, sheet_state::SheetState, workbook::{Workbook, SheetId, CellPos},
+    engine_simple, value::Value, reference::{MAX_ROWS, str_to_col, col_to_str},
+    file::{FileError, ImportInfo, Unsupported, Package, format_error, literal, engine_note}, xml::{self, Element},
+};
+
+pub const EXTENSION: &str = "xlsx";
+
+// Ends of the relationship types, which differ between the transitional and strict flavours
+const OFFICE_DOCUMENT: &str = "/officeDocument";
+const WORKSHEET: &str = "/worksheet";
+const SHARED_STRINGS: &str = "/sharedStrings";
+
+struct Relationship {
+    id: String,
+    kind: String,
+    /// Path of the target part inside the package
+    target: String,
+}
+
+/// Path of `target` relative to the part at `base`
+fn resolve(base: &str, target: &str) -> String {
+    if let Some(absolute) = target.strip_prefix('/') {
+        return absolute.to_string();
+    }
+    let mut parts = base.split('/').collect::<Vec<_>>();
+    parts.pop();
+    for part in target.split('/') {
+        match part {
+            ".." => { parts.pop(); },
+            "." | "" => (),
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Relationships of the part at `path`, of the package itself for an empty path
+fn relationships(package: &mut Package, path: &str) -> Result<Vec<Relationship>, FileError> {
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let rels_path = if dir.is_empty() { format!("_rels/{}.rels", name) } else { format!("{}/_rels/{}.rels", dir, name) };
+    let rels = match package.read(&rels_path)? {
+        Some(rels) => rels,
+        None => return Ok(vec![]),
+    };
+    Ok(rels.children("Relationship").map(|rel| Relationship{
+        id: rel.attr("Id").unwrap_or_default().to_string(),
+        kind: rel.attr("Type").unwrap_or_default().to_string(),
+        target: resolve(path, rel.attr("Target").unwrap_or_default()),
+    }).collect())
+}
+
+/// Text of a shared or inline string, rich text runs joined and phonetic hints left out
+fn rich_text(string: &Element) -> String {
+    string.elements().filter(|part| part.name == "t" || part.name == "r").map(|part| part.text()).collect()
+}
+
+/// `A1` style cell name, as found in the `r` attribute of cells, `None` when not on a sheet
+fn parse_cell(text: &str) -> Option<CellIdx> {
+    let (letters, digits) = text.split_at(text.find(|c: char| c.is_ascii_digit())?);
+    if letters.is_empty() || letters.len() > 3 || !letters.chars().all(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    let row = digits.parse::<u32>().ok().filter(|row| *row <= MAX_ROWS)?.checked_sub(1)?;
+    Some(CellIdx{col: str_to_col(letters)?, row})
+}
+
+/// Value stored with a cell, as it would be entered, `None` for empty cells
+fn cell_value(cell: &Element, strings: &[String]) -> Option<String> {
+    let raw = cell.child("v").map(|v| v.text());
+    let text = match cell.attr("t").unwrap_or("n") {
+        "s" => strings.get(raw?.trim().parse::<usize>().ok()?)?.clone(),
+        "inlineStr" => rich_text(cell.child("is")?),
+        "str" | "d" => raw?,
+        "b" => return Some(if raw?.trim() == "1" { "TRUE" } else { "FALSE" }.to_string()),
+        // Numbers and error codes read back as themselves
+        _ => return raw.filter(|raw| !raw.is_empty()),
+    };
+    (!text.is_empty()).then(|| literal(text))
+}
+
+/// Excel formula text, without its `=`, in the syntax of the simple engine
+fn map_formula(text: &str) -> Option<String> {
+    // Functions added after the first version of the format carry a prefix
+    let text = text.replace("_xlfn.", "").replace("_xlws.", "");
+    engine_simple::canonical(&format!("={}", text))
+}
+
+/// Shared formulas by index: the cell holding their text and that text
+type SharedFormulas = HashMap<String, (CellIdx, Result<String, String>)>;
+
+/// Formula of a cell mapped to the simple engine, or as written when it cannot be.
+/// Cells of a shared formula get the formula of the first cell, translated.
+fn cell_formula(formula: &Element, idx: &CellIdx, shared: &mut SharedFormulas) -> Option<Result<String, String>> {
+    let text = formula.text();
+    let mapped = |text: String| map_formula(&text).ok_or(text);
+    if formula.attr("t") != Some("shared") {
+        return (!text.is_empty()).then(|| mapped(text));
+    }
+
+    let index = formula.attr("si")?.to_string();
+    if !text.is_empty() {
+        let result = mapped(text);
+        shared.insert(index, (idx.clone(), result.clone()));
+        return Some(result);
+    }
+    let (first, result) = shared.get(&index)?;
+    let (dcol, drow) = (idx.col as i64 - first.col as i64, idx.row as i64 - first.row as i64);
+    Some(result.as_ref().map(|text| engine_simple::translate(text, dcol, drow)).map_err(|text| text.clone()))
+}
+
+fn read_sheet(xml: &Element, strings: &[String], sheet: &mut Sheet, name: &str, info: &mut ImportInfo) -> Result<(), FileError> {
+    let mut shared = SharedFormulas::new();
+    let mut row = 0;
+    for row_xml in xml.child("sheetData").into_iter().flat_map(|data| data.children("row")) {
+        // Row and cell positions may be left out, meaning right after the previous one
+        row = row_xml.attr("r").and_then(|r| r.parse::<u32>().ok()).and_then(|r| r.checked_sub(1)).unwrap_or(row);
+        let mut col = 0;
+        for cell in row_xml.children("c") {
+            let idx = match cell.attr("r") {
+                Some(r) => parse_cell(r).ok_or_else(|| format_error(format!("{}: no cell {}", name, r)))?,
+                None => CellIdx{col, row},
+            };
+            col = idx.col + 1;
+
+            let value = cell_value(cell, strings);
+            let text = match cell.child("f").and_then(|formula| cell_formula(formula, &idx, &mut shared)) {
+                Some(Ok(formula)) => Some(formula),
+                Some(Err(formula)) => {
+                    info.unsupported.push(Unsupported{sheet: name.to_string(), idx: idx.clone(), formula});
+                    value
+                },
+                None => value,
+            };
+            if let Some(text) = text {
+                sheet.set_text(idx, text);
+            }
+        }
+        row += 1;
+    }
+    Ok(())
+}
+
+/// Read an Excel workbook into a fresh state.
+/// Formulas the simple engine cannot evaluate are replaced by their cached values and listed in
+/// the returned info. Only values come in: dates stay serial numbers and formatting is dropped.
+pub fn import(bytes: &[u8]) -> Result<(SheetState, ImportInfo), FileError> {
+    let mut package = Package::open(bytes)?;
+    let workbook_path = relationships(&mut package, "")?.into_iter()
+        .find(|rel| rel.kind.ends_with(OFFICE_DOCUMENT))
+        .map(|rel| rel.target)
+        .unwrap_or_else(|| "xl/workbook.xml".to_string());
+    let workbook_xml = package.read(&workbook_path)?.ok_or_else(|| format_error("no workbook part"))?;
+    let rels = relationships(&mut package, &workbook_path)?;
+
+    let strings = match rels.iter().find(|rel| rel.kind.ends_with(SHARED_STRINGS)) {
+        Some(rel) => package.read(&rel.target)?.map(|sst| sst.children("si").map(rich_text).collect()).unwrap_or_default(),
+        None => vec![],
+    };
+
+    let mut info = ImportInfo::default();
+    // Worksheets with their position among all sheets and their part
+    let mut sheets = vec![];
+    for (position, sheet) in workbook_xml.child("sheets").into_iter().flat_map(|sheets| sheets.children("sheet")).enumerate() {
+        let name = sheet.attr("name").unwrap_or_default().to_string();
+        match sheet.attr("id").and_then(|id| rels.iter().find(|rel| rel.id == id)) {
+            Some(rel) if rel.kind.ends_with(WORKSHEET) => sheets.push((position, name, rel.target.clone())),
+            _ => info.skipped.push(name),
+        }
+    }
+    if sheets.is_empty() {
+        return Err(format_error("no worksheets"));
+    }
+
+    let names = sheets.iter().map(|(_, name, _)| name.as_str()).collect::<Vec<_>>();
+    let mut workbook = Workbook::with_sheets(&names).map_err(format_error)?;
+    let ids = workbook.sheet_ids();
+    for (id, (_, name, path)) in ids.iter().zip(sheets.iter()) {
+        let xml = package.read(path)?.ok_or_else(|| format_error(format!("missing sheet {}", path)))?;
+        read_sheet(&xml, &strings, workbook.sheet_mut(*id).unwrap(), name, &mut info)?;
+    }
+    // Importing is not something to undo
+    workbook.take_journal();
+
+    let mut state = SheetState::with_workbook(workbook);
+    let active = workbook_xml.child("bookViews").and_then(|views| views.child("workbookView"))
+        .and_then(|view| view.attr("activeTab")).and_then(|tab| tab.parse::<usize>().ok());
+    if let Some(index) = sheets.iter().position(|(position, _, _)| Some(*position) == active) {
+        state.set_active(ids[index]);
+    }
+    state.text = state.sheet().get_text(&state.selected);
+    Ok((state, info))
+}
+
+pub fn import_file(path: &Path) -> Result<(SheetState, ImportInfo), FileError> {
+    import(&fs::read(path)?)
+}
+
+const MAIN_NS: &str = "http://schemas.openxmlformats.org/spreadsheetml/2006/main";
+const RELS_NS: &str = "http://schemas.openxmlformats.org/package/2006/relationships";
+const DOC_RELS_NS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+const XML_DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n";
+
+/// Width of columns Excel shows by default, in characters
+const DEFAULT_WIDTH: f64 = 8.43;
+const MAX_WIDTH: f64 = 80.0;
+
+const STYLES: &str = "<fonts count=\"1\"><font><sz val=\"11\"/><name val=\"Calibri\"/></font></fonts>\
+    <fills count=\"2\"><fill><patternFill patternType=\"none\"/></fill><fill><patternFill patternType=\"gray125\"/></fill></fills>\
+    <borders count=\"1\"><border><left/><right/><top/><bottom/><diagonal/></border></borders>\
+    <cellStyleXfs count=\"1\"><xf numFmtId=\"0\" fontId=\"0\" fillId=\"0\" borderId=\"0\"/></cellStyleXfs>\
+    <cellXfs count=\"1\"><xf numFmtId=\"0\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\"/></cellXfs>\
+    <cellStyles count=\"1\"><cellStyle name=\"Normal\" xfId=\"0\" builtinId=\"0\"/></cellStyles>";
+
+/// Simple engine formula in Excel syntax, without its `=`
+fn excel_formula(text: &str) -> Option<String> {
+    engine_simple::parse_portable(text).map(|expr| expr.to_string())
+}
+
+fn relationship(out: &mut String, id: &str, kind: &str, target: &str) {
+    out.push_str(&format!("<Relationship Id=\"{}\" Type=\"{}/{}\" Target=\"{}\"/>", id, DOC_RELS_NS, kind, xml::escape(target)));
+}
+
+/// `<c>` element of a cell with an optional formula, `value` being the computed one
+fn cell_xml(out: &mut String, idx: &CellIdx, formula: Option<&str>, value: &Value) {
+    let name = format!("{}{}", col_to_str(idx.col), idx.row + 1);
+    let (kind, content) = match value.to_scalar() {
+        Value::Number(n) => ("", format!("<v>{}</v>", n)),
+        Value::Text(text) if formula.is_some() => (" t=\"str\"", format!("<v>{}</v>", xml::escape(&text))),
+        Value::Text(text) => (" t=\"inlineStr\"", format!("<is><t xml:space=\"preserve\">{}</t></is>", xml::escape(&text))),
+        Value::Boolean(b) => (" t=\"b\"", format!("<v>{}</v>", b as u8)),
+        Value::Error(err) => (" t=\"e\"", format!("<v>{}</v>", err.kind.standard_code())),
+        _ if formula.is_some() => ("", String::new()),
+        _ => return,
+    };
+    let formula = formula.map(|formula| format!("<f>{}</f>", xml::escape(formula))).unwrap_or_default();
+    out.push_str(&format!("<c r=\"{}\"{}>{}{}</c>", name, kind, formula, content));
+}
+
+/// Comments part and the VML drawing Excel needs to show them, for the `number`th sheet
+fn notes_xml(notes: &[(CellIdx, String)], number: usize) -> (String, String) {
+    let mut comments = format!("{}<comments xmlns=\"{}\"><authors><author>Rusty Sheet</author></authors><commentList>", XML_DECLARATION, MAIN_NS);
+    let mut drawing = format!("<xml xmlns:v=\"urn:schemas-microsoft-com:vml\" xmlns:o=\"urn:schemas-microsoft-com:office:office\" \
+        xmlns:x=\"urn:schemas-microsoft-com:office:excel\"><o:shapelayout v:ext=\"edit\"><o:idmap v:ext=\"edit\" data=\"{}\"/></o:shapelayout>\
+        <v:shapetype id=\"_x0000_t202\" coordsize=\"21600,21600\" o:spt=\"202\" path=\"m,l,21600r21600,l21600,xe\">\
+        <v:stroke joinstyle=\"miter\"/><v:path gradientshapeok=\"t\" o:connecttype=\"rect\"/></v:shapetype>", number);
+    for (i, (idx, note)) in notes.iter().enumerate() {
+        comments.push_str(&format!("<comment ref=\"{}{}\" authorId=\"0\"><text><t xml:space=\"preserve\">{}</t></text></comment>",
+            col_to_str(idx.col), idx.row + 1, xml::escape(note)));
+        drawing.push_str(&format!("<v:shape id=\"_x0000_s{}\" type=\"#_x0000_t202\" style=\"position:absolute;width:160pt;height:60pt;visibility:hidden\" \
+            fillcolor=\"#ffffe1\" o:insetmode=\"auto\"><v:fill color2=\"#ffffe1\"/><v:shadow on=\"t\" color=\"black\" obscured=\"t\"/>\
+            <v:path o:connecttype=\"none\"/><v:textbox><div style=\"text-align:left\"/></v:textbox><x:ClientData ObjectType=\"Note\">\
+            <x:MoveWithCells/><x:SizeWithCells/><x:Anchor>{}, 15, {}, 2, {}, 15, {}, 16</x:Anchor><x:AutoFill>False</x:AutoFill>\
+            <x:Row>{}</x:Row><x:Column>{}</x:Column></x:ClientData></v:shape>",
+            number * 1024 + i + 1, idx.col + 1, idx.row, idx.col + 3, idx.row + 4, idx.row, idx.col));
+    }
+    comments.push_str("</commentList></comments>");
+    drawing.push_str("</xml>");
+    (comments, drawing)
+}
+
+/// Worksheet part and the notes left on cells of other engines
+fn sheet_xml(state: &mut SheetState, sheet: SheetId, active: bool) -> (String, Vec<(CellIdx, String)>) {
+    let mut cells = state.workbook.sheet(sheet).unwrap().cells()
+        .filter(|(_, cell)| !cell.value.trim().is_empty())
+        .map(|(idx, cell)| (idx.clone(), Some(cell.clone())))
+        .collect::<Vec<_>>();
+    // Spilled elements are written as values, like the cells of other engines computing them
+    cells.extend(state.spilled_cells(sheet).into_iter().map(|idx| (idx, None)));
+    cells.sort_by_key(|(idx, _)| (idx.row, idx.col));
+
+    let mut rows = String::new();
+    let mut widths: HashMap<u32, usize> = HashMap::new();
+    let mut notes = vec![];
+    let mut row = None;
+    for (idx, cell) in cells {
+        if row != Some(idx.row) {
+            if row.is_some() {
+                rows.push_str("</row>");
+            }
+            rows.push_str(&format!("<row r=\"{}\">", idx.row + 1));
+            row = Some(idx.row);
+        }
+
+        let mut value = state.get_value_at(&CellPos{sheet, idx: idx.clone()});
+        let formula = if let Some(note) = cell.as_ref().and_then(engine_note) {
+            notes.push((idx.clone(), note));
+            None
+        } else if let Some(cell) = cell.filter(|cell| cell.value.starts_with('=')) {
+            let formula = excel_formula(&cell.value);
+            if formula.is_none() {
+                // The text as entered, rather than an error that has lost its formula
+                value = Value::Text(cell.value.clone());
+            }
+            formula
+        } else {
+            None
+        };
+        let width = widths.entry(idx.col).or_default();
+        *width = (*width).max(value.to_string().chars().count());
+        cell_xml(&mut rows, &idx, formula.as_deref(), &value);
+    }
+    if row.is_some() {
+        rows.push_str("</row>");
+    }
+
+    let mut out = format!("{}<worksheet xmlns=\"{}\" xmlns:r=\"{}\">", XML_DECLARATION, MAIN_NS, DOC_RELS_NS);
+    out.push_str(&format!("<sheetViews><sheetView workbookViewId=\"0\"{}/></sheetViews>", if active { " tabSelected=\"1\"" } else { "" }));
+    let mut widths = widths.into_iter().collect::<Vec<_>>();
+    widths.sort();
+    if !widths.is_empty() {
+        out.push_str("<cols>");
+        for (col, chars) in widths {
+            let width = (chars as f64 + 2.0).clamp(DEFAULT_WIDTH, MAX_WIDTH);
+            out.push_str(&format!("<col min=\"{}\" max=\"{}\" width=\"{}\" customWidth=\"1\"/>", col + 1, col + 1, width));
+        }
+        out.push_str("</cols>");
+    }
+    out.push_str(&format!("<sheetData>{}</sheetData>", rows));
+    if !notes.is_empty() {
+        out.push_str("<legacyDrawing r:id=\"rId2\"/>");
+    }
+    out.push_str("</worksheet>");
+    (out, notes)
+}
+
+/// Every sheet of the workbook as an Excel file: formulas in Excel syntax along with their
+/// computed values, so Excel shows them before recalculating
+pub fn export(state: &mut SheetState) -> Vec<u8> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+    let mut add = |path: &str, content: &str| {
+        zip.start_file(path, FileOptions::default()).unwrap();
+        zip.write_all(content.as_bytes()).unwrap();
+    };
+
+    let ids = state.workbook.sheet_ids();
+    let active = state.workbook.position(state.active()).unwrap_or(0);
+    let mut types = format!("{}<Types xmlns=\"http://schemas.openxmlformats.org/package/2006/content-types\">\
+        <Default Extension=\"rels\" ContentType=\"application/vnd.openxmlformats-package.relationships+xml\"/>\
+        <Default Extension=\"xml\" ContentType=\"application/xml\"/>\
+        <Default Extension=\"vml\" ContentType=\"application/vnd.openxmlformats-officedocument.vmlDrawing\"/>\
+        <Override PartName=\"/xl/workbook.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml\"/>\
+        <Override PartName=\"/xl/styles.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.spreadsheetml.styles+xml\"/>", XML_DECLARATION);
+    let mut workbook = format!("{}<workbook xmlns=\"{}\" xmlns:r=\"{}\"><bookViews><workbookView activeTab=\"{}\"/></bookViews><sheets>",
+        XML_DECLARATION, MAIN_NS, DOC_RELS_NS, active);
+    let mut workbook_rels = format!("{}<Relationships xmlns=\"{}\">", XML_DECLARATION, RELS_NS);
+
+    for (i, id) in ids.iter().enumerate() {
+        let number = i + 1;
+        let (sheet, notes) = sheet_xml(state, *id, i == active);
+        add(&format!("xl/worksheets/sheet{}.xml", number), &sheet);
+        types.push_str(&format!("<Override PartName=\"/xl/worksheets/sheet{}.xml\" \
+            ContentType=\"application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml\"/>", number));
+        workbook.push_str(&format!("<sheet name=\"{}\" sheetId=\"{}\" r:id=\"rId{}\"/>", xml::escape(state.workbook.name(*id).unwrap()), number, number));
+        relationship(&mut workbook_rels, &format!("rId{}", number), "worksheet", &format!("worksheets/sheet{}.xml", number));
+
+        if !notes.is_empty() {
+            let (comments, drawing) = notes_xml(&notes, number);
+            add(&format!("xl/comments{}.xml", number), &comments);
+            add(&format!("xl/drawings/vmlDrawing{}.vml", number), &drawing);
+            types.push_str(&format!("<Override PartName=\"/xl/comments{}.xml\" \
+                ContentType=\"application/vnd.openxmlformats-officedocument.spreadsheetml.comments+xml\"/>", number));
+            let mut sheet_rels = format!("{}<Relationships xmlns=\"{}\">", XML_DECLARATION, RELS_NS);
+            relationship(&mut sheet_rels, "rId1", "comments", &format!("../comments{}.xml", number));
+            relationship(&mut sheet_rels, "rId2", "vmlDrawing", &format!("../drawings/vmlDrawing{}.vml", number));
+            sheet_rels.push_str("</Relationships>");
+            add(&format!("xl/worksheets/_rels/sheet{}.xml.rels", number), &sheet_rels);
+        }
+    }
+    relationship(&mut workbook_rels, &format!("rId{}", ids.len() + 1), "styles", "styles.xml");
+    workbook_rels.push_str("</Relationships>");
+    workbook.push_str("</sheets></workbook>");
+    types.push_str("</Types>");
+
+    let mut package_rels = format!("{}<Relationships xmlns=\"{}\">", XML_DECLARATION, RELS_NS);
+    relationship(&mut package_rels, "rId1", "officeDocument", "xl/workbook.xml");
+    package_rels.push_str("</Relationships>");
+
+    add("[Content_Types].xml", &types);
+    add("_rels/.rels", &package_rels);
+    add("xl/workbook.xml", &workbook);
+    add("xl/_rels/workbook.xml.rels", &workbook_rels);
+    add("xl/styles.xml", &format!("{}<styleSheet xmlns=\"{}\">{}</styleSheet>", XML_DECLARATION, MAIN_NS, STYLES));
+    zip.finish().unwrap().into_inner()
+}
+
+pub fn export_file(state: &mut SheetState, path: &Path) -> Result<(), FileError> {
+    fs::write(path, export(state))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(parts: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (path, content) in parts {
+            zip.start_file(*path, FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    const RELS: &str = r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+        <Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/>
+        </Relationships>"#;
+    const WORKBOOK: &str = r#"<workbook xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships">
+        <bookViews><workbookView activeTab="2"/></bookViews>
+        <sheets><sheet name="Sheet1" sheetId="1" r:id="rId1"/><sheet name="Chart" sheetId="2" r:id="rId2"/><sheet name="Q1 Data" sheetId="3" r:id="rId3"/></sheets>
+        </workbook>"#;
+    const WORKBOOK_RELS: &str = r#"<Relationships>
+        <Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet1.xml"/>
+        <Relationship Id="rId2" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/chartsheet" Target="chartsheets/sheet1.xml"/>
+        <Relationship Id="rId3" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="/xl/worksheets/sheet2.xml"/>
+        <Relationship Id="rId4" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/sharedStrings" Target="sharedStrings.xml"/>
+        </Relationships>"#;
+    const STRINGS: &str = r#"<sst><si><t>Total</t></si><si><r><rPr><b/></rPr><t xml:space="preserve">Q1 </t></r><r><t>sales</t></r><rPh><t>x</t></rPh></si><si><t>42</t></si></sst>"#;
+    const SHEET1: &str = r#"<worksheet><sheetData>
+        <row r="1"><c r="A1" t="s"><v>0</v></c><c r="B1"><v>2.5</v></c><c r="C1" t="s"><v>1</v></c><c r="D1" t="s"><v>2</v></c></row>
+        <row r="2"><c r="A2"><f>_xlfn.STDEV.S(B1:B3)</f><v>1.25</v></c><c r="B2"><f t="shared" ref="B2:B3" si="0">B1*2</f><v>5</v></c></row>
+        <row r="3"><c r="A3" t="b"><v>1</v></c><c r="B3"><f t="shared" si="0"/><v>10</v></c><c t="e"><v>#N/A</v></c><c t="inlineStr"><is><t>=text</t></is></c></row>
+        <row><c><f>SUM('Q1 Data'!A1:A2)+$B$1</f><v>9.5</v></c></row>
+        </sheetData></worksheet>"#;
+    const SHEET2: &str = r#"<worksheet><sheetData><row r="1"><c r="A1"><v>3</v></c></row><row r="2"><c r="A2"><v>4</v></c></row></sheetData></worksheet>"#;
+
+    #[test]
+    fn import_workbook() {
+        let bytes = package(&[
+            ("_rels/.rels", RELS), ("xl/workbook.xml", WORKBOOK), ("xl/_rels/workbook.xml.rels", WORKBOOK_RELS),
+            ("xl/sharedStrings.xml", STRINGS), ("xl/worksheets/sheet1.xml", SHEET1), ("xl/worksheets/sheet2.xml", SHEET2),
+        ]);
+        let (mut state, info) = import(&bytes).unwrap();
+        assert_eq!(info.skipped, vec!["Chart"]);
+        assert_eq!(info.unsupported, vec![Unsupported{sheet: "Sheet1".to_string(), idx: CellIdx{col: 0, row: 1}, formula: "_xlfn.STDEV.S(B1:B3)".to_string()}]);
+        assert_eq!(state.workbook.name(state.active()), Some("Q1 Data"));
+
+        state.set_active(state.workbook.sheet_ids()[0]);
+        let text = |state: &SheetState, col, row| state.sheet().get_text(&CellIdx{col, row});
+        assert_eq!(text(&state, 0, 0), "Total");
+        assert_eq!(text(&state, 2, 0), "Q1 sales");
+        assert_eq!(text(&state, 3, 0), "'42");
+        assert_eq!(text(&state, 0, 1), "1.25");
+        assert_eq!(text(&state, 1, 2), "=B2*2");
+        assert_eq!(text(&state, 0, 2), "TRUE");
+        assert_eq!(text(&state, 2, 2), "#N/A");
+        assert_eq!(text(&state, 3, 2), "'=text");
+        assert_eq!(text(&state, 0, 3), "=SUM('Q1 Data'!A1:A2)+$B$1");
+        assert_eq!(state.get_value(&CellIdx{col: 1, row: 2}).to_string(), "10");
+        assert_eq!(state.get_value(&CellIdx{col: 0, row: 3}).to_string(), "9.5");
+        assert!(!state.undo());
+    }
+
+    #[test]
+    fn invalid_packages() {
+        assert!(matches!(import(b"not a zip"), Err(FileError::Format(_))));
+        assert!(matches!(import(&package(&[("_rels/.rels", RELS)])), Err(FileError::Format(_))));
+        assert_eq!(resolve("xl/worksheets/sheet1.xml", "../media/a.png"), "xl/media/a.png");
+        assert_eq!(parse_cell("AB12"), Some(CellIdx{col: 27, row: 11}));
+        assert_eq!(parse_cell("12"), None);
+        assert_eq!(parse_cell("XFD1048576"), Some(CellIdx{col: 16383, row: 1048575}));
+        assert_eq!(parse_cell("XFE1"), None);
+        assert_eq!(parse_cell("A1048577"), None);
+        let sheet = r#"<worksheet><sheetData><row r="1"><c r="AAAAAAAAAAAAA1"><v>1</v></c></row></sheetData></worksheet>"#;
+        let bytes = package(&[
+            ("_rels/.rels", RELS), ("xl/workbook.xml", WORKBOOK), ("xl/_rels/workbook.xml.rels", WORKBOOK_RELS),
+            ("xl/sharedStrings.xml", STRINGS), ("xl/worksheets/sheet1.xml", sheet), ("xl/worksheets/sheet2.xml", SHEET2),
+        ]);
+        assert!(matches!(import(&bytes), Err(FileError::Format(_))));
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut state = SheetState::new();
+        let data = state.workbook.add_sheet("Q1 Data").unwrap();
+        let set = |state: &mut SheetState, col, row, text: &str| state.sheet_mut().set_text(CellIdx{col, row}, text.to_string());
+        set(&mut state, 0, 0, "Quarterly total");
+        set(&mut state, 0, 1, "=SUM('Q1 Data'!A1:A2)*2");
+        set(&mut state, 1, 1, "'007");
+        set(&mut state, 2, 1, "=A2>10");
+        set(&mut state, 3, 1, "=1/0");
+        set(&mut state, 4, 1, "=B2&\"<x>\"");
+        set(&mut state, 5, 1, "=1+");
+        state.set_active(data);
+        set(&mut state, 0, 0, "3");
+        set(&mut state, 0, 1, "4.5");
+
+        let bytes = export(&mut state);
+        let mut package = Package::open(&bytes).unwrap();
+        let sheet = package.read("xl/worksheets/sheet1.xml").unwrap().unwrap();
+        let col = sheet.child("cols").unwrap().child("col").unwrap();
+        assert_eq!((col.attr("min"), col.attr("width")), (Some("1"), Some("17")));
+        let formula = sheet.child("sheetData").unwrap().children("row").nth(1).unwrap().child("c").unwrap();
+        assert_eq!(formula.child("f").unwrap().text(), "SUM('Q1 Data'!A1:A2)*2");
+        assert_eq!(formula.child("v").unwrap().text(), "15");
+
+        let (mut imported, info) = import(&bytes).unwrap();
+        assert!(info.unsupported.is_empty());
+        assert_eq!(imported.workbook.name(imported.active()), Some("Q1 Data"));
+        imported.set_active(imported.workbook.sheet_ids()[0]);
+        let text = |state: &SheetState, col| state.sheet().get_text(&CellIdx{col, row: 1});
+        assert_eq!(imported.sheet().get_text(&CellIdx{col: 0, row: 0}), "Quarterly total");
+        assert_eq!(text(&imported, 0), "=SUM('Q1 Data'!A1:A2)*2");
+        assert_eq!(text(&imported, 1), "'007");
+        assert_eq!(text(&imported, 2), "=A2>10");
+        assert_eq!(text(&imported, 3), "=1/0");
+        assert_eq!(text(&imported, 4), "=B2&\"<x>\"");
+        // Formulas that do not parse keep their text
+        assert_eq!(text(&imported, 5), "'=1+");
+        assert_eq!(imported.get_value(&CellIdx{col: 4, row: 1}).to_string(), "007<x>");
+    }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn python_cells_as_values() {
+        use crate::{sheet::Cell, engine::EngineId};
+
+        let mut state = SheetState::new();
+        state.sheet_mut().insert(CellIdx{col: 1, row: 2}, Cell{engine: EngineId::PYTHON, value: "6 * 7".to_string()});
+        state.sheet_mut().insert(CellIdx{col: 3, row: 3}, Cell{engine: EngineId::PYTHON, value: "['a', 'b']".to_string()});
+        let bytes = export(&mut state);
+        let (mut imported, _) = import(&bytes).unwrap();
+        assert_eq!(imported.sheet().get(&CellIdx{col: 1, row: 2}).unwrap().engine, EngineId::SIMPLE);
+        assert_eq!(imported.get_value(&CellIdx{col: 1, row: 2}).to_string(), "42");
+        // Along with the elements spilled over empty cells
+        assert_eq!(imported.get_value(&CellIdx{col: 3, row: 4}).to_string(), "b");
+
+        let mut package = Package::open(&bytes).unwrap();
+        let comments = package.read("xl/comments1.xml").unwrap().unwrap();
+        let comment = comments.child("commentList").unwrap().child("comment").unwrap();
+        assert_eq!(comment.attr("ref"), Some("B3"));
+        assert!(comment.text().ends_with("6 * 7"));
+        assert!(package.read("xl/drawings/vmlDrawing1.vml").unwrap().is_some());
+    }
+}
@@ -0,0 +1,125 @@
+use quick_xml::{events::{Event, BytesStart}, Reader};
+
+/// An XML element, its name without namespace prefix and attribute names as written
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Element {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    /// Attribute by its name as written, or by its name without prefix
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter()
+            .find(|(key, _)| key == name || key.rsplit_once(':').is_some_and(|(_, local)| local == name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    /// Child elements named `name`
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.elements().filter(move |element| element.name == name)
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|element| element.name == name)
+    }
+
+    /// Text of the element and everything below it
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        self.collect_text(&mut text);
+        text
+    }
+
+    fn collect_text(&self, text: &mut String) {
+        for node in self.children.iter() {
+            match node {
+                Node::Element(element) => element.collect_text(text),
+                Node::Text(part) => text.push_str(part),
+            }
+        }
+    }
+}
+
+fn start(event: &BytesStart) -> Result<Element, String> {
+    let name = String::from_utf8_lossy(event.local_name().as_ref()).into_owned();
+    let attrs = event.attributes().map(|attr| {
+        let attr = attr.map_err(|err| err.to_string())?;
+        let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+        let value = attr.unescape_value().map_err(|err| err.to_string())?.into_owned();
+        Ok((key, value))
+    }).collect::<Result<_, String>>()?;
+    Ok(Element{name, attrs, children: vec![]})
+}
+
+/// Root element of a document
+pub fn parse(bytes: &[u8]) -> Result<Element, String> {
+    let mut reader = Reader::from_reader(bytes);
+    // Open elements, innermost last
+    let mut stack: Vec<Element> = vec![];
+    let mut root = None;
+
+    loop {
+        let node = match reader.read_event().map_err(|err| err.to_string())? {
+            Event::Start(event) => {
+                stack.push(start(&event)?);
+                continue;
+            },
+            Event::End(_) => Node::Element(stack.pop().ok_or("Unbalanced closing tag")?),
+            Event::Empty(event) => Node::Element(start(&event)?),
+            Event::Text(text) => Node::Text(text.unescape().map_err(|err| err.to_string())?.into_owned()),
+            Event::CData(data) => Node::Text(String::from_utf8_lossy(&data.into_inner()).into_owned()),
+            Event::Eof => break,
+            _ => continue,
+        };
+        match (stack.last_mut(), node) {
+            (Some(parent), node) => parent.children.push(node),
+            (None, Node::Element(element)) => root = Some(element),
+            // Whitespace around the root
+            (None, Node::Text(_)) => (),
+        }
+    }
+    if !stack.is_empty() {
+        return Err("Unexpected end of document".to_string());
+    }
+    root.ok_or_else(|| "No root element".to_string())
+}
+
+/// Text escaped for use in content and attribute values
+pub fn escape(text: &str) -> String {
+    quick_xml::escape::escape(text).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_document() {
+        let root = parse(br#"<?xml version="1.0"?>
+            <x:doc xmlns:x="urn:x" x:id="1"><item a="&lt;b&gt;">one<sub>two</sub></item><item/><![CDATA[<raw>]]></x:doc>"#).unwrap();
+        assert_eq!(root.name, "doc");
+        assert_eq!(root.attr("id"), Some("1"));
+        assert_eq!(root.attr("x:id"), Some("1"));
+        assert_eq!(root.attr("y:id"), None);
+        assert_eq!(root.children("item").count(), 2);
+        assert_eq!(root.child("item").unwrap().attr("a"), Some("<b>"));
+        assert_eq!(root.text(), "onetwo<raw>");
+        assert!(parse(b"<open>").is_err());
+        assert_eq!(escape("a<\"b\">&"), "a&lt;&quot;b&quot;&gt;&amp;");
+    }
+}